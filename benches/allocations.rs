@@ -0,0 +1,61 @@
+//! Counts heap allocations for one deposit per client, growing the maps as
+//! they fill against `PaymentsEngine::with_capacity` sizing them up front.
+
+use payments_engine::domain::transaction::Trx;
+use payments_engine::services::payment_engine::PaymentsEngine;
+use rust_decimal::Decimal;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const TX_COUNT: u32 = 50_000;
+
+fn measure(rt: &tokio::runtime::Runtime, engine: impl FnOnce() -> PaymentsEngine) -> (usize, usize) {
+    let txs: Vec<Trx> = (0..TX_COUNT)
+        .map(|i| Trx::Deposit { client: i as u16, tx: i, amount: Decimal::new(1_000, 2) })
+        .collect();
+    let (allocations, bytes) = (ALLOCATIONS.load(Ordering::Relaxed), BYTES.load(Ordering::Relaxed));
+    let engine = engine();
+    rt.block_on(async {
+        for tx in txs {
+            engine.process(tx).await;
+        }
+    });
+    (ALLOCATIONS.load(Ordering::Relaxed) - allocations, BYTES.load(Ordering::Relaxed) - bytes)
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let n = TX_COUNT as usize;
+    for (name, (allocations, bytes)) in [
+        ("default", measure(&rt, PaymentsEngine::new)),
+        ("with_capacity", measure(&rt, || PaymentsEngine::with_capacity(n, n, None))),
+    ] {
+        println!("{:<14} {:>8} allocations {:>12} bytes", name, allocations, bytes);
+    }
+}
@@ -0,0 +1,230 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use payments_engine::services::payment_engine::PaymentsEngine;
+use payments_engine::domain::transaction::Trx;
+use payments_engine::domain::user_account::SortOrder;
+use rust_decimal::Decimal;
+use tokio::runtime::Runtime;
+
+const TX_COUNT: u32 = 10_000;
+const CLIENTS: u32 = 100;
+const HISTORY_SIZES: [Option<usize>; 3] = [None, Some(100_000), Some(1_000)];
+
+fn deposits(first_tx: u32) -> Vec<Trx> {
+    (0..TX_COUNT)
+        .map(|i| Trx::Deposit {
+            client: (i % CLIENTS) as u16,
+            tx: first_tx + i,
+            amount: Decimal::new(1_000, 2),
+        })
+        .collect()
+}
+
+fn withdrawals(first_tx: u32) -> Vec<Trx> {
+    (0..TX_COUNT)
+        .map(|i| Trx::Withdrawal {
+            client: (i % CLIENTS) as u16,
+            tx: first_tx + i,
+            amount: Decimal::new(100, 2),
+        })
+        .collect()
+}
+
+/// Deposits where every fourth one is disputed and then resolved or charged back.
+fn mixed_disputes() -> Vec<Trx> {
+    let mut txs = Vec::new();
+    for i in 0..TX_COUNT {
+        let client = (i % CLIENTS) as u16;
+        txs.push(Trx::Deposit { client, tx: i, amount: Decimal::new(1_000, 2) });
+        if i % 4 == 0 {
+            txs.push(Trx::Dispute { client, tx: i });
+            if i % 8 == 0 {
+                txs.push(Trx::Resolve { client, tx: i });
+            } else {
+                txs.push(Trx::Chargeback { client, tx: i });
+            }
+        }
+    }
+    txs
+}
+
+fn label(history: Option<usize>) -> String {
+    history.map_or("unbounded".to_string(), |n| n.to_string())
+}
+
+fn bench_workload(
+    c: &mut Criterion,
+    rt: &Runtime,
+    name: &str,
+    txs: &[Trx],
+    prefill: &[Trx],
+) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(txs.len() as u64));
+    for history in HISTORY_SIZES {
+        group.bench_with_input(BenchmarkId::from_parameter(label(history)), &history, |b, &history| {
+            b.iter_batched(
+                || {
+                    let engine = PaymentsEngine::with_max_history(history);
+                    rt.block_on(async {
+                        for tx in prefill {
+                            engine.process(tx.clone()).await;
+                        }
+                    });
+                    (engine, txs.to_vec())
+                },
+                |(engine, txs)| {
+                    rt.block_on(async {
+                        for tx in txs {
+                            engine.process(tx).await;
+                        }
+                    });
+                    engine
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+/// Per-transaction `process` against one `process_batch` call, unbounded history.
+fn bench_batch(c: &mut Criterion, rt: &Runtime) {
+    let txs = mixed_disputes();
+    let mut group = c.benchmark_group("batch");
+    group.throughput(Throughput::Elements(txs.len() as u64));
+    group.bench_function("process", |b| {
+        b.iter_batched(
+            || (PaymentsEngine::new(), txs.clone()),
+            |(engine, txs)| {
+                rt.block_on(async {
+                    for tx in txs {
+                        engine.process(tx).await;
+                    }
+                });
+                engine
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    group.bench_function("process_batch", |b| {
+        b.iter_batched(
+            || (PaymentsEngine::new(), txs.clone()),
+            |(engine, txs)| {
+                rt.block_on(engine.process_batch(txs));
+                engine
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    group.finish();
+}
+
+/// Deposit-only workload through per-transaction `process`, `process_batch`
+/// and the `process_deposits` fast path, unbounded history.
+fn bench_deposit_batch(c: &mut Criterion, rt: &Runtime) {
+    let txs = deposits(0);
+    let mut group = c.benchmark_group("deposit_batch");
+    group.throughput(Throughput::Elements(txs.len() as u64));
+    group.bench_function("process", |b| {
+        b.iter_batched(
+            || (PaymentsEngine::new(), txs.clone()),
+            |(engine, txs)| {
+                rt.block_on(async {
+                    for tx in txs {
+                        engine.process(tx).await;
+                    }
+                });
+                engine
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    group.bench_function("process_batch", |b| {
+        b.iter_batched(
+            || (PaymentsEngine::new(), txs.clone()),
+            |(engine, txs)| {
+                rt.block_on(engine.process_batch(txs));
+                engine
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    group.bench_function("process_deposits", |b| {
+        b.iter_batched(
+            || (PaymentsEngine::new(), txs.clone()),
+            |(engine, txs)| {
+                rt.block_on(engine.process_deposits(txs));
+                engine
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    group.finish();
+}
+
+/// Construction plus one deposit per client, growing the maps against
+/// `with_capacity` pre-sizing them for the run.
+fn bench_capacity(c: &mut Criterion, rt: &Runtime) {
+    let txs: Vec<Trx> = (0..TX_COUNT)
+        .map(|i| Trx::Deposit { client: i as u16, tx: i, amount: Decimal::new(1_000, 2) })
+        .collect();
+    let mut group = c.benchmark_group("capacity");
+    group.throughput(Throughput::Elements(txs.len() as u64));
+    let sizes = [("default", None), ("with_capacity", Some(TX_COUNT as usize))];
+    for (name, capacity) in sizes {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || txs.clone(),
+                |txs| {
+                    let engine = match capacity {
+                        Some(n) => PaymentsEngine::with_capacity(n, n, None),
+                        None => PaymentsEngine::new(),
+                    };
+                    rt.block_on(async {
+                        for tx in txs {
+                            engine.process(tx).await;
+                        }
+                    });
+                    engine
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+/// Reading out accounts sorted by client against unsorted, over the full u16
+/// client ID space.
+fn bench_output_order(c: &mut Criterion, rt: &Runtime) {
+    let engine = PaymentsEngine::new();
+    rt.block_on(async {
+        for client in (0..=u16::MAX).rev() {
+            engine.process(Trx::Deposit {
+                client,
+                tx: client as u32,
+                amount: Decimal::ONE,
+            }).await;
+        }
+    });
+    let mut group = c.benchmark_group("output_order");
+    group.throughput(Throughput::Elements(u16::MAX as u64 + 1));
+    for (name, order) in [("sorted", SortOrder::ClientId), ("unsorted", SortOrder::Unsorted)] {
+        group.bench_function(name, |b| b.iter(|| engine.get_accounts_ordered(order)));
+    }
+    group.finish();
+}
+
+fn engine_benches(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    bench_workload(c, &rt, "deposits", &deposits(0), &[]);
+    bench_workload(c, &rt, "withdrawals", &withdrawals(TX_COUNT), &deposits(0));
+    bench_workload(c, &rt, "mixed_disputes", &mixed_disputes(), &[]);
+    bench_batch(c, &rt);
+    bench_deposit_batch(c, &rt);
+    bench_capacity(c, &rt);
+    bench_output_order(c, &rt);
+}
+
+criterion_group!(benches, engine_benches);
+criterion_main!(benches);
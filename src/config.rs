@@ -1,3 +1,159 @@
+use crate::domain::user_account::{OutputColumn, OutputPrecision, SortOrder};
+use crate::error::PaymentError;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputFormat {
+    /// JSON lines for `.jsonl`/`.ndjson` files, CSV otherwise
+    #[default]
+    Auto,
+    Csv,
+    JsonLines,
+}
+
+impl InputFormat {
+    pub fn from_path(path: &str) -> Self {
+        match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") | Some("ndjson") => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
+        }
+    }
+
+    /// Replaces `Auto` with the format detected from `path`.
+    pub fn resolve(self, path: &str) -> Self {
+        match self {
+            InputFormat::Auto => InputFormat::from_path(path),
+            format => format,
+        }
+    }
+}
+
+/// Encoding of the account output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// One JSON array of account objects; column and footer settings don't apply
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(PaymentError::InvalidConfig(format!("unknown output format '{}'", other))),
+        }
+    }
+}
+
+/// How amounts with more than `decimal_precision` places are rounded on ingest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    /// Banker's rounding: ties go to the even digit
+    #[default]
+    HalfEven,
+    /// Ties round away from zero
+    HalfUp,
+    /// Ties round toward zero
+    HalfDown,
+    /// Truncate toward zero
+    Down,
+    /// Always round away from zero
+    Up,
+}
+
+impl RoundingMode {
+    pub fn strategy(&self) -> RoundingStrategy {
+        match self {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::HalfDown => RoundingStrategy::MidpointTowardZero,
+            RoundingMode::Down => RoundingStrategy::ToZero,
+            RoundingMode::Up => RoundingStrategy::AwayFromZero,
+        }
+    }
+}
+
+/// Quoting of fields in CSV output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuoteStyle {
+    /// Only fields containing delimiters, quotes or line breaks
+    #[default]
+    Necessary,
+    Always,
+    /// Every field that doesn't parse as a number
+    NonNumeric,
+    /// Never quote, even if the output becomes ambiguous
+    Never,
+}
+
+impl QuoteStyle {
+    pub fn csv_style(&self) -> csv::QuoteStyle {
+        match self {
+            QuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+            QuoteStyle::Always => csv::QuoteStyle::Always,
+            QuoteStyle::NonNumeric => csv::QuoteStyle::NonNumeric,
+            QuoteStyle::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+/// What disputing a withdrawal does to the balances. Deposit and fee disputes
+/// always hold the disputed funds out of `available`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WithdrawalDisputeMode {
+    /// Same as a deposit: dispute moves the amount from available to held,
+    /// resolve moves it back, chargeback removes it from held and total
+    #[default]
+    Hold,
+    /// The client claims the withdrawal was wrong. Dispute provisionally
+    /// credits the amount into held (and total); resolve drops the credit,
+    /// so the withdrawal stands; chargeback releases it into available
+    CreditBack,
+}
+
+/// How CSV amounts group thousands and mark decimals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AmountLocale {
+    /// `1234.56`, parsed as-is
+    #[default]
+    Plain,
+    /// `1,234.56`; needs a non-comma delimiter or quoted amounts
+    English,
+    /// `1.234,56`; needs a non-comma delimiter or quoted amounts
+    European,
+}
+
+impl AmountLocale {
+    /// Rewrites `amount` into the plain form `Decimal` parses.
+    pub fn normalize(&self, amount: &str) -> String {
+        match self {
+            AmountLocale::Plain => amount.to_string(),
+            AmountLocale::English => amount.replace(',', ""),
+            AmountLocale::European => amount.replace('.', "").replace(',', "."),
+        }
+    }
+}
+
+/// What the server does with a connection accepted while `max_connections`
+/// handlers are already running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionLimitPolicy {
+    /// Stop accepting until a handler finishes; new clients queue in the
+    /// listen backlog
+    #[default]
+    Wait,
+    /// Accept, reply `ERROR server busy` and close
+    Reject,
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct ProcessorConfig {
@@ -5,6 +161,113 @@ pub struct ProcessorConfig {
     pub log_warnings: bool,
     pub decimal_precision: u32,
     pub max_tx_history: Option<usize>,
+    pub output_columns: Vec<OutputColumn>,
+    pub delimiter: u8,
+    pub read_timeout: Option<Duration>,
+    pub max_request_bytes: Option<usize>,
+    pub sort_order: SortOrder,
+    pub verify_invariants: bool,
+    pub allow_negative_on_dispute: bool,
+    pub input_format: InputFormat,
+    pub ordered_dispatch: bool,
+    pub max_tx_per_client: Option<u64>,
+    pub allow_direct_chargeback: bool,
+    pub rounding_mode: RoundingMode,
+    pub freeze_on_lock: bool,
+    pub allowed_clients: Option<HashSet<u16>>,
+    pub denied_clients: HashSet<u16>,
+    /// Accept rows with more or fewer fields than the header; extra fields are ignored
+    pub flexible_columns: bool,
+    /// Fixed hashing and single-threaded file processing, so repeated runs
+    /// produce byte-identical output even with `SortOrder::Unsorted`
+    pub deterministic: bool,
+    /// Honour `unlock` transactions on locked accounts with no open disputes
+    pub allow_unlock: bool,
+    /// Server parses each request while reading it instead of buffering it
+    /// whole. `read_timeout` then applies per read and `max_request_bytes` is
+    /// not enforced
+    pub stream_requests: bool,
+    /// Fail amounts with more significant decimal places than
+    /// `decimal_precision` instead of rounding them
+    pub reject_excess_precision: bool,
+    /// Server writes `ACK <rows>` after every this many rows, before the
+    /// account dump
+    pub ack_interval: Option<usize>,
+    /// Cap on concurrently running connection handlers in the server
+    pub max_connections: Option<usize>,
+    pub connection_limit_policy: ConnectionLimitPolicy,
+    /// Treat a repeated deposit with the same tx, client, currency and amount
+    /// as an accepted no-op retry; other duplicates are conflicts
+    pub idempotent_deposits: bool,
+    /// Extra attempts for a failed file open, or a read failing with a
+    /// transient error (interrupted, would block, timed out), in `process_file`
+    pub io_retries: u32,
+    /// Delay before the first retry; doubles on each further attempt
+    pub io_retry_delay: Duration,
+    /// Reject withdrawals from an account with any transaction under dispute
+    pub block_withdrawals_during_dispute: bool,
+    /// Decimal places for each balance column in account output
+    pub output_precision: OutputPrecision,
+    /// Run `PaymentsEngine::self_check` after every transaction. The scan
+    /// covers the whole history, so this is for debugging only
+    pub self_check: bool,
+    /// When fields in account output are quoted
+    pub quote_style: QuoteStyle,
+    /// Server only: transactions per second allowed for each client within one
+    /// connection, with bursts up to one second's worth. Excess rows are skipped
+    pub client_rate_limit: Option<u32>,
+    /// Server only: every connection gets a fresh engine and sees only its own
+    /// accounts. The shared engine (and its WAL) is left untouched
+    pub isolated_connections: bool,
+    /// Added to every input client ID on ingest, so feeds that reuse IDs can be
+    /// merged. Client filters and output see the shifted IDs
+    pub client_id_offset: u16,
+    /// Treat `total != available + held` after a mutation as fatal instead of
+    /// continuing; checked before any `verify_invariants` repair
+    pub abort_on_invariant_violation: bool,
+    /// Separators used by CSV amounts; group separators are stripped before parsing
+    pub amount_locale: AmountLocale,
+    /// Amount given to deposits and withdrawals without one; `None` skips them
+    /// as `Rejection::MissingAmount`
+    pub default_amount: Option<Decimal>,
+    /// Ends account output with a `# count=N crc32=XXXXXXXX` line over the
+    /// bytes written before it; see `trx_processor::verify_footer`
+    pub output_footer: bool,
+    /// Balance rules for disputes on withdrawals; see `WithdrawalDisputeMode`
+    pub withdrawal_dispute_mode: WithdrawalDisputeMode,
+    /// Server replies list only the accounts of clients with a transaction
+    /// applied by that request, instead of every account
+    pub delta_output: bool,
+    /// Clients the engine may hold accounts for; transactions from another
+    /// client are rejected, while existing clients keep processing
+    pub max_accounts: Option<usize>,
+    /// Abort the run with `PaymentError::Rejected` on the first rejected
+    /// transaction of any kind, not just malformed rows
+    pub fail_fast: bool,
+    /// Lowercase the `type` field before matching, so `Deposit` and `DEPOSIT`
+    /// parse; off in `strict()`
+    pub case_insensitive_types: bool,
+    /// The server lets each connection override this with a `FORMAT` line
+    pub output_format: OutputFormat,
+    /// Where the server writes periodic state snapshots; `None` disables them
+    pub snapshot_path: Option<PathBuf>,
+    /// Snapshot once this many transactions were applied since the last one
+    pub snapshot_every: Option<u64>,
+    /// Snapshot at least this often while transactions keep arriving
+    pub snapshot_interval: Option<Duration>,
+    /// A negative deposit is read as a withdrawal of the absolute amount and a
+    /// negative withdrawal as a deposit, for feeds that only sign the amount
+    pub signed_amounts: bool,
+    /// Under `skip_malformed`, replace invalid UTF-8 in input rows with U+FFFD
+    /// instead of rejecting the row as `PaymentError::InvalidUtf8`
+    pub lossy_utf8: bool,
+    /// Inclusive bounds on client IDs, e.g. a partner's assigned range; clients
+    /// outside them are rejected as `Rejection::ClientOutOfRange`
+    pub min_client: Option<u16>,
+    pub max_client: Option<u16>,
+    /// How far below zero a withdrawal may take `available`; zero rejects any
+    /// withdrawal above the available balance
+    pub overdraft_limit: Decimal,
 }
 
 impl Default for ProcessorConfig {
@@ -14,6 +277,57 @@ impl Default for ProcessorConfig {
             log_warnings: true,
             decimal_precision: 4,
             max_tx_history: None,
+            output_columns: OutputColumn::DEFAULT.to_vec(),
+            delimiter: b',',
+            read_timeout: None,
+            max_request_bytes: None,
+            sort_order: SortOrder::ClientId,
+            verify_invariants: false,
+            allow_negative_on_dispute: true,
+            input_format: InputFormat::Auto,
+            ordered_dispatch: false,
+            max_tx_per_client: None,
+            allow_direct_chargeback: false,
+            rounding_mode: RoundingMode::HalfEven,
+            freeze_on_lock: false,
+            allowed_clients: None,
+            denied_clients: HashSet::new(),
+            flexible_columns: true,
+            deterministic: false,
+            allow_unlock: false,
+            stream_requests: false,
+            reject_excess_precision: false,
+            ack_interval: None,
+            max_connections: None,
+            connection_limit_policy: ConnectionLimitPolicy::Wait,
+            idempotent_deposits: false,
+            io_retries: 0,
+            io_retry_delay: Duration::from_millis(50),
+            block_withdrawals_during_dispute: false,
+            output_precision: OutputPrecision::default(),
+            self_check: false,
+            quote_style: QuoteStyle::Necessary,
+            client_rate_limit: None,
+            isolated_connections: false,
+            client_id_offset: 0,
+            abort_on_invariant_violation: false,
+            amount_locale: AmountLocale::Plain,
+            default_amount: None,
+            output_footer: false,
+            withdrawal_dispute_mode: WithdrawalDisputeMode::Hold,
+            delta_output: false,
+            max_accounts: None,
+            fail_fast: false,
+            case_insensitive_types: true,
+            output_format: OutputFormat::Csv,
+            snapshot_path: None,
+            snapshot_every: None,
+            snapshot_interval: None,
+            signed_amounts: false,
+            lossy_utf8: false,
+            min_client: None,
+            max_client: None,
+            overdraft_limit: Decimal::ZERO,
         }
     }
 }
@@ -31,6 +345,9 @@ impl ProcessorConfig {
             log_warnings: true,
             decimal_precision: 4,
             max_tx_history: Some(10_000_000),
+            read_timeout: Some(Duration::from_secs(30)),
+            max_request_bytes: Some(64 * 1024 * 1024),
+            ..ProcessorConfig::default()
         }
     }
 
@@ -41,6 +358,10 @@ impl ProcessorConfig {
             log_warnings: false,
             decimal_precision: 4,
             max_tx_history: None,
+            flexible_columns: false,
+            reject_excess_precision: true,
+            case_insensitive_types: false,
+            ..ProcessorConfig::default()
         }
     }
 
@@ -67,6 +388,413 @@ impl ProcessorConfig {
         self.max_tx_history = max;
         self
     }
+
+    #[allow(dead_code)]
+    pub fn with_output_columns(mut self, columns: Vec<OutputColumn>) -> Self {
+        self.output_columns = columns;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_max_request_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_request_bytes = max;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_sort_order(mut self, order: SortOrder) -> Self {
+        self.sort_order = order;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_verify_invariants(mut self, verify: bool) -> Self {
+        self.verify_invariants = verify;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_allow_negative_on_dispute(mut self, allow: bool) -> Self {
+        self.allow_negative_on_dispute = allow;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_input_format(mut self, format: InputFormat) -> Self {
+        self.input_format = format;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_ordered_dispatch(mut self, ordered: bool) -> Self {
+        self.ordered_dispatch = ordered;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_max_tx_per_client(mut self, max: Option<u64>) -> Self {
+        self.max_tx_per_client = max;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_allow_direct_chargeback(mut self, allow: bool) -> Self {
+        self.allow_direct_chargeback = allow;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_rounding_mode(mut self, mode: RoundingMode) -> Self {
+        self.rounding_mode = mode;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_freeze_on_lock(mut self, freeze: bool) -> Self {
+        self.freeze_on_lock = freeze;
+        self
+    }
+
+    /// Rounds `amount` to `decimal_precision` places using `rounding_mode`, then
+    /// rescales it so every normalized amount carries exactly that scale
+    /// (`10.0` and `10.00000` both become `10.0000`).
+    pub fn round_amount(&self, amount: Decimal) -> Decimal {
+        let mut rounded =
+            amount.round_dp_with_strategy(self.decimal_precision, self.rounding_mode.strategy());
+        rounded.rescale(self.decimal_precision);
+        rounded
+    }
+
+    #[allow(dead_code)]
+    pub fn with_allowed_clients(mut self, clients: Option<HashSet<u16>>) -> Self {
+        self.allowed_clients = clients;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_denied_clients(mut self, clients: HashSet<u16>) -> Self {
+        self.denied_clients = clients;
+        self
+    }
+
+    /// A client is permitted if it is not denied and, when an allow list is set,
+    /// appears in it.
+    pub fn client_permitted(&self, client: u16) -> bool {
+        !self.denied_clients.contains(&client)
+            && self.allowed_clients.as_ref().is_none_or(|allowed| allowed.contains(&client))
+    }
+
+    /// Whether `client` lies within `min_client..=max_client`.
+    pub fn client_in_range(&self, client: u16) -> bool {
+        self.min_client.is_none_or(|min| client >= min) && self.max_client.is_none_or(|max| client <= max)
+    }
+
+    #[allow(dead_code)]
+    pub fn with_flexible_columns(mut self, flexible: bool) -> Self {
+        self.flexible_columns = flexible;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_allow_unlock(mut self, allow_unlock: bool) -> Self {
+        self.allow_unlock = allow_unlock;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_stream_requests(mut self, stream_requests: bool) -> Self {
+        self.stream_requests = stream_requests;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_reject_excess_precision(mut self, reject_excess_precision: bool) -> Self {
+        self.reject_excess_precision = reject_excess_precision;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_ack_interval(mut self, ack_interval: Option<usize>) -> Self {
+        self.ack_interval = ack_interval;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_connection_limit_policy(mut self, policy: ConnectionLimitPolicy) -> Self {
+        self.connection_limit_policy = policy;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_idempotent_deposits(mut self, idempotent_deposits: bool) -> Self {
+        self.idempotent_deposits = idempotent_deposits;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_io_retries(mut self, retries: u32, base_delay: Duration) -> Self {
+        self.io_retries = retries;
+        self.io_retry_delay = base_delay;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_block_withdrawals_during_dispute(mut self, block: bool) -> Self {
+        self.block_withdrawals_during_dispute = block;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_output_precision(mut self, precision: OutputPrecision) -> Self {
+        self.output_precision = precision;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_self_check(mut self, self_check: bool) -> Self {
+        self.self_check = self_check;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_quote_style(mut self, style: QuoteStyle) -> Self {
+        self.quote_style = style;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_client_rate_limit(mut self, per_second: Option<u32>) -> Self {
+        self.client_rate_limit = per_second;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_isolated_connections(mut self, isolated: bool) -> Self {
+        self.isolated_connections = isolated;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_client_id_offset(mut self, offset: u16) -> Self {
+        self.client_id_offset = offset;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_abort_on_invariant_violation(mut self, abort: bool) -> Self {
+        self.abort_on_invariant_violation = abort;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_amount_locale(mut self, locale: AmountLocale) -> Self {
+        self.amount_locale = locale;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_default_amount(mut self, amount: Option<Decimal>) -> Self {
+        self.default_amount = amount;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_output_footer(mut self, footer: bool) -> Self {
+        self.output_footer = footer;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_withdrawal_dispute_mode(mut self, mode: WithdrawalDisputeMode) -> Self {
+        self.withdrawal_dispute_mode = mode;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_delta_output(mut self, delta: bool) -> Self {
+        self.delta_output = delta;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_max_accounts(mut self, max: Option<usize>) -> Self {
+        self.max_accounts = max;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_case_insensitive_types(mut self, insensitive: bool) -> Self {
+        self.case_insensitive_types = insensitive;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_snapshot_path(mut self, path: Option<PathBuf>) -> Self {
+        self.snapshot_path = path;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_snapshot_every(mut self, every: Option<u64>) -> Self {
+        self.snapshot_every = every;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_snapshot_interval(mut self, interval: Option<Duration>) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_signed_amounts(mut self, signed: bool) -> Self {
+        self.signed_amounts = signed;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_lossy_utf8(mut self, lossy: bool) -> Self {
+        self.lossy_utf8 = lossy;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_min_client(mut self, min: Option<u16>) -> Self {
+        self.min_client = min;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_max_client(mut self, max: Option<u16>) -> Self {
+        self.max_client = max;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_overdraft_limit(mut self, limit: Decimal) -> Self {
+        self.overdraft_limit = limit;
+        self
+    }
+
+    /// Overrides fields from `PE_*` environment variables, e.g.
+    /// `PE_MAX_TX_HISTORY=50000` or `PE_SKIP_MALFORMED=false`. Meant to run on
+    /// the base config before CLI flags are applied, so flags still win.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn from_env(self) -> Result<Self, PaymentError> {
+        self.from_vars(std::env::vars())
+    }
+
+    /// `from_env` over any set of variables. Names without the `PE_` prefix
+    /// are ignored; unrecognised `PE_` names are logged and skipped. `none` or
+    /// an empty value clears an optional limit.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn from_vars<I, K, V>(mut self, vars: I) -> Result<Self, PaymentError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        for (name, value) in vars {
+            let (name, value) = (name.as_ref(), value.as_ref().trim());
+            let Some(field) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match field {
+                "SKIP_MALFORMED" => self.skip_malformed = env_value(name, value)?,
+                "LOG_WARNINGS" => self.log_warnings = env_value(name, value)?,
+                "DECIMAL_PRECISION" => self.decimal_precision = env_value(name, value)?,
+                "MAX_TX_HISTORY" => self.max_tx_history = env_option(name, value)?,
+                "READ_TIMEOUT_SECS" => self.read_timeout = env_option(name, value)?.map(Duration::from_secs),
+                "MAX_REQUEST_BYTES" => self.max_request_bytes = env_option(name, value)?,
+                "SORT_ORDER" => self.sort_order = value.parse()?,
+                "VERIFY_INVARIANTS" => self.verify_invariants = env_value(name, value)?,
+                "ALLOW_NEGATIVE_ON_DISPUTE" => self.allow_negative_on_dispute = env_value(name, value)?,
+                "MAX_TX_PER_CLIENT" => self.max_tx_per_client = env_option(name, value)?,
+                "ALLOW_DIRECT_CHARGEBACK" => self.allow_direct_chargeback = env_value(name, value)?,
+                "FREEZE_ON_LOCK" => self.freeze_on_lock = env_value(name, value)?,
+                "ALLOW_UNLOCK" => self.allow_unlock = env_value(name, value)?,
+                "MAX_CONNECTIONS" => self.max_connections = env_option(name, value)?,
+                "CLIENT_RATE_LIMIT" => self.client_rate_limit = env_option(name, value)?,
+                "MAX_ACCOUNTS" => self.max_accounts = env_option(name, value)?,
+                "FAIL_FAST" => self.fail_fast = env_value(name, value)?,
+                "SIGNED_AMOUNTS" => self.signed_amounts = env_value(name, value)?,
+                "LOSSY_UTF8" => self.lossy_utf8 = env_value(name, value)?,
+                "MIN_CLIENT" => self.min_client = env_option(name, value)?,
+                "MAX_CLIENT" => self.max_client = env_option(name, value)?,
+                "OVERDRAFT_LIMIT" => self.overdraft_limit = env_value(name, value)?,
+                "OUTPUT_FORMAT" => self.output_format = value.parse()?,
+                _ => log::warn!("Ignoring unknown config variable {}", name),
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn csv_reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .trim(csv::Trim::All)
+            .delimiter(self.delimiter)
+            .flexible(self.flexible_columns);
+        builder
+    }
+
+    pub fn csv_writer_builder(&self) -> csv::WriterBuilder {
+        let mut builder = csv::WriterBuilder::new();
+        builder.quote_style(self.quote_style.csv_style());
+        builder
+    }
+}
+
+/// Prefix of the environment variables read by `ProcessorConfig::from_env`.
+pub const ENV_PREFIX: &str = "PE_";
+
+fn env_value<T: FromStr>(name: &str, value: &str) -> Result<T, PaymentError> {
+    value
+        .parse()
+        .map_err(|_| PaymentError::InvalidConfig(format!("invalid value '{}' for {}", value, name)))
+}
+
+fn env_option<T: FromStr>(name: &str, value: &str) -> Result<Option<T>, PaymentError> {
+    if value.is_empty() || value.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    env_value(name, value).map(Some)
 }
 
 #[cfg(test)]
@@ -79,6 +807,8 @@ mod tests {
         assert!(config.skip_malformed);
         assert!(config.log_warnings);
         assert_eq!(config.decimal_precision, 4);
+        assert_eq!(config.output_columns, OutputColumn::DEFAULT);
+        assert!(config.allow_negative_on_dispute);
     }
 
     #[test]
@@ -96,6 +826,8 @@ mod tests {
         let config = ProcessorConfig::production();
         assert!(config.skip_malformed);
         assert!(config.log_warnings);
+        assert!(config.read_timeout.is_some());
+        assert!(config.max_request_bytes.is_some());
     }
 
     #[test]
@@ -104,4 +836,85 @@ mod tests {
         assert!(!config.skip_malformed);
         assert!(!config.log_warnings);
     }
+
+    #[test]
+    fn test_input_format_from_path() {
+        assert_eq!(InputFormat::from_path("feed.jsonl"), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_path("dir/feed.ndjson"), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_path("feed.csv"), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path("feed"), InputFormat::Csv);
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        use rust_decimal_macros::dec;
+
+        let amount = dec!(2.50005);
+        assert_eq!(ProcessorConfig::new().round_amount(amount), dec!(2.5000));
+        let half_up = ProcessorConfig::new().with_rounding_mode(RoundingMode::HalfUp);
+        assert_eq!(half_up.round_amount(amount), dec!(2.5001));
+        let down = ProcessorConfig::new().with_rounding_mode(RoundingMode::Down);
+        assert_eq!(down.round_amount(dec!(2.50009)), dec!(2.5000));
+        let up = ProcessorConfig::new().with_rounding_mode(RoundingMode::Up);
+        assert_eq!(up.round_amount(dec!(2.50001)), dec!(2.5001));
+    }
+
+    #[test]
+    fn test_amount_locale_normalize() {
+        assert_eq!(AmountLocale::Plain.normalize("1234.56"), "1234.56");
+        assert_eq!(AmountLocale::English.normalize("1,234,567.5"), "1234567.5");
+        assert_eq!(AmountLocale::European.normalize("1.234,56"), "1234.56");
+    }
+
+    #[test]
+    fn test_round_amount_normalizes_scale() {
+        use rust_decimal_macros::dec;
+
+        let config = ProcessorConfig::new();
+        assert_eq!(config.round_amount(dec!(10.0)).scale(), 4);
+        assert_eq!(config.round_amount(dec!(10.00000)).scale(), 4);
+        assert_eq!(config.round_amount(dec!(10)).to_string(), "10.0000");
+        assert_eq!(ProcessorConfig::new().with_precision(2).round_amount(dec!(1.5)).to_string(), "1.50");
+    }
+
+    #[test]
+    fn test_client_permitted() {
+        let config = ProcessorConfig::new();
+        assert!(config.client_permitted(1));
+
+        let config = ProcessorConfig::new()
+            .with_allowed_clients(Some(HashSet::from([1, 2])))
+            .with_denied_clients(HashSet::from([2]));
+        assert!(config.client_permitted(1));
+        assert!(!config.client_permitted(2));
+        assert!(!config.client_permitted(3));
+    }
+
+    #[test]
+    fn test_from_vars_overrides_defaults() {
+        let config = ProcessorConfig::production()
+            .from_vars([("PE_MAX_TX_HISTORY", "5000"), ("PE_SKIP_MALFORMED", "false"), ("PE_SORT_ORDER", "total")])
+            .unwrap();
+        assert_eq!(config.max_tx_history, Some(5000));
+        assert!(!config.skip_malformed);
+        assert_eq!(config.sort_order, SortOrder::TotalDesc);
+        // Untouched fields keep the base value
+        assert_eq!(config.read_timeout, Some(Duration::from_secs(30)));
+
+        // A builder applied afterwards, as CLI flags are, takes precedence
+        let config = config.with_skip_malformed(true);
+        assert!(config.skip_malformed);
+    }
+
+    #[test]
+    fn test_from_vars_parsing() {
+        let config = ProcessorConfig::production()
+            .from_vars([("PE_MAX_TX_HISTORY", "none"), ("PE_READ_TIMEOUT_SECS", "5"), ("HOME", "/root")])
+            .unwrap();
+        assert_eq!(config.max_tx_history, None);
+        assert_eq!(config.read_timeout, Some(Duration::from_secs(5)));
+
+        let err = ProcessorConfig::new().from_vars([("PE_DECIMAL_PRECISION", "four")]).unwrap_err();
+        assert!(err.to_string().contains("PE_DECIMAL_PRECISION"));
+    }
 }
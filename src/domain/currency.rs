@@ -0,0 +1,78 @@
+use crate::error::PaymentError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Asset code of up to 8 ASCII alphanumerics, stored uppercased so it stays
+/// `Copy` and cheap to hash as part of an account key. The default (empty)
+/// code is the implicit single currency used when no `currency` column is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Currency([u8; 8]);
+
+impl Currency {
+    pub const DEFAULT: Currency = Currency([0; 8]);
+
+    pub fn is_default(&self) -> bool {
+        *self == Currency::DEFAULT
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(self.0.len());
+        // Only ASCII is ever stored
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = PaymentError;
+
+    /// An empty string parses to the default currency.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s.trim();
+        if code.len() > 8 || !code.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(PaymentError::InvalidTransaction(format!("invalid currency code '{}'", s)));
+        }
+        let mut bytes = [0; 8];
+        for (slot, b) in bytes.iter_mut().zip(code.bytes()) {
+            *slot = b.to_ascii_uppercase();
+        }
+        Ok(Currency(bytes))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_currency() {
+        let usd: Currency = "usd".parse().unwrap();
+        assert_eq!(usd.to_string(), "USD");
+        assert_eq!(usd, " USD ".parse().unwrap());
+        assert!(!usd.is_default());
+
+        assert_eq!("".parse::<Currency>().unwrap(), Currency::DEFAULT);
+        assert_eq!(Currency::DEFAULT.to_string(), "");
+        assert!("TOOLONGCODE".parse::<Currency>().is_err());
+        assert!("US-D".parse::<Currency>().is_err());
+    }
+}
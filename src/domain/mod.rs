@@ -1,2 +1,5 @@
 pub mod user_account;
 pub mod transaction;
+pub mod rejection;
+pub mod summary;
+pub mod currency;
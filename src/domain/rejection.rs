@@ -0,0 +1,174 @@
+use crate::domain::transaction::TrxType;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rejection {
+    Malformed,
+    /// Row whose `type` is not a known transaction type
+    UnknownType,
+    MissingAmount,
+    DuplicateTx,
+    /// Deposit retry whose details differ from the recorded deposit
+    ConflictingDuplicate,
+    InsufficientFunds,
+    TxNotFound,
+    ClientMismatch,
+    /// The client (or the referenced tx record's client) has no account
+    AccountMissing,
+    AlreadyUnderDispute,
+    AlreadyChargedBack,
+    NotUnderDispute,
+    /// Partial resolve of zero, or of more than is still disputed
+    ExceedsDisputed,
+    /// Chargeback of more than the account holds; state is inconsistent
+    InsufficientHeld,
+    AccountLocked,
+    ClientTxLimit,
+    /// New account refused because `max_accounts` is reached
+    AccountLimit,
+    /// Client excluded by the allow/deny lists
+    ClientNotAllowed,
+    /// Client ID outside `min_client..=max_client`, likely misrouted
+    ClientOutOfRange,
+    /// Exceeded `client_rate_limit` on its connection
+    RateLimited,
+    /// Unlock received while `allow_unlock` is off
+    UnlockDisabled,
+    /// Unlock target is not locked
+    AccountNotLocked,
+    /// Unlock target still has transactions under dispute
+    OpenDisputes,
+    /// Deposit, withdrawal or fee on an account frozen by an operator
+    AccountFrozen,
+    /// Thaw target is not frozen
+    AccountNotFrozen,
+    /// Withdrawal while the account has open disputes and
+    /// `block_withdrawals_during_dispute` is on
+    FundsUnderDispute,
+    /// The write-ahead log entry could not be written, so the transaction was
+    /// not applied
+    LogWriteFailed,
+    /// Decimal overflow or underflow on the given balance
+    Overflow(BalanceField),
+    /// Account broke `total = available + held` under `abort_on_invariant_violation`;
+    /// processors turn this into a fatal error
+    InvariantViolated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BalanceField {
+    Available,
+    Held,
+    Total,
+}
+
+impl BalanceField {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BalanceField::Available => "available",
+            BalanceField::Held => "held",
+            BalanceField::Total => "total",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RejectionSummary {
+    pub applied: usize,
+    pub rejected: BTreeMap<Rejection, usize>,
+    /// Parsed records per type, whatever their outcome
+    pub by_type: BTreeMap<TrxType, usize>,
+    /// Every tx ID rejected as a duplicate or conflicting reuse
+    pub reused_tx_ids: BTreeSet<u32>,
+}
+
+impl RejectionSummary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, outcome: Result<(), Rejection>) {
+        match outcome {
+            Ok(()) => self.applied += 1,
+            Err(rejection) => self.reject(rejection),
+        }
+    }
+
+    /// Like `record`, also noting `tx` when the outcome marks it as reused.
+    pub fn record_tx(&mut self, tx: u32, outcome: Result<(), Rejection>) {
+        if matches!(outcome, Err(Rejection::DuplicateTx | Rejection::ConflictingDuplicate)) {
+            self.reused_tx_ids.insert(tx);
+        }
+        self.record(outcome);
+    }
+
+    pub fn reject(&mut self, rejection: Rejection) {
+        *self.rejected.entry(rejection).or_insert(0) += 1;
+    }
+
+    pub fn record_type(&mut self, tx_type: TrxType) {
+        *self.by_type.entry(tx_type).or_insert(0) += 1;
+    }
+
+    pub fn type_count(&self, tx_type: TrxType) -> usize {
+        self.by_type.get(&tx_type).copied().unwrap_or(0)
+    }
+
+    /// `deposit=4, withdrawal=2, ...` for logging.
+    pub fn type_breakdown(&self) -> String {
+        self.by_type
+            .iter()
+            .map(|(tx_type, count)| format!("{}={}", tx_type.name(), count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn count(&self, rejection: Rejection) -> usize {
+        self.rejected.get(&rejection).copied().unwrap_or(0)
+    }
+
+    pub fn total_rejected(&self) -> usize {
+        self.rejected.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_counts() {
+        let mut summary = RejectionSummary::new();
+        summary.record(Ok(()));
+        summary.record(Err(Rejection::DuplicateTx));
+        summary.record(Err(Rejection::DuplicateTx));
+        summary.reject(Rejection::Malformed);
+
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.count(Rejection::DuplicateTx), 2);
+        assert_eq!(summary.count(Rejection::Malformed), 1);
+        assert_eq!(summary.count(Rejection::TxNotFound), 0);
+        assert_eq!(summary.total_rejected(), 3);
+
+        summary.record_type(TrxType::Deposit);
+        summary.record_type(TrxType::Deposit);
+        assert_eq!(summary.type_count(TrxType::Deposit), 2);
+        assert_eq!(summary.type_count(TrxType::Fee), 0);
+        summary.record_type(TrxType::Chargeback);
+        assert_eq!(summary.type_breakdown(), "deposit=2, chargeback=1");
+    }
+
+    #[test]
+    fn test_record_tx_collects_reused_ids() {
+        let mut summary = RejectionSummary::new();
+        summary.record_tx(1, Ok(()));
+        summary.record_tx(1, Err(Rejection::DuplicateTx));
+        summary.record_tx(2, Err(Rejection::ConflictingDuplicate));
+        summary.record_tx(3, Err(Rejection::InsufficientFunds));
+        summary.record_tx(1, Err(Rejection::DuplicateTx));
+
+        assert_eq!(summary.reused_tx_ids, BTreeSet::from([1, 2]));
+        assert_eq!(summary.count(Rejection::DuplicateTx), 2);
+        assert_eq!(summary.applied, 1);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::domain::currency::Currency;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// System-wide totals across every account. A balance is `None` if summing it
+/// overflowed `Decimal`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub available: Option<Decimal>,
+    pub held: Option<Decimal>,
+    pub total: Option<Decimal>,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Summary {
+            accounts: 0,
+            locked_accounts: 0,
+            available: Some(Decimal::ZERO),
+            held: Some(Decimal::ZERO),
+            total: Some(Decimal::ZERO),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let amount = |value: Option<Decimal>| match value {
+            // Balances carry the ingest scale; normalized so the trailer reads as before
+            Some(v) => v.round_dp(4).normalize().to_string(),
+            None => "overflow".to_string(),
+        };
+        write!(
+            f,
+            "accounts={} locked={} available={} held={} total={}",
+            self.accounts,
+            self.locked_accounts,
+            amount(self.available),
+            amount(self.held),
+            amount(self.total)
+        )
+    }
+}
+
+/// Balances of one currency as implied by the retained history versus as held
+/// by accounts. `None` means the sum overflowed `Decimal`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurrencyReconciliation {
+    /// Deposits minus withdrawals, fees and chargebacks
+    pub expected: Option<Decimal>,
+    /// Sum of account totals
+    pub actual: Option<Decimal>,
+}
+
+impl Default for CurrencyReconciliation {
+    fn default() -> Self {
+        CurrencyReconciliation {
+            expected: Some(Decimal::ZERO),
+            actual: Some(Decimal::ZERO),
+        }
+    }
+}
+
+impl CurrencyReconciliation {
+    /// `actual - expected`, or `None` if either side overflowed.
+    pub fn drift(&self) -> Option<Decimal> {
+        self.actual?.checked_sub(self.expected?)
+    }
+}
+
+/// Engine size counters. `estimated_bytes` covers the account map and history
+/// tables from entry sizes and allocated capacity, not allocator overhead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EngineStats {
+    pub accounts: usize,
+    pub tx_history: usize,
+    /// Records dropped from history so far
+    pub evicted: u64,
+    pub estimated_bytes: usize,
+}
+
+impl fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "accounts={} tx_history={} evicted={} estimated_bytes={}",
+            self.accounts, self.tx_history, self.evicted, self.estimated_bytes
+        )
+    }
+}
+
+/// An account whose `held` is less than the amounts its open disputes refer
+/// to, found by `PaymentsEngine::self_check`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeldMismatch {
+    pub client: u16,
+    pub currency: Currency,
+    /// Sum of the account's records under dispute
+    pub disputed: Decimal,
+    /// `held` on the account, zero if the account is missing
+    pub held: Decimal,
+}
+
+/// Output of `PaymentsEngine::reconcile`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Reconciliation {
+    pub currencies: BTreeMap<Currency, CurrencyReconciliation>,
+    /// Records dropped from history so far. Their effect is still in account
+    /// totals, so any non-zero count makes drift expected rather than a bug.
+    pub evicted: u64,
+}
+
+impl Reconciliation {
+    /// Currencies whose totals disagree with history, including overflowed ones.
+    pub fn discrepancies(&self) -> Vec<(Currency, Option<Decimal>)> {
+        self.currencies
+            .iter()
+            .filter(|(_, entry)| entry.drift() != Some(Decimal::ZERO))
+            .map(|(&currency, entry)| (currency, entry.drift()))
+            .collect()
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.discrepancies().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_summary_display() {
+        let summary = Summary {
+            accounts: 2,
+            locked_accounts: 1,
+            available: Some(dec!(1.23456)),
+            held: None,
+            total: Some(dec!(0)),
+        };
+        assert_eq!(
+            summary.to_string(),
+            "accounts=2 locked=1 available=1.2346 held=overflow total=0"
+        );
+
+        // Ingest-scaled balances print the same as unscaled ones
+        let scaled = Summary { available: Some(dec!(2.5000)), total: Some(dec!(0.0000)), ..summary };
+        assert_eq!(scaled.to_string(), "accounts=2 locked=1 available=2.5 held=overflow total=0");
+    }
+
+    #[test]
+    fn test_reconciliation_discrepancies() {
+        let mut report = Reconciliation::default();
+        report.currencies.insert(Currency::DEFAULT, CurrencyReconciliation {
+            expected: Some(dec!(10.0)),
+            actual: Some(dec!(10.00)),
+        });
+        assert!(report.is_balanced());
+
+        let other = "EUR".parse::<Currency>().unwrap();
+        report.currencies.insert(other, CurrencyReconciliation {
+            expected: Some(dec!(5.0)),
+            actual: Some(dec!(4.5)),
+        });
+        assert_eq!(report.discrepancies(), vec![(other, Some(dec!(-0.5)))]);
+        assert!(!report.is_balanced());
+    }
+}
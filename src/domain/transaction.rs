@@ -1,21 +1,76 @@
+use crate::domain::currency::Currency;
+use jiff::Timestamp;
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TrxStatus {
     Normal,
     UnderDispute,
+    /// Dispute was resolved; the record may be disputed again
+    Resolved,
     ChargedBack,
 }
 
-#[derive(Debug, Deserialize)]
+impl TrxStatus {
+    /// The dispute state machine: a record not under dispute may be disputed,
+    /// an open dispute is resolved or charged back, and a chargeback is final.
+    /// A resolved record returns to the disputable state as `Resolved` rather
+    /// than `Normal`. Direct chargebacks of undisputed records are a config
+    /// exception handled by the engine, not a transition listed here.
+    pub fn can_transition(from: TrxStatus, to: TrxStatus) -> bool {
+        use TrxStatus::*;
+        matches!(
+            (from, to),
+            (Normal | Resolved, UnderDispute) | (UnderDispute, Resolved | ChargedBack)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TrxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
+    /// Releases part of a disputed amount; the rest stays disputed
+    #[serde(rename = "partial_resolve")]
+    PartialResolve,
     Chargeback,
+    Fee,
+    Unlock,
+    Freeze,
+    Thaw,
+    /// Any unrecognised `type` value; such rows are counted and skipped
+    #[serde(other)]
+    Unknown,
+}
+
+impl TrxType {
+    /// Matches a `type` value exactly as the input deserializer does.
+    pub fn from_name(name: &str) -> Self {
+        use serde::de::IntoDeserializer;
+        let deserializer: serde::de::value::StrDeserializer<'_, serde::de::value::Error> = name.into_deserializer();
+        TrxType::deserialize(deserializer).unwrap_or(TrxType::Unknown)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrxType::Deposit => "deposit",
+            TrxType::Withdrawal => "withdrawal",
+            TrxType::Dispute => "dispute",
+            TrxType::Resolve => "resolve",
+            TrxType::PartialResolve => "partial_resolve",
+            TrxType::Chargeback => "chargeback",
+            TrxType::Fee => "fee",
+            TrxType::Unlock => "unlock",
+            TrxType::Freeze => "freeze",
+            TrxType::Thaw => "thaw",
+            TrxType::Unknown => "unknown",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +81,11 @@ pub struct RawTrxRecord {
     pub tx: u32,
     #[serde(default)]
     pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// RFC 3339, e.g. `2024-01-31T12:00:00Z`
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
 }
 
 #[derive(Debug, Clone)]
@@ -34,7 +94,16 @@ pub enum Trx {
     Withdrawal { client: u16, tx: u32, amount: Decimal },
     Dispute { client: u16, tx: u32 },
     Resolve { client: u16, tx: u32 },
+    PartialResolve { client: u16, tx: u32, amount: Decimal },
     Chargeback { client: u16, tx: u32 },
+    Fee { client: u16, tx: u32, amount: Decimal },
+    /// Clears `locked` once the account has no open disputes
+    Unlock { client: u16, tx: u32 },
+    /// Operator freeze: blocks the client's deposits, withdrawals and fees in
+    /// every currency without touching balances
+    Freeze { client: u16, tx: u32 },
+    /// Lifts an operator freeze
+    Thaw { client: u16, tx: u32 },
 }
 
 impl Trx {
@@ -64,20 +133,171 @@ impl Trx {
                 client: raw.client,
                 tx: raw.tx,
             }),
+            TrxType::PartialResolve => {
+                let amount = raw.amount?;
+                Some(Trx::PartialResolve {
+                    client: raw.client,
+                    tx: raw.tx,
+                    amount,
+                })
+            }
             TrxType::Chargeback => Some(Trx::Chargeback {
                 client: raw.client,
                 tx: raw.tx,
             }),
+            TrxType::Fee => {
+                let amount = raw.amount?;
+                Some(Trx::Fee {
+                    client: raw.client,
+                    tx: raw.tx,
+                    amount,
+                })
+            }
+            TrxType::Unlock => Some(Trx::Unlock {
+                client: raw.client,
+                tx: raw.tx,
+            }),
+            TrxType::Freeze => Some(Trx::Freeze {
+                client: raw.client,
+                tx: raw.tx,
+            }),
+            TrxType::Thaw => Some(Trx::Thaw {
+                client: raw.client,
+                tx: raw.tx,
+            }),
+            TrxType::Unknown => None,
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        match *self {
+            Trx::Deposit { client, .. }
+            | Trx::Withdrawal { client, .. }
+            | Trx::Dispute { client, .. }
+            | Trx::Resolve { client, .. }
+            | Trx::PartialResolve { client, .. }
+            | Trx::Chargeback { client, .. }
+            | Trx::Fee { client, .. }
+            | Trx::Unlock { client, .. }
+            | Trx::Freeze { client, .. }
+            | Trx::Thaw { client, .. } => client,
         }
     }
 
+    pub fn tx(&self) -> u32 {
+        match *self {
+            Trx::Deposit { tx, .. }
+            | Trx::Withdrawal { tx, .. }
+            | Trx::Dispute { tx, .. }
+            | Trx::Resolve { tx, .. }
+            | Trx::PartialResolve { tx, .. }
+            | Trx::Chargeback { tx, .. }
+            | Trx::Fee { tx, .. }
+            | Trx::Unlock { tx, .. }
+            | Trx::Freeze { tx, .. }
+            | Trx::Thaw { tx, .. } => tx,
+        }
+    }
+
+    pub fn amount(&self) -> Option<Decimal> {
+        match *self {
+            Trx::Deposit { amount, .. }
+            | Trx::Withdrawal { amount, .. }
+            | Trx::Fee { amount, .. }
+            | Trx::PartialResolve { amount, .. } => Some(amount),
+            Trx::Dispute { .. }
+            | Trx::Resolve { .. }
+            | Trx::Chargeback { .. }
+            | Trx::Unlock { .. }
+            | Trx::Freeze { .. }
+            | Trx::Thaw { .. } => None,
+        }
+    }
+
+    pub fn tx_type(&self) -> TrxType {
+        match self {
+            Trx::Deposit { .. } => TrxType::Deposit,
+            Trx::Withdrawal { .. } => TrxType::Withdrawal,
+            Trx::Dispute { .. } => TrxType::Dispute,
+            Trx::Resolve { .. } => TrxType::Resolve,
+            Trx::PartialResolve { .. } => TrxType::PartialResolve,
+            Trx::Chargeback { .. } => TrxType::Chargeback,
+            Trx::Fee { .. } => TrxType::Fee,
+            Trx::Unlock { .. } => TrxType::Unlock,
+            Trx::Freeze { .. } => TrxType::Freeze,
+            Trx::Thaw { .. } => TrxType::Thaw,
+        }
+    }
+
+    /// The input `type` column value for this transaction.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Trx::Deposit { .. } => "deposit",
+            Trx::Withdrawal { .. } => "withdrawal",
+            Trx::Dispute { .. } => "dispute",
+            Trx::Resolve { .. } => "resolve",
+            Trx::PartialResolve { .. } => "partial_resolve",
+            Trx::Chargeback { .. } => "chargeback",
+            Trx::Fee { .. } => "fee",
+            Trx::Unlock { .. } => "unlock",
+            Trx::Freeze { .. } => "freeze",
+            Trx::Thaw { .. } => "thaw",
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxKind {
+    Deposit,
+    Withdrawal,
+    Fee,
+}
+
+impl TxKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxKind::Deposit => "deposit",
+            TxKind::Withdrawal => "withdrawal",
+            TxKind::Fee => "fee",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TxRecord {
+    pub kind: TxKind,
     pub client: u16,
+    pub currency: Currency,
     pub amount: Decimal,
     pub status: TrxStatus,
+    /// Position in the engine's insertion sequence; lower is older
+    pub insertion_ordinal: u64,
+    /// Part of the current dispute already released by partial resolves
+    pub resolved: Decimal,
+    /// When the input row carried one; absent rows store `None`
+    pub timestamp: Option<Timestamp>,
+}
+
+impl TxRecord {
+    /// An undisputed record; the engine assigns the insertion ordinal.
+    pub fn new(kind: TxKind, client: u16, currency: Currency, amount: Decimal, timestamp: Option<Timestamp>) -> Self {
+        TxRecord {
+            kind,
+            client,
+            currency,
+            amount,
+            status: TrxStatus::Normal,
+            insertion_ordinal: 0,
+            resolved: Decimal::ZERO,
+            timestamp,
+        }
+    }
+
+    /// Amount still held by the record's dispute.
+    pub fn disputed_amount(&self) -> Decimal {
+        self.amount - self.resolved
+    }
 }
 
 #[cfg(test)]
@@ -85,6 +305,44 @@ mod tests {
     use super::*;
     use rust_decimal_macros::dec;
 
+    #[test]
+    fn test_status_transitions_exhaustive() {
+        use TrxStatus::*;
+        let all = [Normal, UnderDispute, Resolved, ChargedBack];
+        let legal = [
+            (Normal, UnderDispute),
+            (Resolved, UnderDispute),
+            (UnderDispute, Resolved),
+            (UnderDispute, ChargedBack),
+        ];
+        for from in all {
+            for to in all {
+                assert_eq!(
+                    TrxStatus::can_transition(from, to),
+                    legal.contains(&(from, to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_trx_status_serde_round_trip() {
+        let cases = [
+            (TrxStatus::Normal, "\"normal\""),
+            (TrxStatus::UnderDispute, "\"underdispute\""),
+            (TrxStatus::Resolved, "\"resolved\""),
+            (TrxStatus::ChargedBack, "\"chargedback\""),
+        ];
+        for (status, json) in cases {
+            assert_eq!(serde_json::to_string(&status).unwrap(), json);
+            assert_eq!(serde_json::from_str::<TrxStatus>(json).unwrap(), status);
+        }
+        assert!(serde_json::from_str::<TrxStatus>("\"Normal\"").is_err());
+    }
+
     #[test]
     fn test_deposit_parsing() {
         let raw = RawTrxRecord {
@@ -92,6 +350,8 @@ mod tests {
             client: 1,
             tx: 100,
             amount: Some(dec!(10.5)),
+            currency: None,
+            timestamp: None,
         };
         let tx = Trx::from_raw(raw).unwrap();
         match tx {
@@ -111,6 +371,8 @@ mod tests {
             client: 2,
             tx: 200,
             amount: None,
+            currency: None,
+            timestamp: None,
         };
         let tx = Trx::from_raw(raw).unwrap();
         match tx {
@@ -129,6 +391,8 @@ mod tests {
             client: 1,
             tx: 100,
             amount: None,
+            currency: None,
+            timestamp: None,
         };
         assert!(Trx::from_raw(raw).is_none());
     }
@@ -140,6 +404,8 @@ mod tests {
             client: 1,
             tx: 1,
             amount: Some(dec!(1.1234)),
+            currency: None,
+            timestamp: None,
         };
         let tx = Trx::from_raw(raw).unwrap();
         match tx {
@@ -149,4 +415,24 @@ mod tests {
             _ => panic!("Wrong transaction type"),
         }
     }
+
+    #[test]
+    fn test_fee_parsing() {
+        let raw = RawTrxRecord {
+            tx_type: TrxType::Fee,
+            client: 3,
+            tx: 7,
+            amount: Some(dec!(1.5)),
+            currency: None,
+            timestamp: None,
+        };
+        match Trx::from_raw(raw).unwrap() {
+            Trx::Fee { client, tx, amount } => {
+                assert_eq!(client, 3);
+                assert_eq!(tx, 7);
+                assert_eq!(amount, dec!(1.5));
+            }
+            _ => panic!("Wrong transaction type"),
+        }
+    }
 }
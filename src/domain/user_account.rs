@@ -1,33 +1,208 @@
+use crate::domain::currency::Currency;
+use crate::error::PaymentError;
 use rust_decimal::Decimal;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
 
 fn serialize_decimal<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    serializer.serialize_str(&format!("{:.4}", value))
+    // Values finer than the output precision keep every digit, so the
+    // serialized form reads back unchanged
+    if value.scale() > OutputPrecision::DEFAULT_PLACES as u32 {
+        return serializer.serialize_str(&value.to_string());
+    }
+    serializer.serialize_str(&format_places(*value, OutputPrecision::DEFAULT_PLACES))
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// The default places keep the plain `{:.4}` output. `Decimal`'s formatter
+/// truncates to the requested precision, so other places are rounded first.
+fn format_places(value: Decimal, places: usize) -> String {
+    if places == OutputPrecision::DEFAULT_PLACES {
+        return format!("{:.4}", value);
+    }
+    format!("{:.*}", places, value.round_dp(places as u32))
+}
+
+fn deserialize_decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    Decimal::from_str(&s).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UserAccount {
     pub client: u16,
-    #[serde(serialize_with = "serialize_decimal")]
+    #[serde(default, skip_serializing_if = "Currency::is_default")]
+    pub currency: Currency,
+    #[serde(serialize_with = "serialize_decimal", deserialize_with = "deserialize_decimal")]
     pub available: Decimal,
-    #[serde(serialize_with = "serialize_decimal")]
+    #[serde(serialize_with = "serialize_decimal", deserialize_with = "deserialize_decimal")]
     pub held: Decimal,
-    #[serde(serialize_with = "serialize_decimal")]
+    #[serde(serialize_with = "serialize_decimal", deserialize_with = "deserialize_decimal")]
     pub total: Decimal,
     pub locked: bool,
+    #[serde(default)]
+    pub tx_count: u64,
+    #[serde(default)]
+    pub open_disputes: u32,
+    /// Set by an operator `freeze`; independent of the chargeback `locked`
+    #[serde(default)]
+    pub frozen: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    TxCount,
+    OpenDisputes,
+    Currency,
+    Frozen,
+}
+
+/// Decimal places written for each balance column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputPrecision {
+    pub available: usize,
+    pub held: usize,
+    pub total: usize,
+}
+
+impl OutputPrecision {
+    pub const DEFAULT_PLACES: usize = 4;
+
+    pub fn uniform(places: usize) -> Self {
+        OutputPrecision {
+            available: places,
+            held: places,
+            total: places,
+        }
+    }
+}
+
+impl Default for OutputPrecision {
+    fn default() -> Self {
+        Self::uniform(Self::DEFAULT_PLACES)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    ClientId,
+    /// Map iteration order, skipping the sort for large outputs
+    Unsorted,
+    /// Largest `total` first, ties by client ID
+    TotalDesc,
+    /// Largest `available` first, ties by client ID
+    AvailableDesc,
+}
+
+impl FromStr for SortOrder {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SortOrder::ClientId),
+            "none" => Ok(SortOrder::Unsorted),
+            "total" => Ok(SortOrder::TotalDesc),
+            "available" => Ok(SortOrder::AvailableDesc),
+            other => Err(PaymentError::InvalidConfig(format!("unknown sort order '{}'", other))),
+        }
+    }
+}
+
+impl OutputColumn {
+    pub const DEFAULT: [OutputColumn; 5] = [
+        OutputColumn::Client,
+        OutputColumn::Available,
+        OutputColumn::Held,
+        OutputColumn::Total,
+        OutputColumn::Locked,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputColumn::Client => "client",
+            OutputColumn::Available => "available",
+            OutputColumn::Held => "held",
+            OutputColumn::Total => "total",
+            OutputColumn::Locked => "locked",
+            OutputColumn::TxCount => "tx_count",
+            OutputColumn::OpenDisputes => "open_disputes",
+            OutputColumn::Currency => "currency",
+            OutputColumn::Frozen => "frozen",
+        }
+    }
+
+    /// Parses a comma-separated column list such as `client,total,tx_count`.
+    pub fn parse_list(spec: &str) -> Result<Vec<OutputColumn>, PaymentError> {
+        spec.split(',')
+            .map(|name| name.trim().parse())
+            .collect()
+    }
+}
+
+impl FromStr for OutputColumn {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(OutputColumn::Client),
+            "available" => Ok(OutputColumn::Available),
+            "held" => Ok(OutputColumn::Held),
+            "total" => Ok(OutputColumn::Total),
+            "locked" => Ok(OutputColumn::Locked),
+            "tx_count" => Ok(OutputColumn::TxCount),
+            "open_disputes" => Ok(OutputColumn::OpenDisputes),
+            "currency" => Ok(OutputColumn::Currency),
+            "frozen" => Ok(OutputColumn::Frozen),
+            other => Err(PaymentError::InvalidConfig(format!("unknown output column '{}'", other))),
+        }
+    }
 }
 
 impl UserAccount {
     pub fn new(client_id: u16) -> Self {
+        Self::new_in(client_id, Currency::DEFAULT)
+    }
+
+    pub fn new_in(client_id: u16, currency: Currency) -> Self {
         UserAccount {
             client: client_id,
+            currency,
             available: Decimal::ZERO,
             held: Decimal::ZERO,
             total: Decimal::ZERO,
             locked: false,
+            tx_count: 0,
+            open_disputes: 0,
+            frozen: false,
+        }
+    }
+
+    pub fn column_value(&self, column: OutputColumn) -> String {
+        self.column_value_with(column, &OutputPrecision::default())
+    }
+
+    pub fn column_value_with(&self, column: OutputColumn, precision: &OutputPrecision) -> String {
+        match column {
+            OutputColumn::Client => self.client.to_string(),
+            OutputColumn::Available => format_places(self.available, precision.available),
+            OutputColumn::Held => format_places(self.held, precision.held),
+            OutputColumn::Total => format_places(self.total, precision.total),
+            OutputColumn::Locked => self.locked.to_string(),
+            OutputColumn::TxCount => self.tx_count.to_string(),
+            OutputColumn::OpenDisputes => self.open_disputes.to_string(),
+            OutputColumn::Currency => self.currency.to_string(),
+            OutputColumn::Frozen => self.frozen.to_string(),
         }
     }
 
@@ -60,6 +235,7 @@ mod tests {
             held: dec!(5.25),
             total: dec!(15.75),
             locked: false,
+            ..UserAccount::new(1)
         };
         assert!(acc.verify_totals());
     }
@@ -72,7 +248,84 @@ mod tests {
             held: dec!(5.25),
             total: dec!(20.0),
             locked: false,
+            ..UserAccount::new(1)
         };
         assert!(!acc.verify_totals());
     }
+
+    #[test]
+    fn test_parse_column_list() {
+        let columns = OutputColumn::parse_list("total, client,tx_count").unwrap();
+        assert_eq!(
+            columns,
+            vec![OutputColumn::Total, OutputColumn::Client, OutputColumn::TxCount]
+        );
+        assert!(OutputColumn::parse_list("client,balance").is_err());
+    }
+
+    #[test]
+    fn test_default_precision_matches_plain_format() {
+        let mut account = UserAccount::new(1);
+        account.available = dec!(1.234567);
+        account.held = dec!(-0.000051);
+        assert_eq!(account.column_value(OutputColumn::Available), "1.2345");
+        assert_eq!(account.column_value(OutputColumn::Held), format!("{:.4}", account.held));
+
+        let rounded = OutputPrecision { available: 2, held: 4, total: 4 };
+        assert_eq!(account.column_value_with(OutputColumn::Available, &rounded), "1.23");
+        account.available = dec!(1.235);
+        assert_eq!(account.column_value_with(OutputColumn::Available, &rounded), "1.24");
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let acc = UserAccount {
+            available: dec!(1.5),
+            held: dec!(2.25),
+            total: dec!(3.75),
+            locked: true,
+            tx_count: 4,
+            open_disputes: 1,
+            frozen: true,
+            ..UserAccount::new(7)
+        };
+
+        let json = serde_json::to_string(&acc).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"client":7,"available":"1.5000","held":"2.2500","total":"3.7500","locked":true,"#,
+                r#""tx_count":4,"open_disputes":1,"frozen":true}"#,
+            )
+        );
+        assert_eq!(serde_json::from_str::<UserAccount>(&json).unwrap(), acc);
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(&acc).unwrap();
+        let data = writer.into_inner().unwrap();
+        let mut reader = csv::Reader::from_reader(data.as_slice());
+        let parsed: UserAccount = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(parsed, acc);
+    }
+
+    #[test]
+    fn test_serde_keeps_digits_past_output_precision() {
+        let acc = UserAccount { available: dec!(0.123456), total: dec!(0.123456), ..UserAccount::new(1) };
+        let json = serde_json::to_string(&acc).unwrap();
+        assert!(json.contains(r#""available":"0.123456""#));
+        assert_eq!(serde_json::from_str::<UserAccount>(&json).unwrap(), acc);
+    }
+
+    #[test]
+    fn test_deserialize_defaults_missing_counters() {
+        let json = r#"{"client":1,"available":"1","held":"0","total":"1","locked":false}"#;
+        let acc: UserAccount = serde_json::from_str(json).unwrap();
+        assert_eq!((acc.tx_count, acc.open_disputes, acc.frozen), (0, 0, false));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_bad_decimal() {
+        let json = r#"{"client":1,"available":"abc","held":"0","total":"0","locked":false}"#;
+        assert!(serde_json::from_str::<UserAccount>(json).is_err());
+    }
 }
@@ -1,19 +1,57 @@
+use crate::domain::rejection::Rejection;
 use rust_decimal::Decimal;
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum PaymentError {
     FileNotFound(String),
+    OutputFileError(String, std::io::Error),
     CsvError(csv::Error),
+    JsonError(serde_json::Error),
+    /// A parse error tagged with the 1-based input line it occurred on
+    MalformedRow {
+        line: u64,
+        source: Box<PaymentError>,
+    },
     IoError(std::io::Error),
     InvalidTransaction(String),
+    InvalidConfig(String),
+    RequestTimeout(Duration),
+    RequestTooLarge(usize),
+    Cancelled,
+    TxIdCollision(Vec<u32>),
+    /// Combining the client's balances in a merge would overflow
+    MergeOverflow(u16),
+    /// Output failed `verify_footer`: no readable footer, or rows that don't
+    /// match its count or checksum
+    FooterMismatch(String),
+    ValidationFailed(usize),
+    /// Amount has more decimal places than `decimal_precision` allows
+    PrecisionExceeded {
+        tx: u32,
+        scale: u32,
+    },
+    /// Invalid UTF-8 in a row; `offset` is where the row starts in the input
+    /// and `field` is 1-based
+    InvalidUtf8 {
+        offset: u64,
+        field: usize,
+    },
     InsufficientFunds {
         client: u16,
         available: Decimal,
         requested: Decimal,
     },
     AccountLocked(u16),
+    /// `total != available + held` for the client under `abort_on_invariant_violation`
+    InvariantViolation(u16),
+    /// A transaction was rejected under `fail_fast`
+    Rejected {
+        tx: u32,
+        rejection: Rejection,
+    },
     TransactionNotFound(u32),
     InvalidDispute {
         tx_id: u32,
@@ -25,9 +63,36 @@ impl fmt::Display for PaymentError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             PaymentError::FileNotFound(path) => write!(f, "File not found: {}", path),
+            PaymentError::OutputFileError(path, e) => {
+                write!(f, "Cannot create output file {}: {}", path, e)
+            }
             PaymentError::CsvError(e) => write!(f, "CSV error: {}", e),
+            PaymentError::JsonError(e) => write!(f, "JSON error: {}", e),
+            PaymentError::MalformedRow { line, source } => {
+                write!(f, "Malformed row at line {}: {}", line, source)
+            }
             PaymentError::IoError(e) => write!(f, "I/O error: {}", e),
             PaymentError::InvalidTransaction(msg) => write!(f, "Invalid transaction: {}", msg),
+            PaymentError::InvalidConfig(msg) => write!(f, "Invalid configuration: {}", msg),
+            PaymentError::RequestTimeout(timeout) => {
+                write!(f, "Request not received within {:?}", timeout)
+            }
+            PaymentError::RequestTooLarge(limit) => {
+                write!(f, "Request exceeds maximum size of {} bytes", limit)
+            }
+            PaymentError::Cancelled => write!(f, "Processing cancelled"),
+            PaymentError::TxIdCollision(ids) => {
+                write!(f, "Transaction IDs present in both sources: {:?}", ids)
+            }
+            PaymentError::ValidationFailed(count) => {
+                write!(f, "Validation failed with {} problem(s)", count)
+            }
+            PaymentError::PrecisionExceeded { tx, scale } => {
+                write!(f, "Amount for tx {} has {} decimal places", tx, scale)
+            }
+            PaymentError::InvalidUtf8 { offset, field } => {
+                write!(f, "Invalid UTF-8 in field {} of the row starting at byte {}", field, offset)
+            }
             PaymentError::InsufficientFunds {
                 client,
                 available,
@@ -40,6 +105,16 @@ impl fmt::Display for PaymentError {
             PaymentError::AccountLocked(client) => {
                 write!(f, "Account {} is locked due to chargeback", client)
             }
+            PaymentError::MergeOverflow(client) => {
+                write!(f, "Balance overflow merging client {}", client)
+            }
+            PaymentError::FooterMismatch(reason) => write!(f, "Output footer check failed: {}", reason),
+            PaymentError::InvariantViolation(client) => {
+                write!(f, "Balance invariant violated for client {}: total != available + held", client)
+            }
+            PaymentError::Rejected { tx, rejection } => {
+                write!(f, "Transaction {} rejected: {:?}", tx, rejection)
+            }
             PaymentError::TransactionNotFound(tx_id) => {
                 write!(f, "Transaction {} not found", tx_id)
             }
@@ -54,7 +129,10 @@ impl std::error::Error for PaymentError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             PaymentError::CsvError(e) => Some(e),
+            PaymentError::JsonError(e) => Some(e),
+            PaymentError::MalformedRow { source, .. } => Some(source.as_ref()),
             PaymentError::IoError(e) => Some(e),
+            PaymentError::OutputFileError(_, e) => Some(e),
             _ => None,
         }
     }
@@ -66,6 +144,12 @@ impl From<csv::Error> for PaymentError {
     }
 }
 
+impl From<serde_json::Error> for PaymentError {
+    fn from(err: serde_json::Error) -> Self {
+        PaymentError::JsonError(err)
+    }
+}
+
 impl From<std::io::Error> for PaymentError {
     fn from(err: std::io::Error) -> Self {
         PaymentError::IoError(err)
@@ -1,12 +1,21 @@
-mod domain;
-mod services;
-mod config;
-mod error;
-
+use payments_engine::config::ProcessorConfig;
+use payments_engine::error::PaymentError;
+use payments_engine::services::trx_processor::{validate_file, TrxProcessor};
 use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::process;
-use services::trx_processor::TrxProcessor;
-use error::PaymentError;
+
+const USAGE_FLAGS: &str = "[--sort-output=client|none|total|available] [--output <path>] [--summary] [--stats] [--validate] [--strict] [--fail-fast]";
+
+struct CliArgs {
+    filepath: String,
+    output: Option<String>,
+    summary: bool,
+    stats: bool,
+    validate: bool,
+    config: ProcessorConfig,
+}
 
 #[tokio::main]
 async fn main() {
@@ -14,24 +23,122 @@ async fn main() {
 
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <transactions.csv>", args[0]);
-        process::exit(1);
-    }
-
-    let filepath = &args[1];
+    let cli = match parse_args(&args[1..]) {
+        Ok(Some(cli)) => cli,
+        Ok(None) => {
+            eprintln!("Usage: {} <transactions.csv> {}", args[0], USAGE_FLAGS);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("Usage: {} <transactions.csv> {}", args[0], USAGE_FLAGS);
+            process::exit(1);
+        }
+    };
 
-    if let Err(e) = run(filepath).await {
+    if let Err(e) = run(cli).await {
         log::error!("Failed to process transactions: {}", e);
         eprintln!("Error: {}", e);
         process::exit(1);
     }
 }
 
-async fn run(filepath: &str) -> Result<(), PaymentError> {
-    let mut processor = TrxProcessor::new();
-    processor.process_file(filepath).await?;
-    processor.write_results(std::io::stdout())?;
-    Ok(())
+fn parse_args(args: &[String]) -> Result<Option<CliArgs>, PaymentError> {
+    let mut filepath = None;
+    let mut output = None;
+    let mut summary = false;
+    let mut stats = false;
+    let mut validate = false;
+    let mut sort_order = None;
+    let mut strict = false;
+    let mut fail_fast = false;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if let Some(order) = arg.strip_prefix("--sort-output=") {
+            sort_order = Some(order.parse()?);
+        } else if let Some(path) = arg.strip_prefix("--output=") {
+            output = Some(path.to_string());
+        } else if arg == "--output" {
+            let path = args.next().ok_or_else(|| {
+                PaymentError::InvalidConfig("--output requires a path".to_string())
+            })?;
+            output = Some(path.clone());
+        } else if arg == "--summary" {
+            summary = true;
+        } else if arg == "--stats" {
+            stats = true;
+        } else if arg == "--validate" {
+            validate = true;
+        } else if arg == "--strict" {
+            strict = true;
+        } else if arg == "--fail-fast" {
+            fail_fast = true;
+        } else if arg.starts_with("--") {
+            return Err(PaymentError::InvalidConfig(format!("unknown flag '{}'", arg)));
+        } else if filepath.replace(arg.clone()).is_some() {
+            return Ok(None);
+        }
+    }
+
+    // Validation checks the input against the strict parsing rules; env
+    // overrides and flags still apply on top, in that order
+    let base = if validate { ProcessorConfig::strict() } else { ProcessorConfig::default() };
+    let mut config = base.from_env()?;
+    if let Some(order) = sort_order {
+        config = config.with_sort_order(order);
+    }
+    if strict {
+        config = config
+            .with_skip_malformed(false)
+            .with_case_insensitive_types(false)
+            .with_flexible_columns(false)
+            .with_reject_excess_precision(true);
+    }
+    if fail_fast {
+        config = config.with_fail_fast(true);
+    }
+
+    Ok(filepath.map(|filepath| CliArgs { filepath, output, summary, stats, validate, config }))
 }
 
+async fn run(cli: CliArgs) -> Result<(), PaymentError> {
+    if cli.validate {
+        return validate(&cli);
+    }
+
+    // Opened first, so a bad path fails before the input is processed
+    let writer: Box<dyn Write> = match cli.output {
+        Some(path) => {
+            let file = File::create(&path)
+                .map_err(|e| PaymentError::OutputFileError(path.clone(), e))?;
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(std::io::stdout()),
+    };
+
+    let mut processor = TrxProcessor::with_config(cli.config);
+    processor.process_file(&cli.filepath).await?;
+    processor.engine().flush().await?;
+    if cli.stats {
+        eprintln!("{}", processor.engine().stats().await);
+    }
+    if cli.summary {
+        processor.write_results_with_summary(writer).await
+    } else {
+        processor.write_results(writer).await
+    }
+}
+
+/// Reports every problem in the input on stderr; writes nothing to stdout.
+fn validate(cli: &CliArgs) -> Result<(), PaymentError> {
+    let report = validate_file(&cli.filepath, &cli.config)?;
+    for issue in &report.issues {
+        eprintln!("{}", issue);
+    }
+    if !report.is_valid() {
+        return Err(PaymentError::ValidationFailed(report.issues.len()));
+    }
+    eprintln!("{} rows valid", report.rows);
+    Ok(())
+}
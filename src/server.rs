@@ -1,25 +1,115 @@
-use payments_engine::config::ProcessorConfig;
-use payments_engine::domain::transaction::{RawTrxRecord, Trx};
+use payments_engine::config::{OutputFormat, ProcessorConfig};
 use payments_engine::error::PaymentError;
 use payments_engine::services::payment_engine::PaymentsEngine;
-use std::io::Cursor;
+use payments_engine::services::{snapshot, tcp_server};
+use payments_engine::services::wal::{self, WriteAheadLog};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::net::TcpListener;
 
 #[tokio::main]
 async fn main() -> Result<(), PaymentError> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let args: Vec<String> = std::env::args().collect();
-    let bind_addr = if args.len() > 1 {
-        args[1].as_str()
-    } else {
-        "0.0.0.0:8080"
-    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut bind_addr = "0.0.0.0:8080";
+    let mut wal_path = None;
+    let mut wal_buffered = false;
+    let mut stream_requests = false;
+    let mut max_connections = None;
+    let mut client_rate_limit = None;
+    let mut isolated = false;
+    let mut output_footer = false;
+    let mut delta_output = false;
+    let mut output_format = None;
+    let mut snapshot_path = None;
+    let mut snapshot_every = None;
+    let mut snapshot_interval = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--wal" {
+            wal_path = Some(args.next().ok_or_else(|| {
+                PaymentError::InvalidConfig("--wal requires a path".to_string())
+            })?);
+        } else if arg == "--wal-buffered" {
+            wal_buffered = true;
+        } else if arg == "--max-connections" {
+            let max = args.next().and_then(|max| max.parse::<usize>().ok()).ok_or_else(|| {
+                PaymentError::InvalidConfig("--max-connections requires a count".to_string())
+            })?;
+            max_connections = Some(max);
+        } else if arg == "--client-rate" {
+            let rate = args.next().and_then(|rate| rate.parse::<u32>().ok()).ok_or_else(|| {
+                PaymentError::InvalidConfig("--client-rate requires transactions per second".to_string())
+            })?;
+            client_rate_limit = Some(rate);
+        } else if arg == "--format" {
+            output_format = Some(args
+                .next()
+                .ok_or_else(|| PaymentError::InvalidConfig("--format requires csv or json".to_string()))?
+                .parse::<OutputFormat>()?);
+        } else if arg == "--snapshot" {
+            snapshot_path = Some(PathBuf::from(args.next().ok_or_else(|| {
+                PaymentError::InvalidConfig("--snapshot requires a path".to_string())
+            })?));
+        } else if arg == "--snapshot-every" {
+            let every = args.next().and_then(|every| every.parse::<u64>().ok()).ok_or_else(|| {
+                PaymentError::InvalidConfig("--snapshot-every requires a transaction count".to_string())
+            })?;
+            snapshot_every = Some(every);
+        } else if arg == "--snapshot-secs" {
+            let secs = args.next().and_then(|secs| secs.parse::<u64>().ok()).ok_or_else(|| {
+                PaymentError::InvalidConfig("--snapshot-secs requires a number of seconds".to_string())
+            })?;
+            snapshot_interval = Some(Duration::from_secs(secs));
+        } else if arg == "--isolated" {
+            isolated = true;
+        } else if arg == "--delta" {
+            delta_output = true;
+        } else if arg == "--footer" {
+            output_footer = true;
+        } else if arg == "--stream" {
+            stream_requests = true;
+        } else {
+            bind_addr = arg.as_str();
+        }
+    }
 
-    let config = ProcessorConfig::production();
-    let engine = Arc::new(PaymentsEngine::with_max_history(config.max_tx_history));
+    if snapshot_path.is_some() && snapshot_every.is_none() && snapshot_interval.is_none() {
+        return Err(PaymentError::InvalidConfig(
+            "--snapshot requires --snapshot-every or --snapshot-secs".to_string(),
+        ));
+    }
+
+    // Flags override PE_* variables, which override the production defaults
+    let base = ProcessorConfig::production().from_env()?;
+    let config = base
+        .clone()
+        .with_stream_requests(stream_requests)
+        .with_max_connections(max_connections.or(base.max_connections))
+        .with_client_rate_limit(client_rate_limit.or(base.client_rate_limit))
+        .with_isolated_connections(isolated)
+        .with_output_footer(output_footer)
+        .with_delta_output(delta_output)
+        .with_output_format(output_format.unwrap_or(base.output_format))
+        .with_snapshot_path(snapshot_path)
+        .with_snapshot_every(snapshot_every)
+        .with_snapshot_interval(snapshot_interval);
+    let mut engine = PaymentsEngine::with_config(config.clone());
+    if let Some(path) = wal_path {
+        let summary = wal::replay(path, &engine).await?;
+        log::info!("Replayed {} transactions from WAL {}", summary.applied, path);
+        let wal = if wal_buffered { WriteAheadLog::open_buffered(path)? } else { WriteAheadLog::open(path)? };
+        engine = engine.with_wal(wal);
+    } else if let Some(path) = &config.snapshot_path {
+        // The WAL already holds everything a snapshot would, so only restore
+        // from the snapshot without one
+        if snapshot::load_snapshot(path, &engine).await? {
+            log::info!("Restored {} accounts from snapshot {}", engine.get_accounts().len(), path.display());
+        }
+    }
+    let engine = Arc::new(engine);
 
     let listener = match TcpListener::bind(bind_addr).await {
         Ok(listener) => listener,
@@ -30,6 +120,17 @@ async fn main() -> Result<(), PaymentError> {
     };
     log::info!("Payment engine server listening on {}", bind_addr);
     log::info!("Max transaction history: {:?}", config.max_tx_history);
+    log::info!("Read timeout: {:?}", config.read_timeout);
+    log::info!("Max connections: {:?}", config.max_connections);
+    log::info!("Per-client rate limit: {:?} tx/s per connection", config.client_rate_limit);
+    if config.isolated_connections {
+        log::info!("Isolated connections: each connection uses its own engine");
+    }
+    if config.stream_requests {
+        log::info!("Streaming requests (no request size limit)");
+    } else {
+        log::info!("Max request size: {:?} bytes", config.max_request_bytes);
+    }
     log::info!("Send CSV transactions via TCP. Server will respond with account states.");
     log::info!("");
     log::info!("CSV Format:");
@@ -38,94 +139,27 @@ async fn main() -> Result<(), PaymentError> {
     log::info!("  withdrawal,1,2,5.0");
     log::info!("");
 
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                let engine = engine.clone();
-                let config = config.clone();
-
-                tokio::spawn(async move {
-                    log::info!("[{}] Connection accepted", addr);
-
-                    if let Err(e) = handle_connection(socket, engine, config, addr).await {
-                        log::error!("[{}] Error: {}", addr, e);
-                    }
-
-                    log::info!("[{}] Connection closed", addr);
-                });
-            }
-            Err(e) => {
-                log::error!("Failed to accept connection: {}", e);
-            }
-        }
-    }
+    let shutdown = async {
+        shutdown_signal().await;
+        log::info!("Shutting down: no new connections, waiting for open ones");
+    };
+    let served = tcp_server::serve_until(listener, engine.clone(), config, shutdown).await;
+    log::info!("Flushing pending writes");
+    engine.flush().await?;
+    served
 }
 
-async fn handle_connection(
-    mut socket: TcpStream,
-    engine: Arc<PaymentsEngine>,
-    config: ProcessorConfig,
-    addr: std::net::SocketAddr,
-) -> Result<(), PaymentError> {
-    let mut buffer = Vec::new();
-    socket.read_to_end(&mut buffer).await?;
-
-    let cursor = Cursor::new(buffer);
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(cursor);
-
-    let mut transaction_count = 0;
-    let mut error_count = 0;
-
-    for result in csv_reader.deserialize::<RawTrxRecord>() {
-        match result {
-            Ok(raw_record) => {
-                if let Some(tx) = Trx::from_raw(raw_record) {
-                    engine.process(tx).await;
-                    transaction_count += 1;
-                } else if config.log_warnings {
-                    log::warn!("[{}] Skipping transaction with missing amount", addr);
-                    error_count += 1;
-                }
-            }
-            Err(e) => {
-                if config.skip_malformed {
-                    if config.log_warnings {
-                        log::warn!("[{}] Skipping malformed row: {}", addr, e);
-                    }
-                    error_count += 1;
-                } else {
-                    return Err(e.into());
-                }
-            }
+/// Resolves on Ctrl-C, or SIGTERM where available.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
         }
     }
-
-    log::info!(
-        "[{}] Processed {} transactions ({} errors/skipped)",
-        addr,
-        transaction_count,
-        error_count
-    );
-
-    log::info!("[{}] Sending account states...", addr);
-
-    let accounts = engine.get_accounts();
-    let mut output = Vec::new();
-    let mut csv_writer = csv::Writer::from_writer(&mut output);
-
-    for account in accounts {
-        csv_writer.serialize(&account)?;
-    }
-
-    csv_writer.flush()?;
-    drop(csv_writer);
-
-    socket.write_all(&output).await?;
-    socket.flush().await?;
-
-    log::info!("[{}] Response sent successfully", addr);
-
-    Ok(())
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
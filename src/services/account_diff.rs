@@ -0,0 +1,170 @@
+use crate::domain::currency::Currency;
+use crate::error::PaymentError;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::str::FromStr;
+
+/// One difference between two account CSVs, keyed by client (and currency,
+/// when the files carry a `currency` column).
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountDiff {
+    /// Account present in the first file only
+    MissingRight { client: u16, currency: Currency },
+    /// Account present in the second file only
+    MissingLeft { client: u16, currency: Currency },
+    FieldMismatch {
+        client: u16,
+        currency: Currency,
+        field: String,
+        left: String,
+        right: String,
+    },
+}
+
+type Rows = BTreeMap<(u16, Currency), BTreeMap<String, String>>;
+
+/// Compares two account outputs regardless of row order. Numeric fields are
+/// compared by value, so `1.5` and `1.5000` match; a column missing from one
+/// side compares as empty. Diffs are sorted by client, then currency.
+pub fn diff_accounts<A: Read, B: Read>(a: A, b: B) -> Result<Vec<AccountDiff>, PaymentError> {
+    let (left, right) = (read_rows(a)?, read_rows(b)?);
+    let mut keys: Vec<&(u16, Currency)> = left.keys().chain(right.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut diffs = Vec::new();
+    for &(client, currency) in keys {
+        let (left_fields, right_fields) = match (left.get(&(client, currency)), right.get(&(client, currency))) {
+            (Some(l), Some(r)) => (l, r),
+            (Some(_), None) => {
+                diffs.push(AccountDiff::MissingRight { client, currency });
+                continue;
+            }
+            (None, _) => {
+                diffs.push(AccountDiff::MissingLeft { client, currency });
+                continue;
+            }
+        };
+
+        let mut fields: Vec<&String> = left_fields.keys().chain(right_fields.keys()).collect();
+        fields.sort();
+        fields.dedup();
+        for field in fields {
+            let l = left_fields.get(field).map_or("", String::as_str);
+            let r = right_fields.get(field).map_or("", String::as_str);
+            if !same_value(l, r) {
+                diffs.push(AccountDiff::FieldMismatch {
+                    client,
+                    currency,
+                    field: field.clone(),
+                    left: l.to_string(),
+                    right: r.to_string(),
+                });
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+fn same_value(l: &str, r: &str) -> bool {
+    match (Decimal::from_str(l), Decimal::from_str(r)) {
+        (Ok(l), Ok(r)) => l == r,
+        _ => l == r,
+    }
+}
+
+fn read_rows<R: Read>(reader: R) -> Result<Rows, PaymentError> {
+    let mut csv_reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+    let headers = csv_reader.headers()?.clone();
+    let client_index = headers
+        .iter()
+        .position(|h| h == "client")
+        .ok_or_else(|| PaymentError::InvalidTransaction("account CSV has no client column".to_string()))?;
+    let currency_index = headers.iter().position(|h| h == "currency");
+
+    let mut rows = Rows::new();
+    for record in csv_reader.records() {
+        let record = record?;
+        let client_field = record.get(client_index).unwrap_or_default();
+        let client = client_field.parse::<u16>().map_err(|_| {
+            PaymentError::InvalidTransaction(format!("invalid client '{}' in account CSV", client_field))
+        })?;
+        let currency = match currency_index.and_then(|i| record.get(i)) {
+            Some(code) => Currency::from_str(code)?,
+            None => Currency::DEFAULT,
+        };
+
+        let fields = headers
+            .iter()
+            .zip(record.iter())
+            .enumerate()
+            .filter(|(i, _)| *i != client_index && Some(*i) != currency_index)
+            .map(|(_, (header, value))| (header.to_string(), value.to_string()))
+            .collect();
+        if rows.insert((client, currency), fields).is_some() {
+            return Err(PaymentError::InvalidTransaction(format!(
+                "client {} appears twice in account CSV",
+                client
+            )));
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical_files_have_no_diffs() {
+        let a = "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2,0,2,false\n";
+        let b = "client,available,held,total,locked\n2,2.0000,0.0000,2.0000,false\n1,1.5000,0.0000,1.5000,false\n";
+        assert_eq!(diff_accounts(a.as_bytes(), b.as_bytes()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_balance_difference_reported() {
+        let a = "client,available,held,total,locked\n1,1.5,0,1.5,false\n";
+        let b = "client,available,held,total,locked\n1,1.0,0,1.0,false\n";
+        let diffs = diff_accounts(a.as_bytes(), b.as_bytes()).unwrap();
+        assert_eq!(diffs, vec![
+            AccountDiff::FieldMismatch {
+                client: 1,
+                currency: Currency::DEFAULT,
+                field: "available".to_string(),
+                left: "1.5".to_string(),
+                right: "1.0".to_string(),
+            },
+            AccountDiff::FieldMismatch {
+                client: 1,
+                currency: Currency::DEFAULT,
+                field: "total".to_string(),
+                left: "1.5".to_string(),
+                right: "1.0".to_string(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_missing_client_reported() {
+        let a = "client,available,held,total,locked\n1,1,0,1,false\n2,2,0,2,false\n";
+        let b = "client,available,held,total,locked\n1,1,0,1,false\n3,3,0,3,false\n";
+        let diffs = diff_accounts(a.as_bytes(), b.as_bytes()).unwrap();
+        assert_eq!(diffs, vec![
+            AccountDiff::MissingRight { client: 2, currency: Currency::DEFAULT },
+            AccountDiff::MissingLeft { client: 3, currency: Currency::DEFAULT },
+        ]);
+    }
+
+    #[test]
+    fn test_currency_is_part_of_key() {
+        let a = "client,currency,available,held,total,locked\n1,USD,1,0,1,false\n1,EUR,2,0,2,false\n";
+        let b = "client,currency,available,held,total,locked\n1,EUR,2,0,2,false\n";
+        let diffs = diff_accounts(a.as_bytes(), b.as_bytes()).unwrap();
+        assert_eq!(diffs, vec![AccountDiff::MissingRight {
+            client: 1,
+            currency: Currency::from_str("USD").unwrap(),
+        }]);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config::ProcessorConfig;
+use crate::domain::rejection::RejectionSummary;
+use crate::domain::transaction::{RawTrxRecord, Trx, TrxType};
+use crate::error::{PaymentError, Result};
+use crate::services::payment_engine::PaymentsEngine;
+use crate::services::trx_processor::{apply_record, ProcessProgress, RunHooks};
+use rust_decimal::Decimal;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+
+/// First byte of a binary-protocol connection. It can't begin a CSV header or
+/// a health probe, so text clients need no marker.
+pub const PROTOCOL_MARKER: u8 = 0xB1;
+
+/// Fixed record layout, all integers big-endian:
+///
+/// | offset | size | field                                         |
+/// |--------|------|-----------------------------------------------|
+/// | 0      | 1    | type code (see `type_code`)                   |
+/// | 1      | 2    | client                                        |
+/// | 3      | 4    | tx                                            |
+/// | 7      | 16   | amount as `Decimal::serialize` (zero if none) |
+pub const RECORD_LEN: usize = 23;
+
+fn type_code(tx_type: TrxType) -> u8 {
+    match tx_type {
+        TrxType::Deposit => 0,
+        TrxType::Withdrawal => 1,
+        TrxType::Dispute => 2,
+        TrxType::Resolve => 3,
+        TrxType::Chargeback => 4,
+        TrxType::Fee => 5,
+        TrxType::Unlock => 6,
+        TrxType::Freeze => 7,
+        TrxType::Thaw => 8,
+        TrxType::PartialResolve => 9,
+        TrxType::Unknown => unreachable!("no Trx has an unknown type"),
+    }
+}
+
+fn type_from_code(code: u8) -> Option<TrxType> {
+    Some(match code {
+        0 => TrxType::Deposit,
+        1 => TrxType::Withdrawal,
+        2 => TrxType::Dispute,
+        3 => TrxType::Resolve,
+        4 => TrxType::Chargeback,
+        5 => TrxType::Fee,
+        6 => TrxType::Unlock,
+        7 => TrxType::Freeze,
+        8 => TrxType::Thaw,
+        9 => TrxType::PartialResolve,
+        _ => return None,
+    })
+}
+
+pub fn encode(tx: &Trx) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0] = type_code(tx.tx_type());
+    record[1..3].copy_from_slice(&tx.client().to_be_bytes());
+    record[3..7].copy_from_slice(&tx.tx().to_be_bytes());
+    record[7..].copy_from_slice(&tx.amount().unwrap_or(Decimal::ZERO).serialize());
+    record
+}
+
+/// Decodes one record. Amounts are only read for types that carry one.
+pub fn decode(record: &[u8; RECORD_LEN]) -> Result<RawTrxRecord> {
+    let tx_type = type_from_code(record[0]).ok_or_else(|| {
+        PaymentError::InvalidTransaction(format!("unknown binary type code {}", record[0]))
+    })?;
+    let client = u16::from_be_bytes([record[1], record[2]]);
+    let tx = u32::from_be_bytes([record[3], record[4], record[5], record[6]]);
+
+    let mut amount_bytes = [0u8; 16];
+    amount_bytes.copy_from_slice(&record[7..]);
+    let amount = match tx_type {
+        TrxType::Deposit | TrxType::Withdrawal | TrxType::Fee | TrxType::PartialResolve => {
+            Some(decode_amount(amount_bytes)?)
+        }
+        TrxType::Dispute
+        | TrxType::Resolve
+        | TrxType::Chargeback
+        | TrxType::Unlock
+        | TrxType::Freeze
+        | TrxType::Thaw
+        | TrxType::Unknown => None,
+    };
+
+    Ok(RawTrxRecord {
+        tx_type,
+        client,
+        tx,
+        amount,
+        currency: None,
+        timestamp: None,
+    })
+}
+
+/// `Decimal::deserialize` trusts its input, so reject flag bits other than the
+/// sign and scales it can't represent.
+fn decode_amount(bytes: [u8; 16]) -> Result<Decimal> {
+    let flags = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let scale = (flags >> 16) & 0xFF;
+    if flags & !0x80FF_0000 != 0 || scale > 28 {
+        return Err(PaymentError::InvalidTransaction(format!(
+            "invalid binary amount flags {:#010x}",
+            flags
+        )));
+    }
+    Ok(Decimal::deserialize(bytes))
+}
+
+/// Applies binary records from `reader` until EOF. A trailing partial record
+/// is treated as malformed. `config.read_timeout` applies to each read.
+pub async fn apply_binary_stream<R: AsyncRead + Unpin>(
+    engine: &PaymentsEngine,
+    reader: R,
+    config: &ProcessorConfig,
+    summary: &mut RejectionSummary,
+    hooks: &mut RunHooks,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut progress = ProcessProgress { rows: 0, errors: 0 };
+    let mut record = [0u8; RECORD_LEN];
+
+    loop {
+        let filled = read_record(&mut reader, &mut record, config).await?;
+        let result = match filled {
+            0 => return Ok(()),
+            RECORD_LEN => decode(&record),
+            partial => Err(PaymentError::InvalidTransaction(format!(
+                "truncated binary record of {} bytes",
+                partial
+            ))),
+        };
+        apply_record(engine, result, config, summary, hooks, &mut progress).await?;
+        if filled < RECORD_LEN {
+            return Ok(());
+        }
+    }
+}
+
+/// Fills `record`, returning fewer than `RECORD_LEN` bytes only at EOF.
+async fn read_record<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    record: &mut [u8; RECORD_LEN],
+    config: &ProcessorConfig,
+) -> Result<usize> {
+    let mut filled = 0;
+    while filled < RECORD_LEN {
+        let read = reader.read(&mut record[filled..]);
+        let n = match config.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read)
+                .await
+                .map_err(|_| PaymentError::RequestTimeout(timeout))??,
+            None => read.await?,
+        };
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::rejection::Rejection;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_round_trip() {
+        let transactions = [
+            Trx::Deposit { client: u16::MAX, tx: u32::MAX, amount: dec!(1234.5678) },
+            Trx::Withdrawal { client: 1, tx: 2, amount: dec!(-0.0001) },
+            Trx::Dispute { client: 1, tx: 3 },
+            Trx::Resolve { client: 1, tx: 3 },
+            Trx::Chargeback { client: 1, tx: 3 },
+            Trx::Fee { client: 0, tx: 0, amount: Decimal::MAX },
+            Trx::Unlock { client: 7, tx: 8 },
+            Trx::Freeze { client: 7, tx: 9 },
+            Trx::Thaw { client: 7, tx: 10 },
+            Trx::PartialResolve { client: 1, tx: 3, amount: dec!(0.5) },
+        ];
+        for tx in transactions {
+            let raw = decode(&encode(&tx)).unwrap();
+            assert_eq!(raw.tx_type, tx.tx_type());
+            assert_eq!(raw.client, tx.client());
+            assert_eq!(raw.tx, tx.tx());
+            assert_eq!(raw.amount, tx.amount());
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_type_and_amount() {
+        let mut record = encode(&Trx::Deposit { client: 1, tx: 1, amount: dec!(1.0) });
+        record[0] = 42;
+        assert!(decode(&record).is_err());
+
+        let mut record = encode(&Trx::Deposit { client: 1, tx: 1, amount: dec!(1.0) });
+        record[9] = 29; // scale byte
+        assert!(decode(&record).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_binary_stream_applies_records() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&encode(&Trx::Deposit { client: 1, tx: 1, amount: dec!(10.0) }));
+        payload.extend_from_slice(&encode(&Trx::Withdrawal { client: 1, tx: 2, amount: dec!(2.5) }));
+        payload.extend_from_slice(&encode(&Trx::Dispute { client: 1, tx: 1 }));
+        payload.extend_from_slice(&[0u8; 5]);
+
+        let engine = PaymentsEngine::new();
+        let mut summary = RejectionSummary::new();
+        apply_binary_stream(
+            &engine,
+            payload.as_slice(),
+            &ProcessorConfig::new(),
+            &mut summary,
+            &mut RunHooks::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(summary.applied, 3);
+        assert_eq!(summary.count(Rejection::Malformed), 1);
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-2.5));
+        assert_eq!(accounts[0].held, dec!(10.0));
+    }
+}
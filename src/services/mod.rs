@@ -1,2 +1,8 @@
 pub mod payment_engine;
 pub mod trx_processor;
+pub mod tcp_server;
+pub mod binary_protocol;
+pub mod wal;
+pub mod tx_store;
+pub mod account_diff;
+pub mod snapshot;
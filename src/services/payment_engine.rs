@@ -1,14 +1,55 @@
-use crate::domain::user_account::UserAccount;
-use crate::domain::transaction::{Trx, TxRecord, TrxStatus};
+use crate::config::{ProcessorConfig, WithdrawalDisputeMode};
+use crate::domain::currency::Currency;
+use crate::domain::rejection::{BalanceField, Rejection};
+use crate::domain::summary::{EngineStats, HeldMismatch, Reconciliation, Summary};
+use crate::domain::user_account::{SortOrder, UserAccount};
+use crate::domain::transaction::{Trx, TxKind, TxRecord, TrxStatus};
+use crate::error::{PaymentError, Result as PaymentResult};
+use crate::services::snapshot::EngineSnapshot;
+use crate::services::tx_store::{InMemoryTxStore, TxStore};
+use crate::services::wal::{WalWriter, WriteAheadLog};
 use dashmap::DashMap;
+use dashmap::mapref::multiple::RefMulti;
+use dashmap::mapref::one::RefMut;
+use jiff::Timestamp;
 use rust_decimal::Decimal;
-use indexmap::IndexMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, DefaultHasher, RandomState};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::Mutex;
 
-pub struct PaymentsEngine {
-    user_account_map: DashMap<u16, UserAccount>,
-    tx_history: Mutex<IndexMap<u32, TxRecord>>,
-    max_tx_history: Option<usize>,
+/// Accounts are keyed per client and currency; single-currency input only
+/// ever uses `Currency::DEFAULT`.
+pub type AccountKey = (u16, Currency);
+
+/// Hasher for the account map. `Fixed` uses unkeyed SipHash so map iteration
+/// order depends only on the keys and insertion order, not on a per-process seed.
+#[derive(Clone)]
+pub enum AccountHasher {
+    Random(RandomState),
+    Fixed,
+}
+
+impl BuildHasher for AccountHasher {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        match self {
+            AccountHasher::Random(state) => state.build_hasher(),
+            AccountHasher::Fixed => DefaultHasher::new(),
+        }
+    }
+}
+
+pub struct PaymentsEngine<S: TxStore = InMemoryTxStore> {
+    user_account_map: DashMap<AccountKey, UserAccount, AccountHasher>,
+    tx_history: Mutex<S>,
+    next_ordinal: AtomicU64,
+    evicted_records: AtomicU64,
+    applied: AtomicU64,
+    config: ProcessorConfig,
+    wal: Option<WriteAheadLog>,
 }
 
 impl PaymentsEngine {
@@ -19,56 +60,522 @@ impl PaymentsEngine {
     }
 
     pub fn with_max_history(max_tx_history: Option<usize>) -> Self {
+        Self::with_config(ProcessorConfig::default().with_max_tx_history(max_tx_history))
+    }
+
+    pub fn with_config(config: ProcessorConfig) -> Self {
+        PaymentsEngine::with_store(InMemoryTxStore::new(), config)
+    }
+
+    /// Like `with_max_history`, but sized up front for `accounts` accounts and
+    /// `history` history records, so large runs don't rehash while filling up.
+    pub fn with_capacity(accounts: usize, history: usize, max_tx_history: Option<usize>) -> Self {
+        let config = ProcessorConfig::default().with_max_tx_history(max_tx_history);
+        let mut engine = PaymentsEngine::with_store(InMemoryTxStore::with_capacity(history), config);
+        engine.user_account_map =
+            DashMap::with_capacity_and_hasher(accounts, engine.user_account_map.hasher().clone());
+        engine
+    }
+}
+
+impl<S: TxStore> PaymentsEngine<S> {
+    /// Builds an engine keeping its transaction history in `store`.
+    pub fn with_store(store: S, config: ProcessorConfig) -> Self {
         PaymentsEngine {
-            user_account_map: DashMap::new(),
-            tx_history: Mutex::new(IndexMap::new()),
-            max_tx_history,
+            user_account_map: DashMap::with_hasher(if config.deterministic {
+                AccountHasher::Fixed
+            } else {
+                AccountHasher::Random(RandomState::new())
+            }),
+            tx_history: Mutex::new(store),
+            next_ordinal: AtomicU64::new(0),
+            evicted_records: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            config,
+            wal: None,
+        }
+    }
+
+    /// Logs every accepted transaction to `wal` from now on. Replay the log into
+    /// the engine with `wal::replay` before attaching it.
+    pub fn with_wal(mut self, wal: WriteAheadLog) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn config(&self) -> &ProcessorConfig {
+        &self.config
+    }
+
+    /// Writes out anything attached sinks still buffer. Call before exit.
+    pub async fn flush(&self) -> PaymentResult<()> {
+        match &self.wal {
+            Some(wal) => wal.flush().await,
+            None => Ok(()),
         }
     }
 
-    pub fn get_or_create_account(&self, client_id: u16) -> dashmap::mapref::one::RefMut<'_, u16, UserAccount> {
+    pub fn get_or_create_account(&self, client_id: u16) -> RefMut<'_, AccountKey, UserAccount> {
+        self.get_or_create_account_in(client_id, Currency::DEFAULT)
+    }
+
+    pub fn get_or_create_account_in(
+        &self,
+        client_id: u16,
+        currency: Currency,
+    ) -> RefMut<'_, AccountKey, UserAccount> {
         self.user_account_map
-            .entry(client_id)
-            .or_insert_with(|| UserAccount::new(client_id))
+            .entry((client_id, currency))
+            .or_insert_with(|| UserAccount::new_in(client_id, currency))
+    }
+
+    /// Runs `f` with exclusive access to accounts `a` and `b`, creating them if
+    /// missing. DashMap locks whole shards, so the shards are locked in ascending
+    /// index order, and a pair sharing a shard is taken under a single guard;
+    /// concurrent A->B and B->A callers therefore can't deadlock.
+    ///
+    /// Panics if `a == b`.
+    pub fn lock_two_accounts<R>(
+        &self,
+        a: u16,
+        b: u16,
+        f: impl FnOnce(&mut UserAccount, &mut UserAccount) -> R,
+    ) -> R {
+        self.lock_two_accounts_in(a, b, Currency::DEFAULT, f)
+    }
+
+    /// `lock_two_accounts` for the two clients' accounts in `currency`.
+    pub fn lock_two_accounts_in<R>(
+        &self,
+        a: u16,
+        b: u16,
+        currency: Currency,
+        f: impl FnOnce(&mut UserAccount, &mut UserAccount) -> R,
+    ) -> R {
+        assert_ne!(a, b, "lock_two_accounts needs two distinct clients");
+        drop(self.get_or_create_account_in(a, currency));
+        drop(self.get_or_create_account_in(b, currency));
+
+        // Accounts are never removed, so both lookups below must succeed
+        let map = &self.user_account_map;
+        let (key_a, key_b) = ((a, currency), (b, currency));
+        let (shard_a, shard_b) = (map.determine_map(&key_a), map.determine_map(&key_b));
+        if shard_a == shard_b {
+            let hashes = [map.hasher().hash_one(key_a), map.hasher().hash_one(key_b)];
+            let mut shard = map.shards()[shard_a].write();
+            let [(_, first), (_, second)] = shard
+                .get_many_mut(hashes, |i, (key, _)| *key == [key_a, key_b][i])
+                .expect("both accounts exist");
+            return f(first.get_mut(), second.get_mut());
+        }
+
+        let (lower, higher) = if shard_a < shard_b { (key_a, key_b) } else { (key_b, key_a) };
+        let mut lower_guard = map.get_mut(&lower).expect("account exists");
+        let mut higher_guard = map.get_mut(&higher).expect("account exists");
+        if lower == key_a {
+            f(&mut lower_guard, &mut higher_guard)
+        } else {
+            f(&mut higher_guard, &mut lower_guard)
+        }
     }
 
     pub fn get_accounts(&self) -> Vec<UserAccount> {
-        let mut accounts: Vec<UserAccount> = self.user_account_map
+        self.get_accounts_ordered(SortOrder::ClientId)
+    }
+
+    pub fn get_accounts_ordered(&self, order: SortOrder) -> Vec<UserAccount> {
+        let accounts: Vec<UserAccount> = self.user_account_map
             .iter()
             .map(|entry| entry.value().clone())
             .collect();
-        accounts.sort_by_key(|a| a.client);
-        accounts
+        Self::sort_accounts(accounts, order)
     }
 
-    fn insert_tx_with_eviction(
-        &self,
-        tx_history: &mut indexmap::IndexMap<u32, TxRecord>,
-        tx: u32,
-        client: u16,
-        amount: Decimal,
-    ) {
-        if let Some(max) = self.max_tx_history {
-            if tx_history.len() >= max {
-                tx_history.shift_remove_index(0);
+    /// Point-in-time copy of every account. Transactions apply under the history
+    /// lock, so holding it while copying means each one is either fully in the
+    /// snapshot or not at all, even across accounts; writers pause only for the
+    /// copy, and sorting happens after release. `get_accounts_ordered` takes no
+    /// such pause and may see a transaction on one account but not the next.
+    /// Direct `lock_two_accounts` mutations bypass the lock and aren't covered.
+    pub async fn snapshot_accounts(&self, order: SortOrder) -> Vec<UserAccount> {
+        let accounts: Vec<UserAccount> = {
+            let _tx_history = self.tx_history.lock().await;
+            self.user_account_map.iter().map(|entry| entry.value().clone()).collect()
+        };
+        Self::sort_accounts(accounts, order)
+    }
+
+    fn sort_accounts(mut accounts: Vec<UserAccount>, order: SortOrder) -> Vec<UserAccount> {
+        match order {
+            SortOrder::ClientId => accounts.sort_by_key(|a| (a.client, a.currency)),
+            SortOrder::Unsorted => {}
+            SortOrder::TotalDesc => {
+                accounts.sort_by_key(|a| (std::cmp::Reverse(a.total), a.client, a.currency))
+            }
+            SortOrder::AvailableDesc => {
+                accounts.sort_by_key(|a| (std::cmp::Reverse(a.available), a.client, a.currency))
             }
         }
+        accounts
+    }
+
+    /// Iterates accounts in map order without cloning. Each item holds a read
+    /// lock on its shard, so don't keep items alive across engine calls.
+    pub fn accounts_iter(&self) -> impl Iterator<Item = RefMulti<'_, AccountKey, UserAccount>> {
+        self.user_account_map.iter()
+    }
+
+    /// Calls `f` for every account in map order.
+    pub fn for_each_account(&self, mut f: impl FnMut(&UserAccount)) {
+        for entry in self.accounts_iter() {
+            f(entry.value());
+        }
+    }
 
-        tx_history.insert(tx, TxRecord {
-            client,
-            amount,
-            status: TrxStatus::Normal,
+    /// Sums balances across all accounts using checked arithmetic.
+    pub fn aggregate_summary(&self) -> Summary {
+        let mut summary = Summary::default();
+        self.for_each_account(|account| {
+            summary.accounts += 1;
+            if account.locked {
+                summary.locked_accounts += 1;
+            }
+            summary.available = summary.available.and_then(|sum| sum.checked_add(account.available));
+            summary.held = summary.held.and_then(|sum| sum.checked_add(account.held));
+            summary.total = summary.total.and_then(|sum| sum.checked_add(account.total));
         });
+        summary
+    }
+
+    /// Account and history counts with a rough memory estimate. History entries
+    /// cost the key, the record and the cached hash, plus one index slot.
+    /// Stores that don't report a capacity are estimated from their length.
+    pub async fn stats(&self) -> EngineStats {
+        let tx_history = self.tx_history.lock().await;
+        let account_entry = size_of::<AccountKey>() + size_of::<UserAccount>();
+        let history_entry = size_of::<u32>() + size_of::<TxRecord>() + 2 * size_of::<usize>();
+        EngineStats {
+            accounts: self.user_account_map.len(),
+            tx_history: tx_history.len().await,
+            evicted: self.evicted_records.load(Ordering::Relaxed),
+            estimated_bytes: self.user_account_map.capacity() * account_entry
+                + tx_history.capacity().await * history_entry,
+        }
+    }
+
+    /// Empties the engine for reuse, returning how many accounts and history
+    /// records were dropped. Waits for in-flight transactions; the eviction
+    /// count and insertion ordinals restart, while `applied_count` keeps
+    /// counting. Fails on an engine with a WAL attached: the log would keep the
+    /// pre-clear rows, and replaying it would reject reused tx IDs as
+    /// duplicates of them.
+    pub async fn clear(&self) -> PaymentResult<(usize, usize)> {
+        if self.wal.is_some() {
+            return Err(PaymentError::InvalidConfig(
+                "cannot clear an engine with a write-ahead log attached".to_string(),
+            ));
+        }
+        let mut tx_history = self.tx_history.lock().await;
+        let (accounts, records) = (self.user_account_map.len(), tx_history.len().await);
+        tx_history.clear().await;
+        self.user_account_map.clear();
+        self.next_ordinal.store(0, Ordering::Relaxed);
+        self.evicted_records.store(0, Ordering::Relaxed);
+        log::info!("Engine cleared: {} accounts, {} history records", accounts, records);
+        Ok((accounts, records))
+    }
+
+    /// Every account plus the retained history, with accounts ordered by client and currency. Taken
+    /// under the history lock, so no transaction is half-applied in it.
+    pub async fn snapshot(&self) -> EngineSnapshot {
+        let tx_history = self.tx_history.lock().await;
+        let mut accounts: Vec<UserAccount> =
+            self.user_account_map.iter().map(|entry| entry.value().clone()).collect();
+        accounts.sort_by_key(|account| (account.client, account.currency));
+        EngineSnapshot {
+            accounts,
+            records: tx_history.records().await,
+            next_ordinal: self.next_ordinal.load(Ordering::Relaxed),
+            evicted: self.evicted_records.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Replaces the engine's accounts and history with `snapshot`.
+    pub async fn restore(&self, snapshot: EngineSnapshot) {
+        let mut tx_history = self.tx_history.lock().await;
+        tx_history.clear().await;
+        self.user_account_map.clear();
+        for account in snapshot.accounts {
+            self.user_account_map.insert((account.client, account.currency), account);
+        }
+        for (tx, record) in snapshot.records {
+            tx_history.insert(tx, record).await;
+        }
+        self.next_ordinal.store(snapshot.next_ordinal, Ordering::Relaxed);
+        self.evicted_records.store(snapshot.evicted, Ordering::Relaxed);
+    }
+
+    /// Folds an independently built engine into this one. Fails without changing
+    /// anything if any tx ID exists in both histories, since both sides' balances
+    /// would already include their own version of that transaction.
+    pub async fn merge<T: TxStore>(&self, other: PaymentsEngine<T>) -> PaymentResult<()> {
+        let mut tx_history = self.tx_history.lock().await;
+        let other_history = other.tx_history.into_inner().records().await;
+
+        let mut collisions = Vec::new();
+        for (tx, _) in &other_history {
+            if tx_history.contains(*tx).await {
+                collisions.push(*tx);
+            }
+        }
+        if !collisions.is_empty() {
+            log::error!("Merge rejected: {} colliding tx IDs {:?}", collisions.len(), collisions);
+            return Err(PaymentError::TxIdCollision(collisions));
+        }
+
+        // Combine every account before touching any, so an overflow leaves
+        // this engine unchanged
+        let mut combined = Vec::with_capacity(other.user_account_map.len());
+        for ((client, currency), incoming) in other.user_account_map {
+            let mut account = self
+                .user_account_map
+                .get(&(client, currency))
+                .map(|account| account.clone())
+                .unwrap_or_else(|| UserAccount::new_in(client, currency));
+            let (Some(available), Some(held), Some(total)) = (
+                account.available.checked_add(incoming.available),
+                account.held.checked_add(incoming.held),
+                account.total.checked_add(incoming.total),
+            ) else {
+                log::error!("Merge rejected: client={} (overflow combining balances)", client);
+                return Err(PaymentError::MergeOverflow(client));
+            };
+            account.available = available;
+            account.held = held;
+            account.total = total;
+            account.locked |= incoming.locked;
+            account.frozen |= incoming.frozen;
+            account.tx_count += incoming.tx_count;
+            account.open_disputes += incoming.open_disputes;
+            combined.push(((client, currency), account));
+        }
+        for (key, account) in combined {
+            self.user_account_map.insert(key, account);
+        }
+
+        self.evicted_records.fetch_add(other.evicted_records.into_inner(), Ordering::Relaxed);
+        self.applied.fetch_add(other.applied.into_inner(), Ordering::Relaxed);
+
+        // Merged records are newer than everything already held here
+        for (tx, mut record) in other_history {
+            if let Some(max) = self.config.max_tx_history {
+                if tx_history.len().await >= max {
+                    self.evict_oldest(&mut *tx_history).await;
+                }
+            }
+            record.insertion_ordinal = self.next_insertion_ordinal();
+            tx_history.insert(tx, record).await;
+        }
+
+        Ok(())
+    }
+
+    /// Lists each disputed transaction composing the client's `held` balance.
+    pub async fn held_breakdown(&self, client: u16) -> Vec<(u32, Decimal)> {
+        let mut held = Vec::new();
+        self.tx_history.lock().await.for_each_record(|tx, record| {
+            if record.client == client && record.status == TrxStatus::UnderDispute {
+                held.push((tx, record.disputed_amount()));
+            }
+        }).await;
+        held
+    }
+
+    /// Every transaction currently under dispute as `(tx, client, amount)`, in
+    /// insertion order.
+    pub async fn all_disputed(&self) -> Vec<(u32, u16, Decimal)> {
+        let mut disputed = Vec::new();
+        self.tx_history.lock().await.for_each_record(|tx, record| {
+            if record.status == TrxStatus::UnderDispute {
+                disputed.push((tx, record.client, record.disputed_amount()));
+            }
+        }).await;
+        disputed
+    }
+
+    /// Writes the retained history as `type,client,tx,amount` CSV, plus a
+    /// `currency` column when any record has a non-default currency and a
+    /// `timestamp` column when any record has a timestamp. All deposits and
+    /// withdrawals come first, in insertion order, followed by the dispute, resolve
+    /// and chargeback rows needed to reproduce each record's current status, so
+    /// replaying the export rebuilds the same state (evicted records excepted).
+    pub async fn export_transactions<W: Write>(&self, mut writer: W) -> PaymentResult<()> {
+        // Rendered into memory under the history lock, then written once released
+        let tx_history = self.tx_history.lock().await;
+        let (mut with_currency, mut with_timestamp) = (false, false);
+        tx_history.for_each_record(|_, record| {
+            with_currency |= !record.currency.is_default();
+            with_timestamp |= record.timestamp.is_some();
+        }).await;
+
+        let mut csv_writer = csv::Writer::from_writer(Vec::new());
+        let mut result = Ok(());
+        let mut write_row = |fields: [&str; 4], currency: &str, timestamp: &str| {
+            if result.is_ok() {
+                let currency = with_currency.then_some(currency);
+                let timestamp = with_timestamp.then_some(timestamp);
+                result = csv_writer.write_record(fields.iter().copied().chain(currency).chain(timestamp));
+            }
+        };
+
+        write_row(["type", "client", "tx", "amount"], "currency", "timestamp");
+        tx_history.for_each_record(|tx, record| {
+            let timestamp = record.timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default();
+            write_row(
+                [record.kind.name(), &record.client.to_string(), &tx.to_string(), &record.amount.to_string()],
+                record.currency.as_str(),
+                &timestamp,
+            );
+        }).await;
+
+        // Disputes first, then resolves, then chargebacks: a chargeback locks the
+        // account, which would make any later resolve on it fail during replay
+        let follow_ups: [(&str, &[TrxStatus]); 4] = [
+            ("dispute", &[TrxStatus::UnderDispute, TrxStatus::Resolved, TrxStatus::ChargedBack]),
+            ("partial_resolve", &[TrxStatus::UnderDispute, TrxStatus::ChargedBack]),
+            ("resolve", &[TrxStatus::Resolved]),
+            ("chargeback", &[TrxStatus::ChargedBack]),
+        ];
+        for (tx_type, statuses) in follow_ups {
+            tx_history.for_each_record(|tx, record| {
+                if !statuses.contains(&record.status) {
+                    return;
+                }
+                let amount = match tx_type {
+                    "partial_resolve" if record.resolved.is_zero() => return,
+                    "partial_resolve" => record.resolved.to_string(),
+                    _ => String::new(),
+                };
+                write_row([tx_type, &record.client.to_string(), &tx.to_string(), &amount], record.currency.as_str(), "");
+            }).await;
+        }
+        drop(tx_history);
+
+        result?;
+        let rows = csv_writer.into_inner().map_err(|e| PaymentError::IoError(e.into_error()))?;
+        writer.write_all(&rows)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Records `record` as the newest entry, assigning its insertion ordinal.
+    async fn insert_tx_with_eviction(&self, tx_history: &mut S, tx: u32, mut record: TxRecord) {
+        if let Some(max) = self.config.max_tx_history {
+            if tx_history.len().await >= max {
+                self.evict_oldest(tx_history).await;
+            }
+        }
+
+        record.insertion_ordinal = self.next_insertion_ordinal();
+        tx_history.insert(tx, record).await;
+    }
+
+    fn next_insertion_ordinal(&self) -> u64 {
+        self.next_ordinal.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Drops the record with the lowest insertion ordinal.
+    async fn evict_oldest(&self, tx_history: &mut S) {
+        if tx_history.remove_oldest().await.is_some() {
+            self.evicted_records.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Compares, per currency, the net of the retained history (deposits minus
+    /// withdrawals, fees and chargebacks) against the sum of account totals.
+    /// Holds the history lock throughout, so no transaction lands mid-report.
+    /// Evicted records can't be accounted for; see `Reconciliation::evicted`.
+    pub async fn reconcile(&self) -> Reconciliation {
+        let tx_history = self.tx_history.lock().await;
+        let mut report = Reconciliation {
+            evicted: self.evicted_records.load(Ordering::Relaxed),
+            ..Reconciliation::default()
+        };
+
+        let add = |sum: Option<Decimal>, value: Decimal| sum.and_then(|sum| sum.checked_add(value));
+        tx_history.for_each_record(|_, record| {
+            let entry = report.currencies.entry(record.currency).or_default();
+            entry.expected = add(entry.expected, match record.kind {
+                TxKind::Deposit => record.amount,
+                TxKind::Withdrawal | TxKind::Fee => -record.amount,
+            });
+            // Chargebacks debit the record's amount whatever its kind, except
+            // credit-back withdrawals, whose open dispute or chargeback credits it
+            if self.credits_back(record) {
+                if matches!(record.status, TrxStatus::UnderDispute | TrxStatus::ChargedBack) {
+                    entry.expected = add(entry.expected, record.disputed_amount());
+                }
+            } else if record.status == TrxStatus::ChargedBack {
+                entry.expected = add(entry.expected, -record.disputed_amount());
+            }
+        }).await;
+
+        for account in self.accounts_iter() {
+            let (_, currency) = *account.key();
+            let entry = report.currencies.entry(currency).or_default();
+            entry.actual = add(entry.actual, account.total);
+        }
+
+        for (currency, drift) in report.discrepancies() {
+            log::warn!(
+                "Reconciliation mismatch: currency={}, drift={:?}, evicted={}",
+                currency, drift, report.evicted
+            );
+        }
+        report
+    }
+
+    /// Insertion ordinal of a recorded deposit, withdrawal or fee, or `None`
+    /// if `tx` was never recorded or has been evicted.
+    pub async fn insertion_ordinal(&self, tx: u32) -> Option<u64> {
+        self.tx_history.lock().await.get(tx).await.map(|record| record.insertion_ordinal)
+    }
+
+    /// Input timestamp of a recorded transaction, or `None` if the row had
+    /// none or `tx` is not recorded.
+    pub async fn tx_timestamp(&self, tx: u32) -> Option<Timestamp> {
+        self.tx_history.lock().await.get(tx).await.and_then(|record| record.timestamp)
+    }
+
+    /// Largest decimal scale among all account balances, to catch amounts
+    /// stored at more places than `decimal_precision`.
+    pub fn max_scale_in_use(&self) -> u32 {
+        self.user_account_map
+            .iter()
+            .flat_map(|account| [account.available.scale(), account.held.scale(), account.total.scale()])
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Transactions accepted since the engine was built, of every type.
+    pub fn applied_count(&self) -> u64 {
+        self.applied.load(Ordering::Relaxed)
+    }
+
+    /// Current dispute status of a recorded transaction, or `None` if `tx` was
+    /// never recorded or has been evicted.
+    pub async fn tx_status(&self, tx: u32) -> Option<TrxStatus> {
+        self.tx_history.lock().await.get(tx).await.map(|record| record.status)
     }
 
-    fn check_duplicate_tx(
-        tx_history: &IndexMap<u32, TxRecord>,
+    async fn check_duplicate_tx(
+        tx_history: &S,
         tx: u32,
         tx_type: &str,
         client: u16,
         amount: Decimal,
     ) -> bool {
-        if tx_history.contains_key(&tx) {
+        if tx_history.contains(tx).await {
             log::error!(
                 "{} rejected: client={}, tx={}, amount={} (duplicate transaction ID)",
                 tx_type, client, tx, amount
@@ -79,10 +586,83 @@ impl PaymentsEngine {
         }
     }
 
+    fn credits_back(&self, record: &TxRecord) -> bool {
+        record.kind == TxKind::Withdrawal
+            && self.config.withdrawal_dispute_mode == WithdrawalDisputeMode::CreditBack
+    }
+
+    /// The limit counts clients, so a client already holding an account may
+    /// open one in another currency. The map only holds (client, currency)
+    /// keys, so clients are counted by a scan once there are `max` keys.
+    fn check_account_limit(&self, client: u16, currency: Currency, tx_type: &str, tx: u32) -> Result<(), Rejection> {
+        let Some(max) = self.config.max_accounts else {
+            return Ok(());
+        };
+        if self.user_account_map.len() < max || self.user_account_map.contains_key(&(client, currency)) {
+            return Ok(());
+        }
+
+        let mut clients = HashSet::new();
+        for entry in self.user_account_map.iter() {
+            if entry.key().0 == client {
+                return Ok(());
+            }
+            clients.insert(entry.key().0);
+        }
+        if clients.len() < max {
+            return Ok(());
+        }
+        log::warn!(
+            "{} rejected: client={}, tx={} (account limit of {} reached)",
+            tx_type, client, tx, max
+        );
+        Err(Rejection::AccountLimit)
+    }
+
+    /// Checked before the account is created, so a capped transaction never
+    /// leaves an empty account behind.
+    fn check_client_tx_cap(&self, client: u16, currency: Currency, tx_type: &str, tx: u32) -> Result<(), Rejection> {
+        let Some(max) = self.config.max_tx_per_client else {
+            return Ok(());
+        };
+        let tx_count = self.user_account_map.get(&(client, currency)).map_or(0, |account| account.tx_count);
+        if tx_count >= max {
+            log::warn!(
+                "{} rejected: client={}, tx={} (client reached cap of {} transactions)",
+                tx_type, client, tx, max
+            );
+            return Err(Rejection::ClientTxLimit);
+        }
+        Ok(())
+    }
+
+    fn check_client(&self, client: u16) -> Result<(), Rejection> {
+        if !self.config.client_in_range(client) {
+            log::warn!(
+                "Transaction rejected: client={} outside the configured range {:?}..={:?} (possible routing error)",
+                client, self.config.min_client, self.config.max_client
+            );
+            return Err(Rejection::ClientOutOfRange);
+        }
+        if !self.config.client_permitted(client) {
+            log::warn!("Transaction rejected: client={} not permitted by allow/deny lists", client);
+            return Err(Rejection::ClientNotAllowed);
+        }
+        Ok(())
+    }
+
+    fn check_frozen(account: &UserAccount, tx_type: &str, tx: u32) -> Result<(), Rejection> {
+        if account.frozen {
+            log::warn!("{} rejected: client={}, tx={} (account frozen)", tx_type, account.client, tx);
+            return Err(Rejection::AccountFrozen);
+        }
+        Ok(())
+    }
+
     fn checked_add_with_log(
         current: Decimal,
         amount: Decimal,
-        field_name: &str,
+        field: BalanceField,
         tx_type: &str,
         client: u16,
         tx: u32,
@@ -92,7 +672,7 @@ impl PaymentsEngine {
             None => {
                 log::error!(
                     "{} rejected: client={}, tx={}, amount={}, {}={} (overflow on {})",
-                    tx_type, client, tx, amount, field_name, current, field_name
+                    tx_type, client, tx, amount, field.name(), current, field.name()
                 );
                 None
             }
@@ -102,7 +682,7 @@ impl PaymentsEngine {
     fn checked_sub_with_log(
         current: Decimal,
         amount: Decimal,
-        field_name: &str,
+        field: BalanceField,
         tx_type: &str,
         client: u16,
         tx: u32,
@@ -112,7 +692,7 @@ impl PaymentsEngine {
             None => {
                 log::error!(
                     "{} rejected: client={}, tx={}, amount={}, {}={} (underflow on {})",
-                    tx_type, client, tx, amount, field_name, current, field_name
+                    tx_type, client, tx, amount, field.name(), current, field.name()
                 );
                 None
             }
@@ -120,271 +700,2816 @@ impl PaymentsEngine {
     }
 
     pub async fn process(&self, tx: Trx) {
-        match tx {
-            Trx::Deposit { client, tx, amount } => {
-                self.process_deposit(client, tx, amount).await;
-            }
-            Trx::Withdrawal { client, tx, amount } => {
-                self.process_withdrawal(client, tx, amount).await;
-            }
-            Trx::Dispute { client, tx } => {
-                self.process_dispute(client, tx).await;
-            }
-            Trx::Resolve { client, tx } => {
-                self.process_resolve(client, tx).await;
-            }
-            Trx::Chargeback { client, tx } => {
-                self.process_chargeback(client, tx).await;
-            }
-        }
+        let _ = self.try_process(tx).await;
     }
 
-    async fn process_deposit(&self, client: u16, tx: u32, amount: Decimal) {
-        let mut tx_history = self.tx_history.lock().await;
-
-        if Self::check_duplicate_tx(&tx_history, tx, "Deposit", client, amount) {
-            return;
-        }
-
-        let mut account = self.get_or_create_account(client);
-
-        let Some(new_available) = Self::checked_add_with_log(
-            account.available, amount, "available", "Deposit", client, tx
-        ) else { return };
+    pub async fn try_process(&self, tx: Trx) -> Result<(), Rejection> {
+        self.try_process_in(tx, Currency::DEFAULT).await
+    }
 
-        let Some(new_total) = Self::checked_add_with_log(
-            account.total, amount, "total", "Deposit", client, tx
-        ) else { return };
+    /// Applies `tx` in `currency`. Deposits, withdrawals and fees use `currency`;
+    /// disputes, resolves and chargebacks always act in the currency of the
+    /// transaction they reference.
+    pub async fn try_process_in(&self, tx: Trx, currency: Currency) -> Result<(), Rejection> {
+        self.try_process_at(tx, currency, None).await
+    }
 
-        account.available = new_available;
-        account.total = new_total;
+    /// Like `try_process_in`, storing `timestamp` on the record a deposit,
+    /// withdrawal or fee creates.
+    pub async fn try_process_at(
+        &self,
+        tx: Trx,
+        currency: Currency,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Rejection> {
+        let mut wal = match &self.wal {
+            Some(wal) => Some(wal.lock().await),
+            None => None,
+        };
+        let mut tx_history = self.tx_history.lock().await;
+        self.apply_locked(&mut tx_history, wal.as_deref_mut(), tx, currency, timestamp).await
+    }
 
-        self.insert_tx_with_eviction(&mut tx_history, tx, client, amount);
+    /// Applies `txs` in order in the default currency, taking the history (and
+    /// WAL) lock once for the whole batch. Outcomes match calling `try_process`
+    /// for each; other callers wait until the batch is done. A transaction
+    /// always sees every earlier one in the batch, so a dispute right after its
+    /// deposit finds it even while other tasks are processing. Repeated
+    /// disputes of one tx within a batch apply once; the rest are rejected as
+    /// `AlreadyUnderDispute`.
+    pub async fn process_batch(&self, txs: Vec<Trx>) -> Vec<Result<(), Rejection>> {
+        self.process_batch_in(txs, Currency::DEFAULT).await
     }
 
-    async fn process_withdrawal(&self, client: u16, tx: u32, amount: Decimal) {
+    /// `process_batch` with every transaction applied as by `try_process_in`.
+    pub async fn process_batch_in(&self, txs: Vec<Trx>, currency: Currency) -> Vec<Result<(), Rejection>> {
+        let mut wal = match &self.wal {
+            Some(wal) => Some(wal.lock().await),
+            None => None,
+        };
         let mut tx_history = self.tx_history.lock().await;
-
-        if Self::check_duplicate_tx(&tx_history, tx, "Withdrawal", client, amount) {
-            return;
+        let mut outcomes = Vec::with_capacity(txs.len());
+        for tx in txs {
+            outcomes.push(self.apply_locked(&mut tx_history, wal.as_deref_mut(), tx, currency, None).await);
         }
+        outcomes
+    }
 
-        let mut account = self.get_or_create_account(client);
+    /// `process_batch` for deposit-only feeds: each client's account is looked
+    /// up (and its map shard locked) once per batch instead of once per
+    /// deposit. Outcomes, balances and history match `process_batch`. Batches
+    /// it can't match exactly go through `process_batch` instead: any other
+    /// transaction type, a tx ID repeated within the batch, a batch that would
+    /// evict history, or an engine with a WAL, idempotent deposits, an account
+    /// or per-client transaction limit, or per-transaction invariant checks.
+    pub async fn process_deposits(&self, txs: Vec<Trx>) -> Vec<Result<(), Rejection>> {
+        self.process_deposits_in(txs, Currency::DEFAULT).await
+    }
 
-        if account.available < amount {
-            log::warn!(
-                "Withdrawal rejected: client={}, tx={}, amount={}, available={} (insufficient funds)",
-                client, tx, amount, account.available
-            );
-            return;
+    /// `process_deposits` crediting `currency`; matches `process_batch_in`.
+    pub async fn process_deposits_in(&self, txs: Vec<Trx>, currency: Currency) -> Vec<Result<(), Rejection>> {
+        let config = &self.config;
+        let per_tx_work = self.wal.is_some()
+            || config.idempotent_deposits
+            || config.max_accounts.is_some()
+            || config.max_tx_per_client.is_some()
+            || config.verify_invariants
+            || config.abort_on_invariant_violation
+            || config.self_check;
+        if per_tx_work || !txs.iter().all(|tx| matches!(tx, Trx::Deposit { .. })) {
+            return self.process_batch_in(txs, currency).await;
         }
 
-        let Some(new_available) = Self::checked_sub_with_log(
-            account.available, amount, "available", "Withdrawal", client, tx
-        ) else { return };
+        let mut tx_history = self.tx_history.lock().await;
+        let fits = match config.max_tx_history {
+            Some(max) => tx_history.len().await + txs.len() <= max,
+            None => true,
+        };
+        let mut seen = HashSet::with_capacity(txs.len());
+        if !fits || !txs.iter().all(|tx| seen.insert(tx.tx())) {
+            drop(tx_history);
+            return self.process_batch_in(txs, currency).await;
+        }
 
-        let Some(new_total) = Self::checked_sub_with_log(
-            account.total, amount, "total", "Withdrawal", client, tx
-        ) else { return };
+        // Nothing in the batch is recorded until the end, so every duplicate
+        // check sees the same history `process_batch` would
+        let mut outcomes = vec![Ok(()); txs.len()];
+        let mut pending = Vec::with_capacity(txs.len());
+        for (i, tx) in txs.iter().enumerate() {
+            let Trx::Deposit { client, tx, amount } = *tx else { unreachable!() };
+            if let Err(rejection) = self.check_client(client) {
+                outcomes[i] = Err(rejection);
+            } else if Self::check_duplicate_tx(&tx_history, tx, "Deposit", client, amount).await {
+                outcomes[i] = Err(Rejection::DuplicateTx);
+            } else {
+                pending.push(i);
+            }
+        }
 
-        account.available = new_available;
-        account.total = new_total;
+        // Stable, so each client's deposits keep their batch order
+        pending.sort_by_key(|&i| txs[i].client());
+        for group in pending.chunk_by(|&a, &b| txs[a].client() == txs[b].client()) {
+            let mut account = self.get_or_create_account_in(txs[group[0]].client(), currency);
+            for &i in group {
+                let Trx::Deposit { tx, amount, .. } = txs[i] else { unreachable!() };
+                outcomes[i] = self.credit_deposit(&mut account, tx, amount);
+            }
+        }
 
-        self.insert_tx_with_eviction(&mut tx_history, tx, client, amount);
+        for (tx, outcome) in txs.into_iter().zip(&outcomes) {
+            if let (Trx::Deposit { client, tx, amount }, Ok(())) = (tx, outcome) {
+                let record = TxRecord::new(TxKind::Deposit, client, currency, amount, None);
+                self.insert_tx_with_eviction(&mut tx_history, tx, record).await;
+                self.applied.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        outcomes
     }
 
-    async fn process_dispute(&self, client: u16, tx: u32) {
-        let mut tx_history = self.tx_history.lock().await;
-
-        if let Some(tx_record) = tx_history.get_mut(&tx) {
-            if tx_record.client != client {
-                log::warn!(
-                    "Dispute rejected: client={} attempted to dispute tx={} belonging to client={}",
-                    client, tx, tx_record.client
-                );
-                return;
+    async fn apply_locked(
+        &self,
+        tx_history: &mut S,
+        wal: Option<&mut WalWriter>,
+        tx: Trx,
+        currency: Currency,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Rejection> {
+        let (client, tx_id) = (tx.client(), tx.tx());
+        self.check_client(client)?;
+        // A retry changes nothing, so it is neither logged nor counted
+        if self.is_deposit_retry(tx_history, &tx, currency).await {
+            return Ok(());
+        }
+        // Logged before applying, so nothing is applied that the log lacks
+        if let Some(writer) = wal {
+            if let Err(e) = writer.append(&tx, currency, timestamp) {
+                log::error!("Transaction rejected: client={}, tx={} (WAL append failed: {})", client, tx_id, e);
+                return Err(Rejection::LogWriteFailed);
             }
-
-            if tx_record.status == TrxStatus::ChargedBack {
-                log::warn!(
-                    "Dispute rejected: client={}, tx={} (transaction already charged back)",
-                    client, tx
+        }
+        let outcome = self.dispatch(tx_history, tx, currency, timestamp).await;
+        if self.config.abort_on_invariant_violation || self.config.verify_invariants {
+            let currency = match tx_history.get(tx_id).await {
+                Some(record) if record.client == client => record.currency,
+                _ => currency,
+            };
+            if self.config.abort_on_invariant_violation && !self.totals_hold(client, currency) {
+                log::error!(
+                    "INVARIANT VIOLATED: client={}, currency={}, tx={}, aborting",
+                    client, currency, tx_id
                 );
-                return;
+                return Err(Rejection::InvariantViolated);
             }
-
-            if tx_record.status == TrxStatus::UnderDispute {
-                log::warn!(
-                    "Dispute rejected: client={}, tx={} (already under dispute)",
-                    client, tx
-                );
-                return;
+            if self.config.verify_invariants {
+                self.recompute_total_in(client, currency);
             }
+        }
+        if self.config.self_check {
+            self.held_mismatches(tx_history).await;
+        }
+        if outcome.is_ok() {
+            self.applied.fetch_add(1, Ordering::Relaxed);
+        }
+        outcome
+    }
 
-            let amount = tx_record.amount;
-            tx_record.status = TrxStatus::UnderDispute;
+    /// Corruption canary: finds accounts holding less than their records under
+    /// dispute, as left behind by an operation abandoned mid-update. Eviction
+    /// can leave `held` above the retained disputes, so only shortfalls count.
+    pub async fn self_check(&self) -> Vec<HeldMismatch> {
+        let tx_history = self.tx_history.lock().await;
+        self.held_mismatches(&tx_history).await
+    }
 
-            if let Some(mut account) = self.user_account_map.get_mut(&client) {
-                let Some(new_available) = Self::checked_sub_with_log(
-                    account.available, amount, "available", "Dispute", client, tx
-                ) else {
-                    tx_record.status = TrxStatus::Normal;
-                    return;
-                };
+    async fn held_mismatches(&self, tx_history: &S) -> Vec<HeldMismatch> {
+        let mut disputed: HashMap<AccountKey, Decimal> = HashMap::new();
+        tx_history.for_each_record(|_, record| {
+            if record.status == TrxStatus::UnderDispute {
+                let sum = disputed.entry((record.client, record.currency)).or_default();
+                *sum = sum.saturating_add(record.disputed_amount());
+            }
+        }).await;
 
-                let Some(new_held) = Self::checked_add_with_log(
-                    account.held, amount, "held", "Dispute", client, tx
-                ) else {
-                    tx_record.status = TrxStatus::Normal;
-                    return;
-                };
+        let mut mismatches: Vec<HeldMismatch> = disputed
+            .into_iter()
+            .filter_map(|((client, currency), disputed)| {
+                let held = self
+                    .user_account_map
+                    .get(&(client, currency))
+                    .map_or(Decimal::ZERO, |account| account.held);
+                (held < disputed).then_some(HeldMismatch { client, currency, disputed, held })
+            })
+            .collect();
+        mismatches.sort_by_key(|mismatch| (mismatch.client, mismatch.currency));
 
-                if new_available < Decimal::ZERO {
-                    log::warn!(
-                        "Dispute creates negative balance: client={}, tx={}, amount={}, available={} -> {} (business rule: allowed)",
-                        client, tx, amount, account.available, new_available
-                    );
-                }
+        for mismatch in &mismatches {
+            log::error!(
+                "Self-check failed: client={}, currency={}, held={} but {} under dispute",
+                mismatch.client, mismatch.currency, mismatch.held, mismatch.disputed
+            );
+        }
+        mismatches
+    }
 
-                account.available = new_available;
-                account.held = new_held;
+    fn totals_hold(&self, client: u16, currency: Currency) -> bool {
+        self.user_account_map
+            .get(&(client, currency))
+            .is_none_or(|account| account.available.checked_add(account.held) == Some(account.total))
+    }
+
+    /// Restores `total = available + held` for the client if it has drifted,
+    /// returning whether a repair was needed.
+    pub fn recompute_total(&self, client: u16) -> bool {
+        self.recompute_total_in(client, Currency::DEFAULT)
+    }
+
+    pub fn recompute_total_in(&self, client: u16, currency: Currency) -> bool {
+        let Some(mut account) = self.user_account_map.get_mut(&(client, currency)) else {
+            return false;
+        };
+        let Some(expected) = account.available.checked_add(account.held) else {
+            log::error!(
+                "Invariant check failed: client={}, available={}, held={} (overflow on total)",
+                client, account.available, account.held
+            );
+            return false;
+        };
+        if account.total == expected {
+            return false;
+        }
+
+        log::error!(
+            "INVARIANT VIOLATED: client={}, total={} != available={} + held={}, repairing total to {}",
+            client, account.total, account.available, account.held, expected
+        );
+        account.total = expected;
+        true
+    }
+
+    async fn dispatch(
+        &self,
+        tx_history: &mut S,
+        tx: Trx,
+        currency: Currency,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Rejection> {
+        match tx {
+            Trx::Deposit { client, tx, amount } => {
+                self.process_deposit(tx_history, client, tx, currency, amount, timestamp).await
+            }
+            Trx::Withdrawal { client, tx, amount } => {
+                self.process_withdrawal(tx_history, client, tx, currency, amount, timestamp).await
+            }
+            Trx::Dispute { client, tx } => {
+                self.process_dispute(tx_history, client, tx).await
+            }
+            Trx::Resolve { client, tx } => {
+                self.process_resolve(tx_history, client, tx).await
+            }
+            Trx::PartialResolve { client, tx, amount } => {
+                self.process_partial_resolve(tx_history, client, tx, amount).await
+            }
+            Trx::Chargeback { client, tx } => {
+                self.process_chargeback(tx_history, client, tx).await
+            }
+            Trx::Fee { client, tx, amount } => {
+                self.process_fee(tx_history, client, tx, currency, amount, timestamp).await
+            }
+            Trx::Unlock { client, tx } => self.process_unlock(client, tx, currency),
+            Trx::Freeze { client, tx } => self.process_freeze(client, tx),
+            Trx::Thaw { client, tx } => self.process_thaw(client, tx),
+        }
+    }
+
+    /// With `idempotent_deposits`, whether `tx` repeats a recorded deposit
+    /// exactly (same tx ID, client, currency and amount).
+    async fn is_deposit_retry(&self, tx_history: &S, tx: &Trx, currency: Currency) -> bool {
+        let Trx::Deposit { client, tx, amount } = *tx else {
+            return false;
+        };
+        if !self.config.idempotent_deposits {
+            return false;
+        }
+        let is_retry = tx_history.get(tx).await.is_some_and(|record| {
+            record.kind == TxKind::Deposit
+                && record.client == client
+                && record.currency == currency
+                && record.amount == amount
+        });
+        if is_retry {
+            log::info!(
+                "Deposit retry ignored: client={}, tx={}, amount={} (already applied)",
+                client, tx, amount
+            );
+        }
+        is_retry
+    }
+
+    async fn process_deposit(
+        &self,
+        tx_history: &mut S,
+        client: u16,
+        tx: u32,
+        currency: Currency,
+        amount: Decimal,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Rejection> {
+        // Exact retries never get here; see `is_deposit_retry`
+        if self.config.idempotent_deposits {
+            if let Some(record) = tx_history.get(tx).await {
+                log::error!(
+                    "Deposit rejected: client={}, tx={}, amount={} (conflicts with recorded {} of {} for client={})",
+                    client, tx, amount, record.kind.name(), record.amount, record.client
+                );
+                return Err(Rejection::ConflictingDuplicate);
+            }
+        }
+
+        if Self::check_duplicate_tx(tx_history, tx, "Deposit", client, amount).await {
+            return Err(Rejection::DuplicateTx);
+        }
+        self.check_account_limit(client, currency, "Deposit", tx)?;
+        self.check_client_tx_cap(client, currency, "Deposit", tx)?;
+
+        // The account guard must not be held across the store's await points
+        self.credit_deposit(&mut self.get_or_create_account_in(client, currency), tx, amount)?;
+
+        let record = TxRecord::new(TxKind::Deposit, client, currency, amount, timestamp);
+        self.insert_tx_with_eviction(tx_history, tx, record).await;
+        Ok(())
+    }
+
+    /// The balance side of a deposit, shared with `process_deposits`.
+    fn credit_deposit(&self, account: &mut UserAccount, tx: u32, amount: Decimal) -> Result<(), Rejection> {
+        let client = account.client;
+        Self::check_frozen(account, "Deposit", tx)?;
+
+        let Some(new_available) = Self::checked_add_with_log(
+            account.available, amount, BalanceField::Available, "Deposit", client, tx
+        ) else { return Err(Rejection::Overflow(BalanceField::Available)) };
+
+        let Some(new_total) = Self::checked_add_with_log(
+            account.total, amount, BalanceField::Total, "Deposit", client, tx
+        ) else { return Err(Rejection::Overflow(BalanceField::Total)) };
+
+        account.available = new_available;
+        account.total = new_total;
+        account.tx_count += 1;
+        Ok(())
+    }
+
+    async fn process_withdrawal(
+        &self,
+        tx_history: &mut S,
+        client: u16,
+        tx: u32,
+        currency: Currency,
+        amount: Decimal,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Rejection> {
+        if Self::check_duplicate_tx(tx_history, tx, "Withdrawal", client, amount).await {
+            return Err(Rejection::DuplicateTx);
+        }
+        self.check_account_limit(client, currency, "Withdrawal", tx)?;
+        self.check_client_tx_cap(client, currency, "Withdrawal", tx)?;
+
+        {
+            let mut account = self.get_or_create_account_in(client, currency);
+            Self::check_frozen(&account, "Withdrawal", tx)?;
+
+            if self.config.block_withdrawals_during_dispute && account.open_disputes > 0 {
+                log::warn!(
+                    "Withdrawal rejected: client={}, tx={}, open_disputes={} (funds under dispute)",
+                    client, tx, account.open_disputes
+                );
+                return Err(Rejection::FundsUnderDispute);
+            }
+
+            // available - amount >= -overdraft_limit, without the subtraction overflowing
+            if account.available.saturating_add(self.config.overdraft_limit) < amount {
+                log::warn!(
+                    "Withdrawal rejected: client={}, tx={}, amount={}, available={}, overdraft_limit={} (insufficient funds)",
+                    client, tx, amount, account.available, self.config.overdraft_limit
+                );
+                return Err(Rejection::InsufficientFunds);
+            }
+
+            let Some(new_available) = Self::checked_sub_with_log(
+                account.available, amount, BalanceField::Available, "Withdrawal", client, tx
+            ) else { return Err(Rejection::Overflow(BalanceField::Available)) };
+
+            let Some(new_total) = Self::checked_sub_with_log(
+                account.total, amount, BalanceField::Total, "Withdrawal", client, tx
+            ) else { return Err(Rejection::Overflow(BalanceField::Total)) };
+
+            account.available = new_available;
+            account.total = new_total;
+            account.tx_count += 1;
+        }
+
+        let record = TxRecord::new(TxKind::Withdrawal, client, currency, amount, timestamp);
+        self.insert_tx_with_eviction(tx_history, tx, record).await;
+        Ok(())
+    }
+
+    /// Fees debit like a withdrawal but are never rejected for insufficient
+    /// funds, so they may push `available` negative.
+    async fn process_fee(
+        &self,
+        tx_history: &mut S,
+        client: u16,
+        tx: u32,
+        currency: Currency,
+        amount: Decimal,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Rejection> {
+        if Self::check_duplicate_tx(tx_history, tx, "Fee", client, amount).await {
+            return Err(Rejection::DuplicateTx);
+        }
+        self.check_account_limit(client, currency, "Fee", tx)?;
+        self.check_client_tx_cap(client, currency, "Fee", tx)?;
+
+        {
+            let mut account = self.get_or_create_account_in(client, currency);
+            Self::check_frozen(&account, "Fee", tx)?;
+
+            let Some(new_available) = Self::checked_sub_with_log(
+                account.available, amount, BalanceField::Available, "Fee", client, tx
+            ) else { return Err(Rejection::Overflow(BalanceField::Available)) };
+
+            let Some(new_total) = Self::checked_sub_with_log(
+                account.total, amount, BalanceField::Total, "Fee", client, tx
+            ) else { return Err(Rejection::Overflow(BalanceField::Total)) };
+
+            if new_available < Decimal::ZERO {
+                log::info!(
+                    "Fee overdraws account: client={}, tx={}, amount={}, available={} -> {}",
+                    client, tx, amount, account.available, new_available
+                );
+            }
+
+            account.available = new_available;
+            account.total = new_total;
+            account.tx_count += 1;
+        }
+
+        let record = TxRecord::new(TxKind::Fee, client, currency, amount, timestamp);
+        self.insert_tx_with_eviction(tx_history, tx, record).await;
+        Ok(())
+    }
+
+    /// Unlocks are not recorded in the history; `tx` only identifies the
+    /// request in logs.
+    fn process_unlock(&self, client: u16, tx: u32, currency: Currency) -> Result<(), Rejection> {
+        if !self.config.allow_unlock {
+            log::warn!("Unlock rejected: client={}, tx={} (unlock disabled)", client, tx);
+            return Err(Rejection::UnlockDisabled);
+        }
+
+        let Some(mut account) = self.user_account_map.get_mut(&(client, currency)) else {
+            log::warn!("Unlock rejected: client={}, tx={} (no account)", client, tx);
+            return Err(Rejection::AccountMissing);
+        };
+
+        if !account.locked {
+            log::warn!("Unlock rejected: client={}, tx={} (account not locked)", client, tx);
+            return Err(Rejection::AccountNotLocked);
+        }
+
+        if account.open_disputes > 0 {
+            log::warn!(
+                "Unlock rejected: client={}, tx={} ({} dispute(s) still open)",
+                client, tx, account.open_disputes
+            );
+            return Err(Rejection::OpenDisputes);
+        }
+
+        account.locked = false;
+        log::info!("Account unlocked: client={}, tx={}", client, tx);
+        Ok(())
+    }
+
+    /// Operator freeze: blocks deposits, withdrawals and fees until thawed, in
+    /// every currency the client holds. Kept apart from the chargeback lock, so
+    /// disputes on the accounts still resolve. Balances are untouched.
+    fn process_freeze(&self, client: u16, tx: u32) -> Result<(), Rejection> {
+        let mut frozen = 0;
+        for mut account in self.user_account_map.iter_mut().filter(|entry| entry.key().0 == client) {
+            account.frozen = true;
+            frozen += 1;
+            log::warn!(
+                "Account frozen by operator: client={}, currency={}, tx={}, available={}, held={}",
+                client, account.currency, tx, account.available, account.held
+            );
+        }
+        if frozen == 0 {
+            log::warn!("Freeze rejected: client={}, tx={} (no account)", client, tx);
+            return Err(Rejection::AccountMissing);
+        }
+        Ok(())
+    }
+
+    /// Lifts a freeze from every currency the client holds. A chargeback lock
+    /// is left for `process_unlock`.
+    fn process_thaw(&self, client: u16, tx: u32) -> Result<(), Rejection> {
+        let mut held = 0;
+        let mut thawed = 0;
+        for mut account in self.user_account_map.iter_mut().filter(|entry| entry.key().0 == client) {
+            held += 1;
+            if account.frozen {
+                account.frozen = false;
+                thawed += 1;
+                log::warn!("Account thawed by operator: client={}, currency={}, tx={}", client, account.currency, tx);
+            }
+        }
+        if held == 0 {
+            log::warn!("Thaw rejected: client={}, tx={} (no account)", client, tx);
+            return Err(Rejection::AccountMissing);
+        }
+        if thawed == 0 {
+            log::warn!("Thaw rejected: client={}, tx={} (account not frozen)", client, tx);
+            return Err(Rejection::AccountNotFrozen);
+        }
+        Ok(())
+    }
+
+    async fn process_dispute(
+        &self,
+        tx_history: &mut S,
+        client: u16,
+        tx: u32,
+    ) -> Result<(), Rejection> {
+        if let Some(mut tx_record) = tx_history.get(tx).await {
+            if tx_record.client != client {
+                log::warn!(
+                    "Dispute rejected: client={} attempted to dispute tx={} belonging to client={}",
+                    client, tx, tx_record.client
+                );
+                return Err(Rejection::ClientMismatch);
+            }
+
+            if !TrxStatus::can_transition(tx_record.status, TrxStatus::UnderDispute) {
+                log::warn!(
+                    "Dispute rejected: client={}, tx={}, status={:?} (not disputable)",
+                    client, tx, tx_record.status
+                );
+                return Err(if tx_record.status == TrxStatus::ChargedBack {
+                    Rejection::AlreadyChargedBack
+                } else {
+                    Rejection::AlreadyUnderDispute
+                });
+            }
+
+            if self.config.freeze_on_lock
+                && self.user_account_map.get(&(client, tx_record.currency)).is_some_and(|account| account.locked)
+            {
+                log::warn!(
+                    "Dispute rejected: client={}, tx={} (account locked, freeze_on_lock)",
+                    client, tx
+                );
+                return Err(Rejection::AccountLocked);
+            }
+
+            let amount = tx_record.amount;
+            let credit_back = self.credits_back(&tx_record);
+            tx_record.status = TrxStatus::UnderDispute;
+            tx_record.resolved = Decimal::ZERO;
+
+            if let Some(mut account) = self.user_account_map.get_mut(&(client, tx_record.currency)) {
+                if credit_back {
+                    let Some(new_held) = Self::checked_add_with_log(
+                        account.held, amount, BalanceField::Held, "Dispute", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Held));
+                    };
+
+                    let Some(new_total) = Self::checked_add_with_log(
+                        account.total, amount, BalanceField::Total, "Dispute", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Total));
+                    };
+
+                    account.held = new_held;
+                    account.total = new_total;
+                } else {
+                    let Some(new_available) = Self::checked_sub_with_log(
+                        account.available, amount, BalanceField::Available, "Dispute", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Available));
+                    };
+
+                    let Some(new_held) = Self::checked_add_with_log(
+                        account.held, amount, BalanceField::Held, "Dispute", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Held));
+                    };
+
+                    if new_available < Decimal::ZERO {
+                        if !self.config.allow_negative_on_dispute {
+                            log::warn!(
+                                "Dispute rejected: client={}, tx={}, amount={}, available={} (would overdraw available)",
+                                client, tx, amount, account.available
+                            );
+                            return Err(Rejection::InsufficientFunds);
+                        }
+                        log::warn!(
+                            "Dispute creates negative balance: client={}, tx={}, amount={}, available={} -> {} (business rule: allowed)",
+                            client, tx, amount, account.available, new_available
+                        );
+                    }
+
+                    account.available = new_available;
+                    account.held = new_held;
+                }
+                account.open_disputes += 1;
+            } else {
+                log::warn!(
+                    "Dispute rejected: client={}, tx={} (no account exists for client)",
+                    client, tx
+                );
+                return Err(Rejection::AccountMissing);
             }
+            tx_history.insert(tx, tx_record).await;
         } else {
             log::warn!(
                 "Dispute rejected: client={}, tx={} (transaction not found - may have been evicted from cache)",
                 client, tx
             );
+            return Err(Rejection::TxNotFound);
         }
+        Ok(())
     }
 
-    async fn process_resolve(&self, client: u16, tx: u32) {
-        let mut tx_history = self.tx_history.lock().await;
-
-        if let Some(tx_record) = tx_history.get_mut(&tx) {
+    /// Resolves are rejected on locked accounts: once a chargeback has locked the
+    /// account, its held funds may only leave via further chargebacks and never
+    /// return to `available`.
+    async fn process_resolve(
+        &self,
+        tx_history: &mut S,
+        client: u16,
+        tx: u32,
+    ) -> Result<(), Rejection> {
+        if let Some(mut tx_record) = tx_history.get(tx).await {
             if tx_record.client != client {
                 log::warn!(
                     "Resolve rejected: client={} attempted to resolve tx={} belonging to client={}",
                     client, tx, tx_record.client
                 );
-                return;
+                return Err(Rejection::ClientMismatch);
             }
 
-            if tx_record.status != TrxStatus::UnderDispute {
+            if !TrxStatus::can_transition(tx_record.status, TrxStatus::Resolved) {
                 log::warn!(
                     "Resolve rejected: client={}, tx={}, status={:?} (not under dispute)",
                     client, tx, tx_record.status
                 );
-                return;
+                return Err(Rejection::NotUnderDispute);
             }
 
-            let amount = tx_record.amount;
-            tx_record.status = TrxStatus::Normal;
+            if self.user_account_map.get(&(client, tx_record.currency)).is_some_and(|account| account.locked) {
+                log::warn!(
+                    "Resolve rejected: client={}, tx={} (account locked by prior chargeback)",
+                    client, tx
+                );
+                return Err(Rejection::AccountLocked);
+            }
 
-            if let Some(mut account) = self.user_account_map.get_mut(&client) {
-                let Some(new_held) = Self::checked_sub_with_log(
-                    account.held, amount, "held", "Resolve", client, tx
-                ) else {
-                    tx_record.status = TrxStatus::UnderDispute;
-                    return;
-                };
+            let amount = tx_record.disputed_amount();
+            let credit_back = self.credits_back(&tx_record);
+            tx_record.status = TrxStatus::Resolved;
+            tx_record.resolved = Decimal::ZERO;
 
-                let Some(new_available) = Self::checked_add_with_log(
-                    account.available, amount, "available", "Resolve", client, tx
+            if let Some(mut account) = self.user_account_map.get_mut(&(client, tx_record.currency)) {
+                let Some(new_held) = Self::checked_sub_with_log(
+                    account.held, amount, BalanceField::Held, "Resolve", client, tx
                 ) else {
-                    tx_record.status = TrxStatus::UnderDispute;
-                    return;
+                    return Err(Rejection::Overflow(BalanceField::Held));
                 };
 
+                // A resolved credit-back dispute lets the withdrawal stand, so the
+                // provisional credit is dropped instead of released
+                if credit_back {
+                    let Some(new_total) = Self::checked_sub_with_log(
+                        account.total, amount, BalanceField::Total, "Resolve", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Total));
+                    };
+                    account.total = new_total;
+                } else {
+                    let Some(new_available) = Self::checked_add_with_log(
+                        account.available, amount, BalanceField::Available, "Resolve", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Available));
+                    };
+                    account.available = new_available;
+                }
                 account.held = new_held;
-                account.available = new_available;
+                account.open_disputes = account.open_disputes.saturating_sub(1);
+            } else {
+                log::warn!(
+                    "Resolve rejected: client={}, tx={} (no account exists for client)",
+                    client, tx
+                );
+                return Err(Rejection::AccountMissing);
             }
+            tx_history.insert(tx, tx_record).await;
         } else {
             log::warn!(
                 "Resolve rejected: client={}, tx={} (transaction not found - may have been evicted from cache)",
                 client, tx
             );
+            return Err(Rejection::TxNotFound);
         }
+        Ok(())
     }
 
-    async fn process_chargeback(&self, client: u16, tx: u32) {
-        let mut tx_history = self.tx_history.lock().await;
+    /// Releases `amount` of an open dispute the way a resolve releases all of
+    /// it; the rest stays disputed. Releasing the whole remainder is a resolve.
+    async fn process_partial_resolve(
+        &self,
+        tx_history: &mut S,
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+    ) -> Result<(), Rejection> {
+        let Some(mut tx_record) = tx_history.get(tx).await else {
+            log::warn!(
+                "Partial resolve rejected: client={}, tx={} (transaction not found - may have been evicted from cache)",
+                client, tx
+            );
+            return Err(Rejection::TxNotFound);
+        };
+        if tx_record.client != client {
+            log::warn!(
+                "Partial resolve rejected: client={} attempted to resolve tx={} belonging to client={}",
+                client, tx, tx_record.client
+            );
+            return Err(Rejection::ClientMismatch);
+        }
+        if tx_record.status != TrxStatus::UnderDispute {
+            log::warn!(
+                "Partial resolve rejected: client={}, tx={}, status={:?} (not under dispute)",
+                client, tx, tx_record.status
+            );
+            return Err(Rejection::NotUnderDispute);
+        }
+
+        let remaining = tx_record.disputed_amount();
+        if amount <= Decimal::ZERO || amount > remaining {
+            log::warn!(
+                "Partial resolve rejected: client={}, tx={}, amount={} (disputed amount is {})",
+                client, tx, amount, remaining
+            );
+            return Err(Rejection::ExceedsDisputed);
+        }
+        if amount == remaining {
+            return self.process_resolve(tx_history, client, tx).await;
+        }
+
+        let credit_back = self.credits_back(&tx_record);
+        {
+            let Some(mut account) = self.user_account_map.get_mut(&(client, tx_record.currency)) else {
+                log::warn!("Partial resolve rejected: client={}, tx={} (no account exists for client)", client, tx);
+                return Err(Rejection::AccountMissing);
+            };
+            if account.locked {
+                log::warn!(
+                    "Partial resolve rejected: client={}, tx={} (account locked by prior chargeback)",
+                    client, tx
+                );
+                return Err(Rejection::AccountLocked);
+            }
+            if account.held < amount {
+                log::error!(
+                    "Partial resolve rejected: client={}, tx={}, amount={}, held={} (held below amount)",
+                    client, tx, amount, account.held
+                );
+                return Err(Rejection::InsufficientHeld);
+            }
+
+            let new_held = account.held - amount;
+            if credit_back {
+                let Some(new_total) = Self::checked_sub_with_log(
+                    account.total, amount, BalanceField::Total, "Partial resolve", client, tx
+                ) else {
+                    return Err(Rejection::Overflow(BalanceField::Total));
+                };
+                account.total = new_total;
+            } else {
+                let Some(new_available) = Self::checked_add_with_log(
+                    account.available, amount, BalanceField::Available, "Partial resolve", client, tx
+                ) else {
+                    return Err(Rejection::Overflow(BalanceField::Available));
+                };
+                account.available = new_available;
+            }
+            account.held = new_held;
+        }
+
+        tx_record.resolved += amount;
+        log::info!(
+            "Partial resolve processed: client={}, tx={}, amount={}, still disputed={}",
+            client, tx, amount, tx_record.disputed_amount()
+        );
+        tx_history.insert(tx, tx_record).await;
+        Ok(())
+    }
 
-        if let Some(tx_record) = tx_history.get_mut(&tx) {
+    /// Chargebacks remain allowed on an already locked account, so every other
+    /// open dispute of the client can still be settled.
+    async fn process_chargeback(
+        &self,
+        tx_history: &mut S,
+        client: u16,
+        tx: u32,
+    ) -> Result<(), Rejection> {
+        if let Some(mut tx_record) = tx_history.get(tx).await {
             if tx_record.client != client {
                 log::warn!(
                     "Chargeback rejected: client={} attempted to chargeback tx={} belonging to client={}",
                     client, tx, tx_record.client
                 );
-                return;
+                return Err(Rejection::ClientMismatch);
             }
 
-            if tx_record.status != TrxStatus::UnderDispute {
+            // With allow_direct_chargeback, an undisputed record is charged back in one
+            // step: funds go to held and straight back out, so only available moves
+            let direct = self.config.allow_direct_chargeback
+                && matches!(tx_record.status, TrxStatus::Normal | TrxStatus::Resolved);
+
+            if !direct && !TrxStatus::can_transition(tx_record.status, TrxStatus::ChargedBack) {
                 log::warn!(
                     "Chargeback rejected: client={}, tx={}, status={:?} (not under dispute)",
                     client, tx, tx_record.status
                 );
-                return;
+                return Err(Rejection::NotUnderDispute);
             }
 
-            let amount = tx_record.amount;
+            let amount = tx_record.disputed_amount();
+            let credit_back = self.credits_back(&tx_record);
             tx_record.status = TrxStatus::ChargedBack;
 
-            if let Some(mut account) = self.user_account_map.get_mut(&client) {
-                let Some(new_held) = Self::checked_sub_with_log(
-                    account.held, amount, "held", "Chargeback", client, tx
-                ) else {
-                    tx_record.status = TrxStatus::UnderDispute;
-                    return;
-                };
+            if let Some(mut account) = self.user_account_map.get_mut(&(client, tx_record.currency)) {
+                // Held always covers every open dispute, so a shortfall means
+                // the account or record was altered behind the engine's back
+                if !direct && account.held < amount {
+                    log::error!(
+                        "Chargeback rejected: client={}, tx={}, amount={}, held={} (held below disputed amount)",
+                        client, tx, amount, account.held
+                    );
+                    return Err(Rejection::InsufficientHeld);
+                }
 
-                let Some(new_total) = Self::checked_sub_with_log(
-                    account.total, amount, "total", "Chargeback", client, tx
-                ) else {
-                    tx_record.status = TrxStatus::UnderDispute;
-                    return;
-                };
+                // A direct chargeback stands in for the dispute as well, so it
+                // is held to the dispute's rules
+                if direct && self.config.freeze_on_lock && account.locked {
+                    log::warn!(
+                        "Chargeback rejected: client={}, tx={} (account locked, freeze_on_lock)",
+                        client, tx
+                    );
+                    return Err(Rejection::AccountLocked);
+                }
 
-                account.held = new_held;
-                account.total = new_total;
+                if credit_back {
+                    // Reversing the withdrawal credits it back: the held credit (or,
+                    // directly, a fresh one) lands in available
+                    let Some(new_available) = Self::checked_add_with_log(
+                        account.available, amount, BalanceField::Available, "Chargeback", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Available));
+                    };
+
+                    if direct {
+                        let Some(new_total) = Self::checked_add_with_log(
+                            account.total, amount, BalanceField::Total, "Chargeback", client, tx
+                        ) else {
+                            return Err(Rejection::Overflow(BalanceField::Total));
+                        };
+                        account.total = new_total;
+                    } else {
+                        let Some(new_held) = Self::checked_sub_with_log(
+                            account.held, amount, BalanceField::Held, "Chargeback", client, tx
+                        ) else {
+                            return Err(Rejection::Overflow(BalanceField::Held));
+                        };
+                        account.held = new_held;
+                        account.open_disputes = account.open_disputes.saturating_sub(1);
+                    }
+                    account.available = new_available;
+                } else {
+                    let (source, source_field) = if direct {
+                        (account.available, BalanceField::Available)
+                    } else {
+                        (account.held, BalanceField::Held)
+                    };
+                    let Some(new_source) = Self::checked_sub_with_log(
+                        source, amount, source_field, "Chargeback", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(source_field));
+                    };
+
+                    let Some(new_total) = Self::checked_sub_with_log(
+                        account.total, amount, BalanceField::Total, "Chargeback", client, tx
+                    ) else {
+                        return Err(Rejection::Overflow(BalanceField::Total));
+                    };
+
+                    if direct && new_source < Decimal::ZERO {
+                        if !self.config.allow_negative_on_dispute {
+                            log::warn!(
+                                "Chargeback rejected: client={}, tx={}, amount={}, available={} (would overdraw available)",
+                                client, tx, amount, account.available
+                            );
+                            return Err(Rejection::InsufficientFunds);
+                        }
+                        log::warn!(
+                            "Chargeback creates negative balance: client={}, tx={}, amount={}, available={} -> {} (business rule: allowed)",
+                            client, tx, amount, account.available, new_source
+                        );
+                    }
+
+                    if direct {
+                        account.available = new_source;
+                    } else {
+                        account.held = new_source;
+                        account.open_disputes = account.open_disputes.saturating_sub(1);
+                    }
+                    account.total = new_total;
+                }
                 account.locked = true;
 
                 log::info!(
                     "Chargeback processed: client={}, tx={}, amount={}, account locked",
                     client, tx, amount
                 );
+            } else {
+                log::warn!(
+                    "Chargeback rejected: client={}, tx={} (no account exists for client)",
+                    client, tx
+                );
+                return Err(Rejection::AccountMissing);
             }
+            tx_history.insert(tx, tx_record).await;
         } else {
             log::warn!(
                 "Chargeback rejected: client={}, tx={} (transaction not found - may have been evicted from cache)",
                 client, tx
             );
+            return Err(Rejection::TxNotFound);
         }
+        Ok(())
+    }
+}
+
+impl Default for PaymentsEngine {
+    fn default() -> Self {
+        Self::with_config(ProcessorConfig::default())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::services::trx_processor::process_csv_str;
     use rust_decimal_macros::dec;
     use Trx::Deposit;
 
     #[tokio::test]
     async fn test_create_account_on_first_tx() {
         let engine = PaymentsEngine::new();
-        let tx = Deposit {
-            client: 1,
-            tx: 100,
-            amount: dec!(10.0),
-        };
-        engine.process(tx).await;
+        let tx = Deposit {
+            client: 1,
+            tx: 100,
+            amount: dec!(10.0),
+        };
+        engine.process(tx).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].client, 1);
+    }
+
+    #[tokio::test]
+    async fn test_totals_invariant_maintained() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert!(accounts[0].verify_totals());
+    }
+
+    #[tokio::test]
+    async fn test_deposit_increases_available() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.5),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(10.5));
+        assert_eq!(accounts[0].total, dec!(10.5));
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_decreases_when_possible() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(5.0));
+        assert_eq!(accounts[0].total, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_withdrawal_ignored() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(5.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(10.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        // Should still have original deposit
+        assert_eq!(accounts[0].available, dec!(5.0));
+        assert_eq!(accounts[0].total, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_within_overdraft_limit() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_overdraft_limit(dec!(20.0)));
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(5.0),
+        }).await;
+
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(15.0),
+        }).await;
+        assert_eq!(result, Ok(()));
+        // Exactly at the limit still passes
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: dec!(10.0),
+        }).await;
+        assert_eq!(result, Ok(()));
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-20.0));
+        assert_eq!(accounts[0].total, dec!(-20.0));
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_beyond_overdraft_limit_rejected() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_overdraft_limit(dec!(20.0)));
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(5.0),
+        }).await;
+
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(25.01),
+        }).await;
+        assert_eq!(result, Err(Rejection::InsufficientFunds));
+        assert_eq!(engine.get_accounts()[0].available, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_tx_indexing() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 100,
+            amount: dec!(10.0),
+        }).await;
+
+        // Check transaction was stored
+        let tx_history = engine.tx_history.lock().await;
+        assert!(tx_history.contains(100).await);
+        let tx_record = &tx_history.get(100).await.unwrap();
+        assert_eq!(tx_record.client, 1);
+        assert_eq!(tx_record.amount, dec!(10.0));
+        assert_eq!(tx_record.status, TrxStatus::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_resolve_roundtrip() {
+        let engine = PaymentsEngine::new();
+
+        // Make a deposit
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+
+        // Dispute it
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(10.0));
+        assert_eq!(accounts[0].total, dec!(10.0));
+
+        // Resolve it
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_resolved_status_and_redispute() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(engine.tx_history.lock().await.get(1).await.unwrap().status, TrxStatus::Resolved);
+
+        // A resolved record is again eligible for dispute, but not for resolve
+        let result = engine.try_process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::NotUnderDispute));
+        let result = engine.try_process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(engine.tx_history.lock().await.get(1).await.unwrap().status, TrxStatus::UnderDispute);
+
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(engine.tx_history.lock().await.get(1).await.unwrap().status, TrxStatus::ChargedBack);
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].total, dec!(0.0));
+        assert!(accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_redispute_keeps_resolved_status() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new().with_allow_negative_on_dispute(false)
+        );
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+
+        let result = engine.try_process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::InsufficientFunds));
+        assert_eq!(engine.tx_history.lock().await.get(1).await.unwrap().status, TrxStatus::Resolved);
+    }
+
+    #[tokio::test]
+    async fn test_direct_chargeback_rejected_by_default() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+
+        let result = engine.try_process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::NotUnderDispute));
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert!(!accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_direct_chargeback_when_allowed() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new().with_allow_direct_chargeback(true)
+        );
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(4.0),
+        }).await;
+
+        let result = engine.try_process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(engine.tx_history.lock().await.get(1).await.unwrap().status, TrxStatus::ChargedBack);
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(4.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(4.0));
+        assert_eq!(accounts[0].open_disputes, 0);
+        assert!(accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_direct_chargeback_keeps_disputed_path() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new().with_allow_direct_chargeback(true)
+        );
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(0.0));
+        assert_eq!(accounts[0].open_disputes, 0);
+
+        // A second chargeback on the same record is still rejected
+        let result = engine.try_process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::NotUnderDispute));
+    }
+
+    #[tokio::test]
+    async fn test_direct_chargeback_follows_dispute_rules() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new()
+                .with_allow_direct_chargeback(true)
+                .with_allow_negative_on_dispute(false)
+        );
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(8.0),
+        }).await;
+
+        let result = engine.try_process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::InsufficientFunds));
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(2.0));
+        assert!(!accounts[0].locked);
+
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new()
+                .with_allow_direct_chargeback(true)
+                .with_freeze_on_lock(true)
+        );
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let result = engine.try_process(Trx::Chargeback {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountLocked));
+        assert_eq!(engine.get_accounts()[0].total, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_chargeback_locks_account() {
+        let engine = PaymentsEngine::new();
+
+        // Make a deposit
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(5.0),
+        }).await;
+
+        // Dispute it
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Chargeback
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(0.0));
+        assert!(accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_partial_resolve_then_chargeback_remainder() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let partial = Trx::PartialResolve {
+            client: 1,
+            tx: 1,
+            amount: dec!(60.0),
+        };
+        assert_eq!(engine.try_process(partial).await, Ok(()));
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(60.0));
+        assert_eq!(accounts[0].held, dec!(40.0));
+        assert_eq!(engine.all_disputed().await, vec![(1, 1, dec!(40.0))]);
+
+        // Export carries the partial release, so a replay lands in the same state
+        let mut exported = Vec::new();
+        engine.export_transactions(&mut exported).await.unwrap();
+        let replayed = PaymentsEngine::new();
+        let exported = String::from_utf8(exported).unwrap();
+        process_csv_str(&replayed, &exported, &ProcessorConfig::new()).await.unwrap();
+        assert_eq!(replayed.get_accounts()[0].held, dec!(40.0));
+
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(60.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(60.0));
+        assert!(accounts[0].locked);
+        assert!(engine.all_disputed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_partial_resolve_limits() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+
+        let partial = |amount| Trx::PartialResolve { client: 1, tx: 1, amount };
+        assert_eq!(engine.try_process(partial(dec!(1.0))).await, Err(Rejection::NotUnderDispute));
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(engine.try_process(partial(dec!(0.0))).await, Err(Rejection::ExceedsDisputed));
+        assert_eq!(engine.try_process(partial(dec!(4.0))).await, Ok(()));
+        assert_eq!(engine.try_process(partial(dec!(6.5))).await, Err(Rejection::ExceedsDisputed));
+
+        // Releasing exactly what is left resolves the dispute
+        assert_eq!(engine.try_process(partial(dec!(6.0))).await, Ok(()));
+        assert_eq!(engine.tx_status(1).await, Some(TrxStatus::Resolved));
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+
+        // A fresh dispute holds the full amount again
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(engine.get_accounts()[0].held, dec!(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_dispute_wrong_client() {
+        let engine = PaymentsEngine::new();
+
+        // Client 1 makes deposit
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+
+        // Client 2 tries to dispute it - should be ignored
+        engine.process(Trx::Dispute {
+            client: 2,
+            tx: 1,
+        }).await;
+
+        // Client 1's funds should be unchanged
+        let accounts = engine.get_accounts();
+        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(client1.available, dec!(10.0));
+        assert_eq!(client1.held, dec!(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_without_dispute_ignored() {
+        let engine = PaymentsEngine::new();
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+
+        // Try to resolve without disputing first
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Should still be in normal state
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_after_chargeback_ignored() {
+        let engine = PaymentsEngine::new();
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Try to dispute again - should be ignored
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_lru_pruning_limits_memory() {
+        // Create engine with max history of 2 transactions
+        let engine = PaymentsEngine::with_max_history(Some(2));
+
+        // Process 3 deposits
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(20.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 3,
+            amount: dec!(30.0),
+        }).await;
+
+        // Verify only 2 transactions are stored (oldest was evicted)
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.len().await, 2);
+        assert!(!tx_history.contains(1).await); // tx 1 should be evicted
+        assert!(tx_history.contains(2).await);
+        assert!(tx_history.contains(3).await);
+
+        // Account should still have all deposits
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].total, dec!(60.0));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_fails_on_pruned_transaction() {
+        let engine = PaymentsEngine::with_max_history(Some(1));
+
+        // Process 2 deposits (first will be pruned)
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(20.0),
+        }).await;
+
+        // Try to dispute the pruned transaction - should be ignored
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Account should be unchanged (no funds held)
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(30.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_negative_balance_allowed_on_dispute() {
+        // Test that disputes can create negative balances (business rule)
+        let engine = PaymentsEngine::new();
+
+        // Deposit 100
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+
+        // Withdraw 80
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(80.0),
+        }).await;
+
+        // Now available = 20, total = 20
+
+        // Dispute the original deposit of 100
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // This creates negative available balance
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-80.0)); // 20 - 100 = -80
+        assert_eq!(accounts[0].held, dec!(100.0));
+        assert_eq!(accounts[0].total, dec!(20.0)); // -80 + 100 = 20
+        assert!(!accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_negative_balance_dispute_rejected_when_disallowed() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new().with_allow_negative_on_dispute(false)
+        );
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(80.0),
+        }).await;
+
+        // Disputing the deposit would take available from 20 to -80
+        let result = engine.try_process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::InsufficientFunds));
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(20.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(20.0));
+        assert_eq!(engine.tx_history.lock().await.get(1).await.unwrap().status, TrxStatus::Normal);
+
+        // A dispute covered by available funds still goes through
+        engine.process(Deposit {
+            client: 1,
+            tx: 3,
+            amount: dec!(5.0),
+        }).await;
+        let result = engine.try_process(Trx::Dispute {
+            client: 1,
+            tx: 3,
+        }).await;
+        assert_eq!(result, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_negative_balance_withdrawal_dispute_scenario() {
+        let engine = PaymentsEngine::new();
+
+        // Deposit 50
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(50.0),
+        }).await;
+
+        // Withdraw 40
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(40.0),
+        }).await;
+
+        // Dispute the withdrawal (holds 40 from available 10)
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 2,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-30.0)); // 10 - 40 = -30
+        assert_eq!(accounts[0].held, dec!(40.0));
+        assert_eq!(accounts[0].total, dec!(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_negative_balance_resolve_returns_to_positive() {
+        let engine = PaymentsEngine::new();
+
+        // Create negative balance scenario
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(90.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Available is now -90 (10 - 100)
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-90.0));
+
+        // Resolve the dispute
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Available returns to positive
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(10.0)); // -90 + 100 = 10
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_negative_balance_chargeback_reduces_total() {
+        let engine = PaymentsEngine::new();
+
+        // Create negative balance scenario and chargeback
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(95.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Available is now -95 (5 - 100), held is 100, total is 5
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-95.0));
+        assert_eq!(accounts[0].held, dec!(100.0));
+        assert_eq!(accounts[0].total, dec!(5.0));
+
+        // Chargeback
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Available still -95, held removed, total is now -95
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-95.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(-95.0)); // -95 + 0 = -95 (lost the 100)
+        assert!(accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_already_under_dispute_rejected() {
+        let engine = PaymentsEngine::new();
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+
+        // First dispute succeeds
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].held, dec!(100.0));
+
+        // Second dispute should be rejected (already under dispute)
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Should be unchanged
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(100.0));
+        assert_eq!(accounts[0].total, dec!(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_deposit_overflow_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // Deposit close to max
+        let near_max = Decimal::MAX - dec!(10.0);
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: near_max,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, near_max);
+        assert_eq!(accounts[0].total, near_max);
+
+        // Try to deposit more (should overflow and be rejected)
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(20.0),
+        }).await;
+
+        // Account should be unchanged (overflow rejected)
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, near_max);
+        assert_eq!(accounts[0].total, near_max);
+
+        // Transaction should not be stored
+        let tx_history = engine.tx_history.lock().await;
+        assert!(!tx_history.contains(2).await);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_no_underflow() {
+        let engine = PaymentsEngine::new();
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+
+        // Valid withdrawal
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(50.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(50.0));
+        assert_eq!(accounts[0].total, dec!(50.0));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_overflow_on_held_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // Create account with near-max held
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Manually set held to near max (for testing - wouldn't happen in production)
+        {
+            let mut account = engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap();
+            account.held = Decimal::MAX - dec!(10.0);
+        }
+
+        // Deposit and dispute to overflow held
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(100.0),
+        }).await;
+
+        // This dispute should be rejected due to held overflow
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 2,
+        }).await;
+
+        // Check transaction 2 is still Normal (dispute rejected)
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.get(2).await.unwrap().status, TrxStatus::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_overflow_on_available_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // Create disputed transaction
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Manually set available to near max (for testing)
+        {
+            let mut account = engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap();
+            account.available = Decimal::MAX - dec!(10.0);
+        }
+
+        // Resolve should be rejected due to available overflow
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // Transaction should still be under dispute (resolve rejected)
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.get(1).await.unwrap().status, TrxStatus::UnderDispute);
+
+        // Held should still have the amount
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].held, dec!(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_deposit_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // First deposit succeeds
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(100.0));
+        assert_eq!(accounts[0].total, dec!(100.0));
+
+        // Second deposit with same tx ID should be rejected
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(50.0),
+        }).await;
+
+        // Account should be unchanged (only first deposit applied)
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(100.0));
+        assert_eq!(accounts[0].total, dec!(100.0));
+
+        // Transaction history should only have first deposit
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.get(1).await.unwrap().amount, dec!(100.0));
+        assert_eq!(tx_history.get(1).await.unwrap().client, 1);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_withdrawal_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // Setup with initial deposit
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+
+        // First withdrawal succeeds
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(30.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(70.0));
+
+        // Second withdrawal with same tx ID should be rejected
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(20.0),
+        }).await;
+
+        // Account should be unchanged
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(70.0));
+        assert_eq!(accounts[0].total, dec!(70.0));
+
+        // Transaction history should only have first withdrawal
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.get(2).await.unwrap().amount, dec!(30.0));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_different_clients_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // Client 1 deposits with tx=1
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+
+        // Client 2 tries to deposit with same tx=1 (should be rejected)
+        engine.process(Deposit {
+            client: 2,
+            tx: 1,
+            amount: dec!(50.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+
+        // Client 1 should have their deposit
+        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(client1.available, dec!(100.0));
+
+        // Client 2 should have nothing (their deposit was rejected)
+        assert!(accounts.iter().find(|a| a.client == 2).is_none());
+
+        // Transaction history should only have client 1's transaction
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.get(1).await.unwrap().client, 1);
+        assert_eq!(tx_history.get(1).await.unwrap().amount, dec!(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_mixed_types_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // Deposit with tx=1
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+
+        // Try withdrawal with same tx=1 (should be rejected)
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 1,
+            amount: dec!(50.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        // Only deposit should have been applied
+        assert_eq!(accounts[0].available, dec!(100.0));
+        assert_eq!(accounts[0].total, dec!(100.0));
+
+        // Transaction history should only have deposit
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.len().await, 1);
+        assert_eq!(tx_history.get(1).await.unwrap().amount, dec!(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_after_dispute_rejected() {
+        let engine = PaymentsEngine::new();
+
+        // Deposit and dispute it
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(100.0));
+
+        // Try to deposit with same tx=1 (should be rejected even though under dispute)
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(50.0),
+        }).await;
+
+        // Account should be unchanged
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(100.0));
+        assert_eq!(accounts[0].total, dec!(100.0));
+
+        // Transaction should still be under dispute with original amount
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.get(1).await.unwrap().status, TrxStatus::UnderDispute);
+        assert_eq!(tx_history.get(1).await.unwrap().amount, dec!(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_on_account_locked_by_other_chargeback_rejected() {
+        let engine = PaymentsEngine::new();
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 2,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        // tx 2 may not be resolved back into available on the locked account
+        let result = engine.try_process(Trx::Resolve { client: 1, tx: 2 }).await;
+        assert_eq!(result, Err(Rejection::AccountLocked));
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(5.0));
+        assert!(accounts[0].locked);
+        assert_eq!(engine.tx_history.lock().await.get(2).await.unwrap().status, TrxStatus::UnderDispute);
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_on_already_locked_account_allowed() {
+        let engine = PaymentsEngine::new();
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 2,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
+
+        let result = engine.try_process(Trx::Chargeback { client: 1, tx: 2 }).await;
+        assert_eq!(result, Ok(()));
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts[0].total, dec!(0.0));
+        assert!(accounts[0].verify_totals());
+        assert!(accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_accounts_sorted_by_total_desc() {
+        let engine = PaymentsEngine::new();
+        for (client, amount) in [(1u16, dec!(5.0)), (2, dec!(20.0)), (3, dec!(5.0)), (4, dec!(7.5))] {
+            engine.process(Deposit {
+                client,
+                tx: client as u32,
+                amount,
+            }).await;
+        }
+        // Holding funds moves them out of available but keeps the total
+        engine.process(Trx::Dispute {
+            client: 2,
+            tx: 2,
+        }).await;
+
+        let by_total: Vec<u16> = engine
+            .get_accounts_ordered(SortOrder::TotalDesc)
+            .iter()
+            .map(|a| a.client)
+            .collect();
+        assert_eq!(by_total, vec![2, 4, 1, 3]);
+
+        let by_available: Vec<u16> = engine
+            .get_accounts_ordered(SortOrder::AvailableDesc)
+            .iter()
+            .map(|a| a.client)
+            .collect();
+        assert_eq!(by_available, vec![4, 1, 3, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_unsorted_accounts_contain_same_clients() {
+        let engine = PaymentsEngine::new();
+        for client in (1u16..=50).rev() {
+            engine.process(Deposit {
+                client,
+                tx: client as u32,
+                amount: dec!(1.0),
+            }).await;
+        }
+
+        let sorted = engine.get_accounts_ordered(SortOrder::ClientId);
+        let mut unsorted: Vec<u16> = engine
+            .get_accounts_ordered(SortOrder::Unsorted)
+            .iter()
+            .map(|a| a.client)
+            .collect();
+        unsorted.sort();
+
+        let sorted: Vec<u16> = sorted.iter().map(|a| a.client).collect();
+        assert_eq!(sorted, (1u16..=50).collect::<Vec<_>>());
+        assert_eq!(unsorted, sorted);
+    }
+
+    #[tokio::test]
+    async fn test_export_transactions_round_trip() {
+        use crate::config::ProcessorConfig;
+        use crate::services::trx_processor::process_csv_str;
+
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(100.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(30.0),
+        }).await;
+        engine.process(Deposit {
+            client: 2,
+            tx: 3,
+            amount: dec!(50.0),
+        }).await;
+        engine.process(Deposit {
+            client: 2,
+            tx: 4,
+            amount: dec!(20.0),
+        }).await;
+        engine.process(Deposit {
+            client: 3,
+            tx: 5,
+            amount: dec!(7.5),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 2,
+            tx: 3,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 2,
+            tx: 3,
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 3,
+            tx: 5,
+        }).await;
+        engine.process(Trx::Resolve {
+            client: 3,
+            tx: 5,
+        }).await;
+
+        let mut buffer = Vec::new();
+        engine.export_transactions(&mut buffer).await.unwrap();
+        let export = String::from_utf8(buffer).unwrap();
+        assert!(export.starts_with("type,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,30.0\n"));
+
+        let replayed = PaymentsEngine::new();
+        let summary = process_csv_str(&replayed, &export, &ProcessorConfig::new()).await.unwrap();
+        assert_eq!(summary.total_rejected(), 0);
+        assert_eq!(replayed.get_accounts(), engine.get_accounts());
+
+        let original = engine.tx_history.lock().await;
+        let rebuilt = replayed.tx_history.lock().await;
+        for (tx, record) in original.records().await {
+            let rebuilt_record = rebuilt.get(tx).await.unwrap();
+            assert_eq!(rebuilt_record.status, record.status);
+            assert_eq!(rebuilt_record.kind, record.kind);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_held_breakdown_sums_to_held() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(2.5),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 3,
+            amount: dec!(4.0),
+        }).await;
+        engine.process(Deposit {
+            client: 2,
+            tx: 4,
+            amount: dec!(8.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 3,
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 2,
+            tx: 4,
+        }).await;
+
+        let breakdown = engine.held_breakdown(1).await;
+        assert_eq!(breakdown, vec![(1, dec!(10.0)), (3, dec!(4.0))]);
+
+        let held: Decimal = breakdown.iter().map(|(_, amount)| amount).sum();
+        let accounts = engine.get_accounts();
+        assert_eq!(held, accounts[0].held);
+        assert!(engine.held_breakdown(3).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_all_disputed_spans_clients() {
+        let engine = PaymentsEngine::new();
+        let deposits = [
+            (1, 1, dec!(10.0)),
+            (2, 2, dec!(8.0)),
+            (1, 3, dec!(4.0)),
+            (2, 4, dec!(1.5)),
+            (1, 5, dec!(3.0)),
+        ];
+        for (client, tx, amount) in deposits {
+            engine.process(Deposit { client, tx, amount }).await;
+        }
+        for (client, tx) in [(2, 4), (1, 1), (2, 2), (1, 3), (1, 5)] {
+            engine.process(Trx::Dispute { client, tx }).await;
+        }
+        engine.process(Trx::Resolve { client: 1, tx: 3 }).await;
+        engine.process(Trx::Chargeback { client: 2, tx: 2 }).await;
+
+        assert_eq!(engine.all_disputed().await, vec![
+            (1, 1, dec!(10.0)),
+            (4, 2, dec!(1.5)),
+            (5, 1, dec!(3.0)),
+        ]);
+        assert!(PaymentsEngine::new().all_disputed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recompute_total_repairs_drift() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        assert!(!engine.recompute_total(1));
+
+        engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap().total += dec!(0.0001);
+        assert!(!engine.get_accounts()[0].verify_totals());
+
+        assert!(engine.recompute_total(1));
+        let accounts = engine.get_accounts();
+        assert!(accounts[0].verify_totals());
+        assert_eq!(accounts[0].total, dec!(10.0));
+        assert!(!engine.recompute_total(2));
+    }
+
+    #[tokio::test]
+    async fn test_verify_invariants_mode_repairs_after_mutation() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_verify_invariants(true));
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap().total = dec!(99.0);
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert!(accounts[0].verify_totals());
+        assert_eq!(accounts[0].total, dec!(15.0));
+    }
+
+    #[tokio::test]
+    async fn test_abort_on_invariant_violation_rejects_instead_of_repairing() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new()
+                .with_abort_on_invariant_violation(true)
+                .with_verify_invariants(true),
+        );
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap().total = dec!(99.0);
+
+        let result = engine.try_process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::InvariantViolated));
+        assert!(!engine.get_accounts()[0].verify_totals());
+    }
+
+    #[tokio::test]
+    async fn test_invariant_violation_tolerated_by_default() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap().total = dec!(99.0);
+
+        let result = engine.try_process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+        assert_eq!(result, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_fee_can_push_available_negative() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(2.0),
+        }).await;
+
+        let result = engine.try_process(Trx::Fee { client: 1, tx: 2, amount: dec!(5.0) }).await;
+        assert_eq!(result, Ok(()));
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-3.0));
+        assert_eq!(accounts[0].total, dec!(-3.0));
+        assert!(accounts[0].verify_totals());
+
+        let tx_history = engine.tx_history.lock().await;
+        assert_eq!(tx_history.get(2).await.unwrap().kind, TxKind::Fee);
+        assert_eq!(tx_history.get(2).await.unwrap().amount, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_fee_invariant_holds_with_open_dispute() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Fee {
+            client: 1,
+            tx: 2,
+            amount: dec!(1.25),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(-1.25));
+        assert_eq!(accounts[0].held, dec!(10.0));
+        assert!(accounts[0].verify_totals());
+
+        // Fee IDs share the global dedup space
+        let result = engine.try_process(Trx::Fee { client: 1, tx: 1, amount: dec!(1.0) }).await;
+        assert_eq!(result, Err(Rejection::DuplicateTx));
+    }
+
+    #[tokio::test]
+    async fn test_merge_disjoint_engines() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 2,
+            tx: 2,
+            amount: dec!(4.0),
+        }).await;
+
+        let other = PaymentsEngine::new();
+        other.process(Deposit {
+            client: 1,
+            tx: 10,
+            amount: dec!(5.0),
+        }).await;
+        other.process(Trx::Dispute {
+            client: 1,
+            tx: 10,
+        }).await;
+        other.process(Deposit {
+            client: 3,
+            tx: 11,
+            amount: dec!(1.0),
+        }).await;
+
+        engine.merge(other).await.unwrap();
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert_eq!(accounts[0].held, dec!(5.0));
+        assert_eq!(accounts[0].total, dec!(15.0));
+        assert_eq!(accounts[2].total, dec!(1.0));
+
+        // Merged history is usable for later disputes
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 10,
+        }).await;
+        assert_eq!(engine.get_accounts()[0].available, dec!(15.0));
+        assert_eq!(engine.tx_history.lock().await.len().await, 4);
+    }
+
+    #[tokio::test]
+    async fn test_clear_empties_engine_for_reuse() {
+        let engine = PaymentsEngine::with_max_history(Some(2));
+        for tx in 1..=3 {
+            engine.process(Deposit {
+                client: tx as u16,
+                tx,
+                amount: dec!(1.0),
+            }).await;
+        }
+        engine.process(Trx::Dispute {
+            client: 3,
+            tx: 3,
+        }).await;
+
+        assert_eq!(engine.clear().await.unwrap(), (3, 2));
+        assert!(engine.get_accounts().is_empty());
+        let stats = engine.stats().await;
+        assert_eq!((stats.tx_history, stats.evicted), (0, 0));
+        assert_eq!(engine.tx_status(3).await, None);
+
+        // Tx IDs from before the clear are free again
+        let result = engine.try_process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(2.5),
+        }).await;
+        assert_eq!(result, Ok(()));
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(2.5));
+        assert_eq!(engine.clear().await.unwrap(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_merge_colliding_engines_rejected() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(10.0),
+        }).await;
+
+        let other = PaymentsEngine::new();
+        other.process(Deposit {
+            client: 2,
+            tx: 2,
+            amount: dec!(3.0),
+        }).await;
+        other.process(Deposit {
+            client: 2,
+            tx: 3,
+            amount: dec!(3.0),
+        }).await;
+
+        let result = engine.merge(other).await;
+        assert!(matches!(result, Err(PaymentError::TxIdCollision(ref ids)) if ids == &vec![2]));
+
+        // Nothing from the rejected engine was applied
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].total, dec!(20.0));
+        assert!(!engine.tx_history.lock().await.contains(3).await);
+    }
+
+    #[tokio::test]
+    async fn test_merge_overflow_leaves_engine_unchanged() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Decimal::MAX,
+        }).await;
+
+        let other = PaymentsEngine::new();
+        for client in 2..10 {
+            other.process(Deposit {
+                client,
+                tx: client as u32,
+                amount: dec!(1.0),
+            }).await;
+        }
+        other.process(Deposit {
+            client: 1,
+            tx: 10,
+            amount: Decimal::MAX,
+        }).await;
+
+        let result = engine.merge(other).await;
+        assert!(matches!(result, Err(PaymentError::MergeOverflow(1))));
+
+        // No other client's balance was merged before the overflow was found
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].total, Decimal::MAX);
+        assert!(!engine.tx_history.lock().await.contains(2).await);
+    }
+
+    #[tokio::test]
+    async fn test_client_tx_cap_rejects_only_offending_client() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_max_tx_per_client(Some(2)));
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(1.0),
+        }).await;
+        let result = engine.try_process(Deposit { client: 1, tx: 3, amount: dec!(10.0) }).await;
+        assert_eq!(result, Err(Rejection::ClientTxLimit));
+        let result = engine.try_process(Trx::Withdrawal { client: 1, tx: 4, amount: dec!(1.0) }).await;
+        assert_eq!(result, Err(Rejection::ClientTxLimit));
+
+        // Disputes are not capped, and other clients are unaffected
+        assert_eq!(engine.try_process(Trx::Dispute { client: 1, tx: 1 }).await, Ok(()));
+        engine.process(Deposit {
+            client: 2,
+            tx: 5,
+            amount: dec!(3.0),
+        }).await;
+        engine.process(Deposit {
+            client: 2,
+            tx: 6,
+            amount: dec!(3.0),
+        }).await;
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].total, dec!(9.0));
+        assert_eq!(accounts[0].tx_count, 2);
+        assert_eq!(accounts[1].total, dec!(6.0));
+        assert!(!engine.tx_history.lock().await.contains(3).await);
+    }
+
+    #[tokio::test]
+    async fn test_client_tx_cap_covers_fees_and_creates_no_account() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_max_tx_per_client(Some(1)));
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        let result = engine.try_process(Trx::Fee {
+            client: 1,
+            tx: 2,
+            amount: dec!(1.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::ClientTxLimit));
+        assert_eq!(engine.get_accounts()[0].total, dec!(10.0));
+
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_max_tx_per_client(Some(0)));
+        let result = engine.try_process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::ClientTxLimit));
+        assert!(engine.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_summary() {
+        let engine = PaymentsEngine::new();
+        assert_eq!(engine.aggregate_summary(), Summary::default());
+
+        engine.process(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.5),
+        }).await;
+        engine.process(Deposit {
+            client: 2,
+            tx: 2,
+            amount: dec!(20.0),
+        }).await;
+        engine.process(Deposit {
+            client: 3,
+            tx: 3,
+            amount: dec!(5.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 2,
+            tx: 2,
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 3,
+            tx: 3,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 3,
+            tx: 3,
+        }).await;
+
+        let summary = engine.aggregate_summary();
+        assert_eq!(summary.accounts, 3);
+        assert_eq!(summary.locked_accounts, 1);
+        assert_eq!(summary.available, Some(dec!(10.5)));
+        assert_eq!(summary.held, Some(dec!(20.0)));
+        assert_eq!(summary.total, Some(dec!(30.5)));
+    }
+
+    #[test]
+    fn test_aggregate_summary_overflow() {
+        let engine = PaymentsEngine::new();
+        for client in 1..=2 {
+            let mut account = engine.get_or_create_account(client);
+            account.available = Decimal::MAX;
+            account.total = Decimal::MAX;
+        }
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].client, 1);
+        let summary = engine.aggregate_summary();
+        assert_eq!(summary.accounts, 2);
+        assert_eq!(summary.available, None);
+        assert_eq!(summary.total, None);
+        assert_eq!(summary.held, Some(Decimal::ZERO));
     }
 
     #[tokio::test]
-    async fn test_totals_invariant_maintained() {
+    async fn test_dispute_rejection_categories() {
         let engine = PaymentsEngine::new();
         engine.process(Deposit {
             client: 1,
@@ -392,228 +3517,418 @@ mod tests {
             amount: dec!(10.0),
         }).await;
 
-        let accounts = engine.get_accounts();
-        assert!(accounts[0].verify_totals());
+        // Unknown tx
+        let result = engine.try_process(Trx::Dispute {
+            client: 1,
+            tx: 99,
+        }).await;
+        assert_eq!(result, Err(Rejection::TxNotFound));
+
+        // Tx exists but belongs to another client (who has no account either)
+        let result = engine.try_process(Trx::Dispute {
+            client: 2,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::ClientMismatch));
+
+        // Tx record whose client has no account
+        engine.tx_history.lock().await.insert(2, TxRecord {
+            kind: TxKind::Deposit,
+            client: 3,
+            currency: Currency::DEFAULT,
+            amount: dec!(5.0),
+            status: TrxStatus::Normal,
+            insertion_ordinal: 0,
+            resolved: Decimal::ZERO,
+            timestamp: None,
+        }).await;
+        let result = engine.try_process(Trx::Dispute {
+            client: 3,
+            tx: 2,
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountMissing));
+        assert_eq!(engine.tx_history.lock().await.get(2).await.unwrap().status, TrxStatus::Normal);
+        assert_eq!(engine.get_accounts().len(), 1);
     }
 
     #[tokio::test]
-    async fn test_deposit_increases_available() {
+    async fn test_resolve_and_chargeback_account_missing() {
         let engine = PaymentsEngine::new();
-        engine.process(Deposit {
-            client: 1,
-            tx: 1,
-            amount: dec!(10.5),
+        engine.tx_history.lock().await.insert(1, TxRecord {
+            kind: TxKind::Deposit,
+            client: 3,
+            currency: Currency::DEFAULT,
+            amount: dec!(5.0),
+            status: TrxStatus::UnderDispute,
+            insertion_ordinal: 0,
+            resolved: Decimal::ZERO,
+            timestamp: None,
         }).await;
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(10.5));
-        assert_eq!(accounts[0].total, dec!(10.5));
+        let result = engine.try_process(Trx::Resolve {
+            client: 3,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountMissing));
+        let result = engine.try_process(Trx::Chargeback {
+            client: 3,
+            tx: 1,
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountMissing));
+        assert_eq!(engine.tx_history.lock().await.get(1).await.unwrap().status, TrxStatus::UnderDispute);
     }
 
     #[tokio::test]
-    async fn test_withdrawal_decreases_when_possible() {
+    async fn test_dispute_on_locked_account_allowed_by_default() {
         let engine = PaymentsEngine::new();
         engine.process(Deposit {
             client: 1,
             tx: 1,
             amount: dec!(10.0),
         }).await;
-        engine.process(Trx::Withdrawal {
+        engine.process(Deposit {
             client: 1,
             tx: 2,
             amount: dec!(5.0),
         }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
+        }).await;
 
+        let result = engine.try_process(Trx::Dispute {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(result, Ok(()));
         let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(5.0));
-        assert_eq!(accounts[0].total, dec!(5.0));
+        assert_eq!(accounts[0].available, dec!(0.0));
+        assert_eq!(accounts[0].held, dec!(5.0));
     }
 
     #[tokio::test]
-    async fn test_insufficient_withdrawal_ignored() {
-        let engine = PaymentsEngine::new();
+    async fn test_freeze_on_lock_rejects_new_disputes() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_freeze_on_lock(true));
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(5.0),
+            amount: dec!(10.0),
         }).await;
-        engine.process(Trx::Withdrawal {
+        engine.process(Deposit {
             client: 1,
             tx: 2,
-            amount: dec!(10.0),
+            amount: dec!(5.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
         }).await;
 
+        let result = engine.try_process(Trx::Dispute {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountLocked));
+        assert_eq!(engine.tx_history.lock().await.get(2).await.unwrap().status, TrxStatus::Normal);
         let accounts = engine.get_accounts();
-        // Should still have original deposit
         assert_eq!(accounts[0].available, dec!(5.0));
-        assert_eq!(accounts[0].total, dec!(5.0));
+        assert_eq!(accounts[0].held, dec!(0.0));
     }
 
     #[tokio::test]
-    async fn test_tx_indexing() {
-        let engine = PaymentsEngine::new();
+    async fn test_denied_client_ignored() {
+        use std::collections::HashSet;
+
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new().with_denied_clients(HashSet::from([2]))
+        );
         engine.process(Deposit {
             client: 1,
-            tx: 100,
+            tx: 1,
             amount: dec!(10.0),
         }).await;
+        let result = engine.try_process(Deposit {
+            client: 2,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::ClientNotAllowed));
 
-        // Check transaction was stored
-        let tx_history = engine.tx_history.lock().await;
-        assert!(tx_history.contains_key(&100));
-        let tx_record = &tx_history[&100];
-        assert_eq!(tx_record.client, 1);
-        assert_eq!(tx_record.amount, dec!(10.0));
-        assert_eq!(tx_record.status, TrxStatus::Normal);
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].client, 1);
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert!(!engine.tx_history.lock().await.contains(2).await);
     }
 
     #[tokio::test]
-    async fn test_dispute_resolve_roundtrip() {
-        let engine = PaymentsEngine::new();
+    async fn test_client_range_drops_out_of_range_clients() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new().with_min_client(Some(1000)).with_max_client(Some(1999))
+        );
+        for (client, tx) in [(1000, 1), (999, 2), (1999, 3), (2000, 4)] {
+            engine.process(Deposit {
+                client,
+                tx,
+                amount: dec!(5.0),
+            }).await;
+        }
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 42,
+            tx: 5,
+            amount: dec!(1.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::ClientOutOfRange));
 
-        // Make a deposit
+        let clients: Vec<u16> = engine.get_accounts_ordered(SortOrder::ClientId).iter().map(|a| a.client).collect();
+        assert_eq!(clients, vec![1000, 1999]);
+        assert!(!engine.tx_history.lock().await.contains(2).await);
+    }
+
+    #[tokio::test]
+    async fn test_allow_list_restricts_clients() {
+        use std::collections::HashSet;
+
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new().with_allowed_clients(Some(HashSet::from([1, 3])))
+        );
         engine.process(Deposit {
             client: 1,
             tx: 1,
             amount: dec!(10.0),
         }).await;
-
-        // Dispute it
-        engine.process(Trx::Dispute {
-            client: 1,
-            tx: 1,
+        engine.process(Deposit {
+            client: 2,
+            tx: 2,
+            amount: dec!(5.0),
+        }).await;
+        engine.process(Deposit {
+            client: 3,
+            tx: 3,
+            amount: dec!(7.0),
+        }).await;
+        let result = engine.try_process(Trx::Dispute {
+            client: 2,
+            tx: 2,
         }).await;
+        assert_eq!(result, Err(Rejection::ClientNotAllowed));
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(0.0));
-        assert_eq!(accounts[0].held, dec!(10.0));
-        assert_eq!(accounts[0].total, dec!(10.0));
+        let clients: Vec<u16> = engine.get_accounts().iter().map(|a| a.client).collect();
+        assert_eq!(clients, vec![1, 3]);
+    }
 
-        // Resolve it
-        engine.process(Trx::Resolve {
-            client: 1,
-            tx: 1,
-        }).await;
+    #[tokio::test]
+    async fn test_accounts_iter_matches_get_accounts() {
+        let engine = PaymentsEngine::new();
+        for client in [5, 1, 3] {
+            engine.process(Deposit {
+                client,
+                tx: client as u32,
+                amount: dec!(2.0),
+            }).await;
+        }
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(10.0));
-        assert_eq!(accounts[0].held, dec!(0.0));
-        assert_eq!(accounts[0].total, dec!(10.0));
+        let mut iterated: Vec<UserAccount> = engine.accounts_iter().map(|entry| entry.value().clone()).collect();
+        iterated.sort_by_key(|a| a.client);
+        assert_eq!(iterated, engine.get_accounts());
+
+        let mut clients = Vec::new();
+        engine.for_each_account(|account| clients.push(account.client));
+        clients.sort();
+        assert_eq!(clients, vec![1, 3, 5]);
     }
 
     #[tokio::test]
-    async fn test_dispute_chargeback_locks_account() {
+    async fn test_currencies_are_separate_accounts() {
         let engine = PaymentsEngine::new();
+        let usd: Currency = "USD".parse().unwrap();
+        let eur: Currency = "EUR".parse().unwrap();
 
-        // Make a deposit
-        engine.process(Deposit {
+        engine.try_process_in(Trx::Deposit {
             client: 1,
             tx: 1,
+            amount: dec!(10.0),
+        }, usd).await.unwrap();
+        engine.try_process_in(Trx::Deposit {
+            client: 1,
+            tx: 2,
             amount: dec!(5.0),
-        }).await;
+        }, eur).await.unwrap();
 
-        // Dispute it
+        // Withdrawal in EUR can't draw on the USD balance
+        let result = engine.try_process_in(Trx::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: dec!(6.0),
+        }, eur).await;
+        assert_eq!(result, Err(Rejection::InsufficientFunds));
+
+        // Dispute and chargeback follow the referenced deposit's currency
+        // and only lock that account
         engine.process(Trx::Dispute {
             client: 1,
             tx: 1,
         }).await;
-
-        // Chargeback
         engine.process(Trx::Chargeback {
             client: 1,
             tx: 1,
         }).await;
 
         let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(0.0));
-        assert_eq!(accounts[0].held, dec!(0.0));
-        assert_eq!(accounts[0].total, dec!(0.0));
-        assert!(accounts[0].locked);
+        assert_eq!(accounts.len(), 2);
+        let (eur_account, usd_account) = (&accounts[0], &accounts[1]);
+        assert_eq!(eur_account.currency, eur);
+        assert_eq!(eur_account.available, dec!(5.0));
+        assert!(!eur_account.locked);
+        assert_eq!(usd_account.currency, usd);
+        assert_eq!(usd_account.total, dec!(0.0));
+        assert!(usd_account.locked);
     }
 
     #[tokio::test]
-    async fn test_invalid_dispute_wrong_client() {
-        let engine = PaymentsEngine::new();
-
-        // Client 1 makes deposit
+    async fn test_unlock_after_chargeback() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_allow_unlock(true));
         engine.process(Deposit {
             client: 1,
             tx: 1,
             amount: dec!(10.0),
         }).await;
-
-        // Client 2 tries to dispute it - should be ignored
         engine.process(Trx::Dispute {
-            client: 2,
+            client: 1,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
             tx: 1,
         }).await;
+        assert!(engine.get_accounts()[0].locked);
 
-        // Client 1's funds should be unchanged
-        let accounts = engine.get_accounts();
-        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(client1.available, dec!(10.0));
-        assert_eq!(client1.held, dec!(0.0));
+        let result = engine.try_process(Trx::Unlock {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(result, Ok(()));
+        assert!(!engine.get_accounts()[0].locked);
+
+        // Unlocking again has nothing to do
+        let result = engine.try_process(Trx::Unlock {
+            client: 1,
+            tx: 3,
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountNotLocked));
     }
 
     #[tokio::test]
-    async fn test_resolve_without_dispute_ignored() {
-        let engine = PaymentsEngine::new();
-
-        engine.process(Deposit {
+    async fn test_unlock_rejected_with_open_disputes() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_allow_unlock(true));
+        for tx in 1..=2 {
+            engine.process(Deposit {
+                client: 1,
+                tx,
+                amount: dec!(5.0),
+            }).await;
+            engine.process(Trx::Dispute {
+                client: 1,
+                tx,
+            }).await;
+        }
+        engine.process(Trx::Chargeback {
             client: 1,
             tx: 1,
-            amount: dec!(10.0),
         }).await;
 
-        // Try to resolve without disputing first
-        engine.process(Trx::Resolve {
+        let result = engine.try_process(Trx::Unlock {
             client: 1,
-            tx: 1,
+            tx: 3,
         }).await;
+        assert_eq!(result, Err(Rejection::OpenDisputes));
+        assert!(engine.get_accounts()[0].locked);
 
-        // Should still be in normal state
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(10.0));
-        assert_eq!(accounts[0].held, dec!(0.0));
+        // Resolves are refused on a locked account, so close it with a chargeback
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 2,
+        }).await;
+        let result = engine.try_process(Trx::Unlock {
+            client: 1,
+            tx: 4,
+        }).await;
+        assert_eq!(result, Ok(()));
     }
 
     #[tokio::test]
-    async fn test_chargeback_after_chargeback_ignored() {
+    async fn test_unlock_disabled_by_default() {
         let engine = PaymentsEngine::new();
-
         engine.process(Deposit {
             client: 1,
             tx: 1,
             amount: dec!(10.0),
         }).await;
-
         engine.process(Trx::Dispute {
             client: 1,
             tx: 1,
         }).await;
-
         engine.process(Trx::Chargeback {
             client: 1,
             tx: 1,
         }).await;
 
-        // Try to dispute again - should be ignored
-        engine.process(Trx::Dispute {
+        let result = engine.try_process(Trx::Unlock {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(result, Err(Rejection::UnlockDisabled));
+        assert!(engine.get_accounts()[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_freeze_blocks_withdrawal_until_thaw() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
             client: 1,
             tx: 1,
+            amount: dec!(10.0),
         }).await;
+        assert_eq!(engine.try_process(Trx::Freeze { client: 1, tx: 2 }).await, Ok(()));
+        assert!(engine.get_accounts()[0].frozen);
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(0.0));
-        assert_eq!(accounts[0].held, dec!(0.0));
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: dec!(4.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountFrozen));
+        let result = engine.try_process(Deposit {
+            client: 1,
+            tx: 4,
+            amount: dec!(1.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::AccountFrozen));
+        assert_eq!(engine.get_accounts()[0].available, dec!(10.0));
+
+        assert_eq!(engine.try_process(Trx::Thaw { client: 1, tx: 5 }).await, Ok(()));
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: dec!(4.0),
+        }).await;
+        assert_eq!(result, Ok(()));
+        let account = &engine.get_accounts()[0];
+        assert!(!account.frozen);
+        assert_eq!(account.available, dec!(6.0));
     }
 
     #[tokio::test]
-    async fn test_lru_pruning_limits_memory() {
-        // Create engine with max history of 2 transactions
-        let engine = PaymentsEngine::with_max_history(Some(2));
-
-        // Process 3 deposits
+    async fn test_frozen_account_resolves_disputes() {
+        let engine = PaymentsEngine::new();
         engine.process(Deposit {
             client: 1,
             tx: 1,
@@ -622,527 +3937,1053 @@ mod tests {
         engine.process(Deposit {
             client: 1,
             tx: 2,
-            amount: dec!(20.0),
+            amount: dec!(5.0),
         }).await;
+        engine.process(Trx::Dispute { client: 1, tx: 1 }).await;
+        engine.process(Trx::Dispute { client: 1, tx: 2 }).await;
+        engine.process(Trx::Freeze { client: 1, tx: 3 }).await;
+
+        assert_eq!(engine.try_process(Trx::Resolve { client: 1, tx: 1 }).await, Ok(()));
+        assert_eq!(engine.get_accounts()[0].available, dec!(10.0));
+
+        // A chargeback while frozen locks the account; thawing leaves that lock
+        assert_eq!(engine.try_process(Trx::Chargeback { client: 1, tx: 2 }).await, Ok(()));
+        assert_eq!(engine.try_process(Trx::Thaw { client: 1, tx: 4 }).await, Ok(()));
+        let account = &engine.get_accounts()[0];
+        assert!(account.locked);
+        assert!(!account.frozen);
+    }
+
+    #[tokio::test]
+    async fn test_freeze_and_thaw_rejections() {
+        let engine = PaymentsEngine::new();
+        assert_eq!(engine.try_process(Trx::Freeze { client: 1, tx: 1 }).await, Err(Rejection::AccountMissing));
+
         engine.process(Deposit {
             client: 1,
+            tx: 2,
+            amount: dec!(10.0),
+        }).await;
+        assert_eq!(engine.try_process(Trx::Thaw { client: 1, tx: 3 }).await, Err(Rejection::AccountNotFrozen));
+
+        // A chargeback lock is not a freeze, so thaw leaves it in place
+        engine.process(Trx::Dispute { client: 1, tx: 2 }).await;
+        engine.process(Trx::Chargeback { client: 1, tx: 2 }).await;
+        assert_eq!(engine.try_process(Trx::Thaw { client: 1, tx: 3 }).await, Err(Rejection::AccountNotFrozen));
+        assert!(engine.get_accounts()[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_freeze_covers_every_currency_and_fees() {
+        let engine = PaymentsEngine::new();
+        let usd: Currency = "USD".parse().unwrap();
+        let eur: Currency = "EUR".parse().unwrap();
+        engine.try_process_in(Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }, usd).await.unwrap();
+        engine.try_process_in(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(5.0),
+        }, eur).await.unwrap();
+        engine.process(Deposit {
+            client: 2,
             tx: 3,
-            amount: dec!(30.0),
+            amount: dec!(1.0),
         }).await;
 
-        // Verify only 2 transactions are stored (oldest was evicted)
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history.len(), 2);
-        assert!(!tx_history.contains_key(&1)); // tx 1 should be evicted
-        assert!(tx_history.contains_key(&2));
-        assert!(tx_history.contains_key(&3));
-
-        // Account should still have all deposits
+        assert_eq!(engine.try_process(Trx::Freeze { client: 1, tx: 4 }).await, Ok(()));
         let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].total, dec!(60.0));
+        assert!(accounts.iter().filter(|account| account.client == 1).all(|account| account.frozen));
+        assert!(!accounts.iter().any(|account| account.client == 2 && account.frozen));
+
+        let result = engine.try_process_in(Trx::Withdrawal {
+            client: 1,
+            tx: 5,
+            amount: dec!(1.0),
+        }, eur).await;
+        assert_eq!(result, Err(Rejection::AccountFrozen));
+        let result = engine.try_process_in(Trx::Fee {
+            client: 1,
+            tx: 6,
+            amount: dec!(1.0),
+        }, usd).await;
+        assert_eq!(result, Err(Rejection::AccountFrozen));
+        assert!(engine.get_accounts().iter().all(|account| account.tx_count == 1));
+
+        assert_eq!(engine.try_process(Trx::Thaw { client: 1, tx: 7 }).await, Ok(()));
+        assert!(engine.get_accounts().iter().all(|account| !account.frozen));
+        let result = engine.try_process_in(Trx::Fee {
+            client: 1,
+            tx: 6,
+            amount: dec!(1.0),
+        }, usd).await;
+        assert_eq!(result, Ok(()));
     }
 
     #[tokio::test]
-    async fn test_dispute_fails_on_pruned_transaction() {
-        let engine = PaymentsEngine::with_max_history(Some(1));
+    async fn test_idempotent_deposit_retry_is_noop() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_idempotent_deposits(true));
+        let deposit = Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        };
+        assert_eq!(engine.try_process(deposit.clone()).await, Ok(()));
+        assert_eq!(engine.try_process(deposit).await, Ok(()));
 
-        // Process 2 deposits (first will be pruned)
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert_eq!(accounts[0].tx_count, 1);
+        assert_eq!(engine.applied_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_deposit_conflicting_retry() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_idempotent_deposits(true));
         engine.process(Deposit {
             client: 1,
             tx: 1,
             amount: dec!(10.0),
         }).await;
-        engine.process(Deposit {
+        engine.process(Trx::Withdrawal {
             client: 1,
             tx: 2,
-            amount: dec!(20.0),
+            amount: dec!(3.0),
         }).await;
 
-        // Try to dispute the pruned transaction - should be ignored
-        engine.process(Trx::Dispute {
+        let result = engine.try_process(Deposit {
             client: 1,
             tx: 1,
+            amount: dec!(10.5),
+        }).await;
+        assert_eq!(result, Err(Rejection::ConflictingDuplicate));
+        let result = engine.try_process(Deposit {
+            client: 2,
+            tx: 1,
+            amount: dec!(10.0),
         }).await;
+        assert_eq!(result, Err(Rejection::ConflictingDuplicate));
+        // A deposit reusing a withdrawal's ID is never a retry
+        let result = engine.try_process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(3.0),
+        }).await;
+        assert_eq!(result, Err(Rejection::ConflictingDuplicate));
 
-        // Account should be unchanged (no funds held)
         let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(30.0));
-        assert_eq!(accounts[0].held, dec!(0.0));
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(7.0));
     }
 
     #[tokio::test]
-    async fn test_negative_balance_allowed_on_dispute() {
-        // Test that disputes can create negative balances (business rule)
-        let engine = PaymentsEngine::new();
+    async fn test_eviction_removes_lowest_insertion_ordinal() {
+        let engine = PaymentsEngine::with_max_history(Some(3));
+        for tx in [10, 20, 30] {
+            engine.process(Deposit {
+                client: 1,
+                tx,
+                amount: dec!(1.0),
+            }).await;
+        }
+        let ordinals = [
+            engine.insertion_ordinal(10).await.unwrap(),
+            engine.insertion_ordinal(20).await.unwrap(),
+            engine.insertion_ordinal(30).await.unwrap(),
+        ];
+        assert!(ordinals.windows(2).all(|pair| pair[0] < pair[1]));
 
-        // Deposit 100
         engine.process(Deposit {
             client: 1,
-            tx: 1,
-            amount: dec!(100.0),
+            tx: 40,
+            amount: dec!(1.0),
         }).await;
+        assert_eq!(engine.insertion_ordinal(10).await, None);
+        assert!(engine.insertion_ordinal(40).await.unwrap() > ordinals[2]);
 
-        // Withdraw 80
-        engine.process(Trx::Withdrawal {
+        // Eviction follows ordinals, not map position
+        {
+            let mut tx_history = engine.tx_history.lock().await;
+            let mut record = tx_history.get(30).await.unwrap();
+            record.insertion_ordinal = 0;
+            tx_history.insert(30, record).await;
+        }
+        engine.process(Deposit {
             client: 1,
-            tx: 2,
-            amount: dec!(80.0),
+            tx: 50,
+            amount: dec!(1.0),
         }).await;
+        assert_eq!(engine.insertion_ordinal(30).await, None);
+        assert!(engine.insertion_ordinal(20).await.is_some());
+    }
 
-        // Now available = 20, total = 20
-
-        // Dispute the original deposit of 100
+    #[tokio::test]
+    async fn test_boundary_client_and_tx_ids() {
+        let engine = PaymentsEngine::new();
+        engine.process(Deposit {
+            client: u16::MAX,
+            tx: u32::MAX,
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 0,
+            tx: 0,
+            amount: dec!(1.0),
+        }).await;
         engine.process(Trx::Dispute {
-            client: 1,
-            tx: 1,
+            client: u16::MAX,
+            tx: u32::MAX,
         }).await;
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[1].client, u16::MAX);
+        assert_eq!(accounts[1].held, dec!(10.0));
 
-        // This creates negative available balance
+        engine.process(Trx::Chargeback {
+            client: u16::MAX,
+            tx: u32::MAX,
+        }).await;
         let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(-80.0)); // 20 - 100 = -80
-        assert_eq!(accounts[0].held, dec!(100.0));
-        assert_eq!(accounts[0].total, dec!(20.0)); // -80 + 100 = 20
-        assert!(!accounts[0].locked);
+        assert_eq!(accounts[0].client, 0);
+        assert_eq!(accounts[1].total, dec!(0.0));
+        assert!(accounts[1].locked);
+
+        engine.lock_two_accounts(0, u16::MAX, |low, high| {
+            assert_eq!((low.client, high.client), (0, u16::MAX));
+        });
+
+        let mut buffer = Vec::new();
+        engine.export_transactions(&mut buffer).await.unwrap();
+        let export = String::from_utf8(buffer).unwrap();
+        assert!(export.contains("deposit,65535,4294967295,10.0\n"));
+        assert!(export.contains("chargeback,65535,4294967295,"));
     }
 
     #[tokio::test]
-    async fn test_negative_balance_withdrawal_dispute_scenario() {
+    async fn test_tx_status_follows_dispute_lifecycle() {
         let engine = PaymentsEngine::new();
+        assert_eq!(engine.tx_status(1).await, None);
 
-        // Deposit 50
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(50.0),
+            amount: dec!(10.0),
         }).await;
+        assert_eq!(engine.tx_status(1).await, Some(TrxStatus::Normal));
 
-        // Withdraw 40
-        engine.process(Trx::Withdrawal {
+        engine.process(Trx::Dispute {
             client: 1,
-            tx: 2,
-            amount: dec!(40.0),
+            tx: 1,
         }).await;
+        assert_eq!(engine.tx_status(1).await, Some(TrxStatus::UnderDispute));
+
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(engine.tx_status(1).await, Some(TrxStatus::Resolved));
 
-        // Dispute the withdrawal (holds 40 from available 10)
         engine.process(Trx::Dispute {
             client: 1,
-            tx: 2,
+            tx: 1,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 1,
         }).await;
+        assert_eq!(engine.tx_status(1).await, Some(TrxStatus::ChargedBack));
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(-30.0)); // 10 - 40 = -30
-        assert_eq!(accounts[0].held, dec!(40.0));
-        assert_eq!(accounts[0].total, dec!(10.0));
+        // Disputes reference deposits; they don't get a status of their own
+        assert_eq!(engine.tx_status(2).await, None);
     }
 
-    #[tokio::test]
-    async fn test_negative_balance_resolve_returns_to_positive() {
-        let engine = PaymentsEngine::new();
-
-        // Create negative balance scenario
+    async fn withdraw_during_dispute(config: ProcessorConfig) -> (PaymentsEngine, Result<(), Rejection>) {
+        let engine = PaymentsEngine::with_config(config);
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(100.0),
+            amount: dec!(10.0),
         }).await;
-        engine.process(Trx::Withdrawal {
+        engine.process(Deposit {
             client: 1,
             tx: 2,
-            amount: dec!(90.0),
+            amount: dec!(5.0),
         }).await;
         engine.process(Trx::Dispute {
             client: 1,
             tx: 1,
         }).await;
 
-        // Available is now -90 (10 - 100)
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: dec!(2.0),
+        }).await;
+        (engine, result)
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_during_dispute_allowed_by_default() {
+        let (engine, result) = withdraw_during_dispute(ProcessorConfig::new()).await;
+        assert_eq!(result, Ok(()));
+
         let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(-90.0));
+        assert_eq!(accounts[0].available, dec!(3.0));
+        assert_eq!(accounts[0].held, dec!(10.0));
+    }
 
-        // Resolve the dispute
+    #[tokio::test]
+    async fn test_withdrawal_during_dispute_blocked() {
+        let config = ProcessorConfig::new().with_block_withdrawals_during_dispute(true);
+        let (engine, result) = withdraw_during_dispute(config).await;
+        assert_eq!(result, Err(Rejection::FundsUnderDispute));
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(5.0));
+        assert_eq!(accounts[0].held, dec!(10.0));
+
+        // Allowed again once the dispute closes
         engine.process(Trx::Resolve {
             client: 1,
             tx: 1,
         }).await;
-
-        // Available returns to positive
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(10.0)); // -90 + 100 = 10
-        assert_eq!(accounts[0].held, dec!(0.0));
-        assert_eq!(accounts[0].total, dec!(10.0));
+        let result = engine.try_process(Trx::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: dec!(2.0),
+        }).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(engine.get_accounts()[0].available, dec!(13.0));
     }
 
     #[tokio::test]
-    async fn test_negative_balance_chargeback_reduces_total() {
+    async fn test_reconcile_clean_engine() {
         let engine = PaymentsEngine::new();
+        assert!(engine.reconcile().await.is_balanced());
 
-        // Create negative balance scenario and chargeback
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(100.0),
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Deposit {
+            client: 2,
+            tx: 2,
+            amount: dec!(7.5),
         }).await;
         engine.process(Trx::Withdrawal {
             client: 1,
+            tx: 3,
+            amount: dec!(4.0),
+        }).await;
+        engine.process(Trx::Fee {
+            client: 2,
+            tx: 4,
+            amount: dec!(0.5),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 2,
+            tx: 2,
+        }).await;
+        engine.process(Trx::Chargeback {
+            client: 2,
             tx: 2,
-            amount: dec!(95.0),
         }).await;
         engine.process(Trx::Dispute {
             client: 1,
             tx: 1,
         }).await;
-
-        // Available is now -95 (5 - 100), held is 100, total is 5
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(-95.0));
-        assert_eq!(accounts[0].held, dec!(100.0));
-        assert_eq!(accounts[0].total, dec!(5.0));
-
-        // Chargeback
-        engine.process(Trx::Chargeback {
+        // Rejected: insufficient funds
+        engine.process(Trx::Withdrawal {
             client: 1,
-            tx: 1,
+            tx: 5,
+            amount: dec!(100.0),
         }).await;
 
-        // Available still -95, held removed, total is now -95
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(-95.0));
-        assert_eq!(accounts[0].held, dec!(0.0));
-        assert_eq!(accounts[0].total, dec!(-95.0)); // -95 + 0 = -95 (lost the 100)
-        assert!(accounts[0].locked);
+        let report = engine.reconcile().await;
+        assert!(report.is_balanced(), "{:?}", report);
+        assert_eq!(report.evicted, 0);
+        let entry = report.currencies[&Currency::DEFAULT];
+        assert_eq!(entry.expected, Some(dec!(5.5)));
+        assert_eq!(entry.actual, Some(dec!(5.5)));
     }
 
     #[tokio::test]
-    async fn test_dispute_already_under_dispute_rejected() {
+    async fn test_reconcile_flags_drift() {
         let engine = PaymentsEngine::new();
-
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(100.0),
-        }).await;
-
-        // First dispute succeeds
-        engine.process(Trx::Dispute {
-            client: 1,
-            tx: 1,
-        }).await;
-
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].held, dec!(100.0));
-
-        // Second dispute should be rejected (already under dispute)
-        engine.process(Trx::Dispute {
-            client: 1,
-            tx: 1,
+            amount: dec!(10.0),
         }).await;
+        engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap().total += dec!(1.0);
 
-        // Should be unchanged
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(0.0));
-        assert_eq!(accounts[0].held, dec!(100.0));
-        assert_eq!(accounts[0].total, dec!(100.0));
+        let report = engine.reconcile().await;
+        assert_eq!(report.discrepancies(), vec![(Currency::DEFAULT, Some(dec!(1.0)))]);
     }
 
     #[tokio::test]
-    async fn test_deposit_overflow_rejected() {
-        let engine = PaymentsEngine::new();
-
-        // Deposit close to max
-        let near_max = Decimal::MAX - dec!(10.0);
+    async fn test_reconcile_reports_evictions() {
+        let engine = PaymentsEngine::with_max_history(Some(1));
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: near_max,
+            amount: dec!(10.0),
         }).await;
-
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, near_max);
-        assert_eq!(accounts[0].total, near_max);
-
-        // Try to deposit more (should overflow and be rejected)
         engine.process(Deposit {
             client: 1,
             tx: 2,
-            amount: dec!(20.0),
+            amount: dec!(5.0),
         }).await;
 
-        // Account should be unchanged (overflow rejected)
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, near_max);
-        assert_eq!(accounts[0].total, near_max);
-
-        // Transaction should not be stored
-        let tx_history = engine.tx_history.lock().await;
-        assert!(!tx_history.contains_key(&2));
+        // The evicted deposit still counts in the account total
+        let report = engine.reconcile().await;
+        assert_eq!(report.evicted, 1);
+        assert_eq!(report.discrepancies(), vec![(Currency::DEFAULT, Some(dec!(10.0)))]);
     }
 
     #[tokio::test]
-    async fn test_withdrawal_no_underflow() {
-        let engine = PaymentsEngine::new();
-
+    async fn test_self_check_detects_missing_held() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_self_check(true));
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(100.0),
+            amount: dec!(10.0),
+        }).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
         }).await;
+        assert!(engine.self_check().await.is_empty());
 
-        // Valid withdrawal
-        engine.process(Trx::Withdrawal {
+        // Simulate an update abandoned after the status change
+        engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap().held = Decimal::ZERO;
+
+        let mismatches = engine.self_check().await;
+        assert_eq!(mismatches, vec![HeldMismatch {
             client: 1,
+            currency: Currency::DEFAULT,
+            disputed: dec!(10.0),
+            held: Decimal::ZERO,
+        }]);
+
+        // Still fires after further operations, which run it per transaction
+        engine.process(Deposit {
+            client: 2,
             tx: 2,
-            amount: dec!(50.0),
+            amount: dec!(1.0),
         }).await;
+        assert_eq!(engine.self_check().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_process_batch_matches_sequential() {
+        let mut txs = Vec::new();
+        for i in 0..200u32 {
+            let client = (i % 7) as u16;
+            txs.push(Deposit {
+                client,
+                tx: i,
+                amount: Decimal::new(1_000 + i as i64, 2),
+            });
+            match i % 5 {
+                0 => txs.push(Trx::Dispute { client, tx: i }),
+                1 => txs.push(Trx::Withdrawal {
+                    client,
+                    tx: 10_000 + i,
+                    amount: dec!(25.0),
+                }),
+                2 => txs.push(Trx::Chargeback { client, tx: i - 2 }),
+                3 => txs.push(Trx::Resolve { client, tx: i - 3 }),
+                // Duplicate tx ID and a dispute of another client's tx
+                _ => {
+                    txs.push(Deposit {
+                        client,
+                        tx: i,
+                        amount: dec!(1.0),
+                    });
+                    txs.push(Trx::Dispute { client: client + 1, tx: i });
+                }
+            }
+        }
+
+        let sequential = PaymentsEngine::new();
+        let mut expected = Vec::new();
+        for tx in txs.clone() {
+            expected.push(sequential.try_process(tx).await);
+        }
+
+        let batched = PaymentsEngine::new();
+        let outcomes = batched.process_batch(txs).await;
+
+        assert_eq!(outcomes, expected);
+        assert!(expected.iter().any(|outcome| outcome.is_err()));
+        assert_eq!(
+            batched.get_accounts_ordered(SortOrder::ClientId),
+            sequential.get_accounts_ordered(SortOrder::ClientId)
+        );
+        for tx in 0..200 {
+            assert_eq!(batched.tx_status(tx).await, sequential.tx_status(tx).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_deposits_matches_process_batch() {
+        let config = ProcessorConfig::new()
+            .with_max_tx_per_client(Some(12))
+            .with_denied_clients(HashSet::from([9]));
+        let setup = [
+            Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(5.0),
+            },
+            Deposit {
+                client: 3,
+                tx: 2,
+                amount: dec!(1.0),
+            },
+            Trx::Freeze {
+                client: 3,
+                tx: 3,
+            },
+            Deposit {
+                client: 4,
+                tx: 4,
+                amount: Decimal::MAX,
+            },
+        ];
+        let mut txs: Vec<Trx> = (10..100u32)
+            .map(|tx| Deposit { client: (tx % 6) as u16, tx, amount: Decimal::new(tx as i64, 1) })
+            .collect();
+        // Already recorded, frozen account, overflow, denied client
+        txs.extend([
+            Deposit {
+                client: 2,
+                tx: 1,
+                amount: dec!(1.0),
+            },
+            Deposit {
+                client: 3,
+                tx: 100,
+                amount: dec!(1.0),
+            },
+            Deposit {
+                client: 4,
+                tx: 101,
+                amount: dec!(1.0),
+            },
+            Deposit {
+                client: 9,
+                tx: 102,
+                amount: dec!(1.0),
+            },
+        ]);
+
+        let (baseline, fast) = (PaymentsEngine::with_config(config.clone()), PaymentsEngine::with_config(config));
+        for engine in [&baseline, &fast] {
+            for tx in setup.clone() {
+                engine.process(tx).await;
+            }
+        }
+        let expected = baseline.process_batch(txs.clone()).await;
+        let outcomes = fast.process_deposits(txs).await;
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(50.0));
-        assert_eq!(accounts[0].total, dec!(50.0));
+        assert_eq!(outcomes, expected);
+        for rejection in [Rejection::DuplicateTx, Rejection::AccountFrozen, Rejection::ClientTxLimit,
+            Rejection::Overflow(BalanceField::Available), Rejection::ClientNotAllowed] {
+            assert!(expected.contains(&Err(rejection)), "{:?} not exercised", rejection);
+        }
+        assert_eq!(
+            fast.get_accounts_ordered(SortOrder::ClientId),
+            baseline.get_accounts_ordered(SortOrder::ClientId)
+        );
+        assert_eq!(fast.applied_count(), baseline.applied_count());
+        let (mut fast_export, mut baseline_export) = (Vec::new(), Vec::new());
+        fast.export_transactions(&mut fast_export).await.unwrap();
+        baseline.export_transactions(&mut baseline_export).await.unwrap();
+        assert_eq!(fast_export, baseline_export);
     }
 
     #[tokio::test]
-    async fn test_dispute_overflow_on_held_rejected() {
+    async fn test_batch_helpers_apply_in_currency() {
+        let usd: Currency = "USD".parse().unwrap();
+        let deposits = vec![
+            Deposit { client: 1, tx: 1, amount: dec!(5.0) },
+            Deposit { client: 2, tx: 2, amount: dec!(3.0) },
+        ];
+        let (fast, batched) = (PaymentsEngine::new(), PaymentsEngine::new());
+        fast.process_deposits_in(deposits.clone(), usd).await;
+        batched.process_batch_in(deposits, usd).await;
+        assert_eq!(fast.get_accounts(), batched.get_accounts());
+        assert!(fast.get_accounts().iter().all(|account| account.currency == usd));
+
+        fast.lock_two_accounts_in(1, 2, usd, |source, dest| {
+            source.available -= dec!(1.0);
+            dest.available += dec!(1.0);
+        });
+        let accounts = fast.get_accounts();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!((accounts[0].available, accounts[1].available), (dec!(4.0), dec!(4.0)));
+    }
+
+    #[tokio::test]
+    async fn test_process_deposits_repeated_tx_falls_back() {
+        let txs = vec![
+            Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(1.0),
+            },
+            Deposit {
+                client: 2,
+                tx: 1,
+                amount: dec!(2.0),
+            },
+            Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(3.0),
+            },
+        ];
         let engine = PaymentsEngine::new();
+        let outcomes = engine.process_deposits(txs).await;
+        assert_eq!(outcomes, vec![Ok(()), Err(Rejection::DuplicateTx), Ok(())]);
+        assert_eq!(engine.get_accounts_ordered(SortOrder::ClientId)[1].available, dec!(3.0));
+    }
 
-        // Create account with near-max held
-        engine.process(Deposit {
-            client: 1,
-            tx: 1,
-            amount: dec!(100.0),
-        }).await;
-        engine.process(Trx::Dispute {
-            client: 1,
-            tx: 1,
-        }).await;
+    #[tokio::test]
+    async fn test_process_batch_repeated_disputes_apply_once() {
+        let engine = PaymentsEngine::new();
+        let outcomes = engine.process_batch(vec![
+            Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10.0),
+            },
+            Trx::Dispute { client: 1, tx: 1 },
+            Trx::Dispute { client: 1, tx: 1 },
+            Trx::Resolve { client: 1, tx: 1 },
+            Trx::Dispute { client: 1, tx: 1 },
+            Trx::Dispute { client: 1, tx: 1 },
+        ]).await;
+
+        assert_eq!(outcomes, vec![
+            Ok(()),
+            Ok(()),
+            Err(Rejection::AlreadyUnderDispute),
+            Ok(()),
+            Ok(()),
+            Err(Rejection::AlreadyUnderDispute),
+        ]);
+        let account = &engine.get_accounts()[0];
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.held, dec!(10.0));
+        assert_eq!(account.total, dec!(10.0));
+        assert_eq!(account.open_disputes, 1);
+        assert_eq!(engine.tx_status(1).await, Some(TrxStatus::UnderDispute));
+    }
 
-        // Manually set held to near max (for testing - wouldn't happen in production)
-        {
-            let mut account = engine.user_account_map.get_mut(&1).unwrap();
-            account.held = Decimal::MAX - dec!(10.0);
+    #[tokio::test]
+    async fn test_stats_counts_inserts_and_evictions() {
+        let engine = PaymentsEngine::with_max_history(Some(50));
+        assert_eq!(engine.stats().await.tx_history, 0);
+
+        for tx in 0..120u32 {
+            engine.process(Deposit {
+                client: (tx % 10) as u16,
+                tx,
+                amount: dec!(1.0),
+            }).await;
         }
 
-        // Deposit and dispute to overflow held
-        engine.process(Deposit {
-            client: 1,
-            tx: 2,
-            amount: dec!(100.0),
-        }).await;
+        let stats = engine.stats().await;
+        assert_eq!(stats.accounts, 10);
+        assert_eq!(stats.tx_history, 50);
+        assert_eq!(stats.evicted, 70);
+        assert!(stats.estimated_bytes >= 50 * size_of::<TxRecord>() + 10 * size_of::<UserAccount>());
+    }
 
-        // This dispute should be rejected due to held overflow
-        engine.process(Trx::Dispute {
-            client: 1,
-            tx: 2,
-        }).await;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_process_batch_deposit_then_dispute() {
+        use std::sync::Arc;
+        let engine = Arc::new(PaymentsEngine::new());
 
-        // Check transaction 2 is still Normal (dispute rejected)
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history[&2].status, TrxStatus::Normal);
+        // Other tasks contend for the history lock while batches run
+        let mut handles = Vec::new();
+        for task in 0..8u32 {
+            let engine = Arc::clone(&engine);
+            handles.push(tokio::spawn(async move {
+                for i in 0..100u32 {
+                    let tx = 1_000_000 + task * 1_000 + i;
+                    engine.process(Deposit {
+                        client: 100 + task as u16,
+                        tx,
+                        amount: dec!(1.0),
+                    }).await;
+                }
+            }));
+        }
+        for i in 0..100u32 {
+            let outcomes = engine.process_batch(vec![
+                Deposit {
+                    client: 1,
+                    tx: i,
+                    amount: dec!(2.0),
+                },
+                Trx::Dispute { client: 1, tx: i },
+            ]).await;
+            assert_eq!(outcomes, vec![Ok(()), Ok(())], "batch {}", i);
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let account = engine.get_accounts().into_iter().find(|account| account.client == 1).unwrap();
+        assert_eq!(account.held, dec!(200.0));
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.open_disputes, 100);
     }
 
     #[tokio::test]
-    async fn test_resolve_overflow_on_available_rejected() {
+    async fn test_chargeback_rejected_when_held_short() {
         let engine = PaymentsEngine::new();
-
-        // Create disputed transaction
         engine.process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(100.0),
+            amount: dec!(10.0),
         }).await;
         engine.process(Trx::Dispute {
             client: 1,
             tx: 1,
         }).await;
+        engine.user_account_map.get_mut(&(1, Currency::DEFAULT)).unwrap().held = dec!(4.0);
 
-        // Manually set available to near max (for testing)
-        {
-            let mut account = engine.user_account_map.get_mut(&1).unwrap();
-            account.available = Decimal::MAX - dec!(10.0);
-        }
-
-        // Resolve should be rejected due to available overflow
-        engine.process(Trx::Resolve {
+        let result = engine.try_process(Trx::Chargeback {
             client: 1,
             tx: 1,
         }).await;
+        assert_eq!(result, Err(Rejection::InsufficientHeld));
 
-        // Transaction should still be under dispute (resolve rejected)
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history[&1].status, TrxStatus::UnderDispute);
-
-        // Held should still have the amount
         let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].held, dec!(100.0));
+        assert_eq!(accounts[0].held, dec!(4.0));
+        assert_eq!(accounts[0].total, dec!(10.0));
+        assert!(!accounts[0].locked);
+        assert_eq!(engine.tx_status(1).await, Some(TrxStatus::UnderDispute));
     }
 
     #[tokio::test]
-    async fn test_duplicate_deposit_rejected() {
+    async fn test_overflow_rejection_records_field() {
+        use crate::domain::rejection::RejectionSummary;
         let engine = PaymentsEngine::new();
+        let mut summary = RejectionSummary::new();
 
-        // First deposit succeeds
-        engine.process(Deposit {
+        summary.record(engine.try_process(Deposit {
             client: 1,
             tx: 1,
-            amount: dec!(100.0),
-        }).await;
-
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(100.0));
-        assert_eq!(accounts[0].total, dec!(100.0));
+            amount: Decimal::MAX,
+        }).await);
+        summary.record(engine.try_process(Deposit {
+            client: 1,
+            tx: 2,
+            amount: dec!(1.0),
+        }).await);
 
-        // Second deposit with same tx ID should be rejected
         engine.process(Deposit {
-            client: 1,
-            tx: 1,
-            amount: dec!(50.0),
+            client: 2,
+            tx: 3,
+            amount: dec!(5.0),
         }).await;
+        engine.user_account_map.get_mut(&(2, Currency::DEFAULT)).unwrap().held = Decimal::MAX;
+        summary.record(engine.try_process(Trx::Dispute {
+            client: 2,
+            tx: 3,
+        }).await);
 
-        // Account should be unchanged (only first deposit applied)
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(100.0));
-        assert_eq!(accounts[0].total, dec!(100.0));
-
-        // Transaction history should only have first deposit
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history[&1].amount, dec!(100.0));
-        assert_eq!(tx_history[&1].client, 1);
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.count(Rejection::Overflow(BalanceField::Available)), 1);
+        assert_eq!(summary.count(Rejection::Overflow(BalanceField::Held)), 1);
+        assert_eq!(summary.count(Rejection::Overflow(BalanceField::Total)), 0);
     }
 
     #[tokio::test]
-    async fn test_duplicate_withdrawal_rejected() {
-        let engine = PaymentsEngine::new();
+    async fn test_engine_on_mock_store_matches_in_memory() {
+        use crate::services::tx_store::tests::MockTxStore;
+        use std::sync::atomic::Ordering;
+
+        let config = ProcessorConfig::default().with_max_tx_history(Some(2));
+        let store = MockTxStore::default();
+        let calls = store.calls.clone();
+        let mock = PaymentsEngine::with_store(store, config.clone());
+        let in_memory = PaymentsEngine::with_config(config);
+
+        let txs = vec![
+            Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10.0),
+            },
+            Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(5.0),
+            },
+            Trx::Dispute {
+                client: 1,
+                tx: 2,
+            },
+            Deposit {
+                client: 1,
+                tx: 3,
+                amount: dec!(1.0),
+            },
+            Trx::Dispute {
+                client: 1,
+                tx: 1,
+            },
+            Trx::Chargeback {
+                client: 1,
+                tx: 2,
+            },
+        ];
+        for tx in txs {
+            assert_eq!(mock.try_process(tx.clone()).await, in_memory.try_process(tx).await);
+        }
 
-        // Setup with initial deposit
+        assert_eq!(mock.get_accounts(), in_memory.get_accounts());
+        assert_eq!(mock.tx_status(1).await, None);
+        assert_eq!(mock.tx_status(2).await, Some(TrxStatus::ChargedBack));
+        assert_eq!(mock.stats().await.evicted, 1);
+        assert!(calls.load(Ordering::Relaxed) > 0);
+    }
+
+    async fn withdrawal_dispute_engine(mode: WithdrawalDisputeMode) -> PaymentsEngine {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_withdrawal_dispute_mode(mode));
         engine.process(Deposit {
             client: 1,
             tx: 1,
             amount: dec!(100.0),
         }).await;
-
-        // First withdrawal succeeds
         engine.process(Trx::Withdrawal {
             client: 1,
             tx: 2,
             amount: dec!(30.0),
         }).await;
-
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(70.0));
-
-        // Second withdrawal with same tx ID should be rejected
-        engine.process(Trx::Withdrawal {
+        engine.process(Trx::Dispute {
             client: 1,
             tx: 2,
-            amount: dec!(20.0),
         }).await;
+        engine
+    }
 
-        // Account should be unchanged
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(70.0));
-        assert_eq!(accounts[0].total, dec!(70.0));
-
-        // Transaction history should only have first withdrawal
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history[&2].amount, dec!(30.0));
+    fn balances(engine: &PaymentsEngine) -> (Decimal, Decimal, Decimal) {
+        let account = &engine.get_accounts()[0];
+        (account.available, account.held, account.total)
     }
 
     #[tokio::test]
-    async fn test_duplicate_different_clients_rejected() {
-        let engine = PaymentsEngine::new();
+    async fn test_withdrawal_dispute_hold_mode_math() {
+        let engine = withdrawal_dispute_engine(WithdrawalDisputeMode::Hold).await;
+        assert_eq!(balances(&engine), (dec!(40.0), dec!(30.0), dec!(70.0)));
 
-        // Client 1 deposits with tx=1
-        engine.process(Deposit {
+        engine.process(Trx::Resolve {
             client: 1,
-            tx: 1,
-            amount: dec!(100.0),
+            tx: 2,
         }).await;
+        assert_eq!(balances(&engine), (dec!(70.0), dec!(0.0), dec!(70.0)));
 
-        // Client 2 tries to deposit with same tx=1 (should be rejected)
-        engine.process(Deposit {
-            client: 2,
-            tx: 1,
-            amount: dec!(50.0),
+        let engine = withdrawal_dispute_engine(WithdrawalDisputeMode::Hold).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 2,
         }).await;
+        assert_eq!(balances(&engine), (dec!(40.0), dec!(0.0), dec!(40.0)));
+    }
 
-        let accounts = engine.get_accounts();
-
-        // Client 1 should have their deposit
-        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(client1.available, dec!(100.0));
+    #[tokio::test]
+    async fn test_withdrawal_dispute_credit_back_mode_math() {
+        let engine = withdrawal_dispute_engine(WithdrawalDisputeMode::CreditBack).await;
+        assert_eq!(balances(&engine), (dec!(70.0), dec!(30.0), dec!(100.0)));
+        assert!(engine.reconcile().await.is_balanced());
 
-        // Client 2 should have nothing (their deposit was rejected)
-        assert!(accounts.iter().find(|a| a.client == 2).is_none());
+        // Resolve: the withdrawal stands
+        engine.process(Trx::Resolve {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(balances(&engine), (dec!(70.0), dec!(0.0), dec!(70.0)));
+        assert!(engine.reconcile().await.is_balanced());
 
-        // Transaction history should only have client 1's transaction
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history[&1].client, 1);
-        assert_eq!(tx_history[&1].amount, dec!(100.0));
+        // Chargeback: the withdrawal is reversed and the account locked
+        let engine = withdrawal_dispute_engine(WithdrawalDisputeMode::CreditBack).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(balances(&engine), (dec!(100.0), dec!(0.0), dec!(100.0)));
+        assert!(engine.get_accounts()[0].locked);
+        assert!(engine.reconcile().await.is_balanced());
     }
 
     #[tokio::test]
-    async fn test_duplicate_mixed_types_rejected() {
-        let engine = PaymentsEngine::new();
+    async fn test_credit_back_mode_leaves_deposit_disputes_alone() {
+        let engine = withdrawal_dispute_engine(WithdrawalDisputeMode::CreditBack).await;
+        engine.process(Trx::Dispute {
+            client: 1,
+            tx: 1,
+        }).await;
+        assert_eq!(balances(&engine), (dec!(-30.0), dec!(130.0), dec!(100.0)));
+    }
 
-        // Deposit with tx=1
+    #[tokio::test]
+    async fn test_credit_back_direct_chargeback() {
+        let engine = PaymentsEngine::with_config(
+            ProcessorConfig::new()
+                .with_withdrawal_dispute_mode(WithdrawalDisputeMode::CreditBack)
+                .with_allow_direct_chargeback(true),
+        );
         engine.process(Deposit {
             client: 1,
             tx: 1,
             amount: dec!(100.0),
         }).await;
-
-        // Try withdrawal with same tx=1 (should be rejected)
         engine.process(Trx::Withdrawal {
             client: 1,
-            tx: 1,
-            amount: dec!(50.0),
+            tx: 2,
+            amount: dec!(30.0),
         }).await;
+        engine.process(Trx::Chargeback {
+            client: 1,
+            tx: 2,
+        }).await;
+        assert_eq!(balances(&engine), (dec!(100.0), dec!(0.0), dec!(100.0)));
+        assert!(engine.reconcile().await.is_balanced());
+    }
 
-        let accounts = engine.get_accounts();
-        // Only deposit should have been applied
-        assert_eq!(accounts[0].available, dec!(100.0));
-        assert_eq!(accounts[0].total, dec!(100.0));
-
-        // Transaction history should only have deposit
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history.len(), 1);
-        assert_eq!(tx_history[&1].amount, dec!(100.0));
+    #[tokio::test]
+    async fn test_with_capacity_matches_default_constructor() {
+        let sized = PaymentsEngine::with_capacity(1_000, 5_000, Some(3));
+        let plain = PaymentsEngine::with_max_history(Some(3));
+        assert!(sized.stats().await.estimated_bytes > plain.stats().await.estimated_bytes);
+
+        let txs = vec![
+            Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10.0),
+            },
+            Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(5.0),
+            },
+            Trx::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: dec!(4.0),
+            },
+            Deposit {
+                client: 1,
+                tx: 4,
+                amount: dec!(1.0),
+            },
+            Trx::Dispute {
+                client: 1,
+                tx: 1,
+            },
+            Trx::Dispute {
+                client: 2,
+                tx: 2,
+            },
+        ];
+        for tx in txs {
+            assert_eq!(sized.try_process(tx.clone()).await, plain.try_process(tx).await);
+        }
+        assert_eq!(
+            sized.get_accounts_ordered(SortOrder::ClientId),
+            plain.get_accounts_ordered(SortOrder::ClientId)
+        );
+        assert_eq!(sized.stats().await.evicted, plain.stats().await.evicted);
     }
 
     #[tokio::test]
-    async fn test_duplicate_after_dispute_rejected() {
-        let engine = PaymentsEngine::new();
+    async fn test_max_accounts_rejects_new_clients_only() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_max_accounts(Some(2)));
+        for client in 1..=2 {
+            engine.process(Deposit {
+                client,
+                tx: client.into(),
+                amount: dec!(10.0),
+            }).await;
+        }
 
-        // Deposit and dispute it
-        engine.process(Deposit {
-            client: 1,
-            tx: 1,
-            amount: dec!(100.0),
+        let result = engine.try_process(Deposit {
+            client: 3,
+            tx: 3,
+            amount: dec!(10.0),
         }).await;
-        engine.process(Trx::Dispute {
-            client: 1,
-            tx: 1,
+        assert_eq!(result, Err(Rejection::AccountLimit));
+        let result = engine.try_process(Trx::Fee {
+            client: 3,
+            tx: 4,
+            amount: dec!(1.0),
         }).await;
+        assert_eq!(result, Err(Rejection::AccountLimit));
 
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(0.0));
-        assert_eq!(accounts[0].held, dec!(100.0));
-
-        // Try to deposit with same tx=1 (should be rejected even though under dispute)
-        engine.process(Deposit {
+        // Existing clients keep processing
+        assert!(engine.try_process(Trx::Withdrawal {
             client: 1,
-            tx: 1,
-            amount: dec!(50.0),
-        }).await;
+            tx: 5,
+            amount: dec!(4.0),
+        }).await.is_ok());
+        assert!(engine.try_process(Deposit {
+            client: 2,
+            tx: 6,
+            amount: dec!(1.0),
+        }).await.is_ok());
+
+        let accounts = engine.get_accounts_ordered(SortOrder::ClientId);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].available, dec!(6.0));
+        assert_eq!(accounts[1].available, dec!(11.0));
+        assert_eq!(engine.tx_status(3).await, None);
+    }
 
-        // Account should be unchanged
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts[0].available, dec!(0.0));
-        assert_eq!(accounts[0].held, dec!(100.0));
-        assert_eq!(accounts[0].total, dec!(100.0));
+    #[tokio::test]
+    async fn test_max_accounts_counts_clients_not_currencies() {
+        let engine = PaymentsEngine::with_config(ProcessorConfig::new().with_max_accounts(Some(2)));
+        let eur: Currency = "EUR".parse().unwrap();
+        for client in 1..=2 {
+            engine.process(Deposit {
+                client,
+                tx: client.into(),
+                amount: dec!(10.0),
+            }).await;
+        }
 
-        // Transaction should still be under dispute with original amount
-        let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history[&1].status, TrxStatus::UnderDispute);
-        assert_eq!(tx_history[&1].amount, dec!(100.0));
+        // An existing client opening a second currency is not a new account
+        let result = engine.try_process_in(Deposit {
+            client: 1,
+            tx: 3,
+            amount: dec!(5.0),
+        }, eur).await;
+        assert_eq!(result, Ok(()));
+        let result = engine.try_process_in(Deposit {
+            client: 2,
+            tx: 4,
+            amount: dec!(5.0),
+        }, eur).await;
+        assert_eq!(result, Ok(()));
+
+        let result = engine.try_process_in(Deposit {
+            client: 3,
+            tx: 5,
+            amount: dec!(5.0),
+        }, eur).await;
+        assert_eq!(result, Err(Rejection::AccountLimit));
+        assert_eq!(engine.get_accounts().len(), 4);
     }
 
     // ============================================
@@ -1183,8 +5024,8 @@ mod tests {
 
         // Verify only one transaction stored
         let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history.len(), 1);
-        assert_eq!(tx_history[&1].amount, dec!(100.0));
+        assert_eq!(tx_history.len().await, 1);
+        assert_eq!(tx_history.get(1).await.unwrap().amount, dec!(100.0));
     }
 
     #[tokio::test]
@@ -1218,7 +5059,7 @@ mod tests {
         assert_eq!(accounts[0].total, dec!(1000.0));
 
         let tx_history = engine.tx_history.lock().await;
-        assert_eq!(tx_history.len(), 100);
+        assert_eq!(tx_history.len().await, 100);
     }
 
     #[tokio::test]
@@ -1323,8 +5164,8 @@ mod tests {
 
         // Check that dispute happened only once
         let tx_history = engine.tx_history.lock().await;
-        let disputed = tx_history.values()
-            .filter(|t| t.status == TrxStatus::UnderDispute)
+        let disputed = tx_history.records().await.iter()
+            .filter(|(_, t)| t.status == TrxStatus::UnderDispute)
             .count();
         assert!(disputed <= 1, "Should have at most 1 disputed transaction");
     }
@@ -1391,7 +5232,7 @@ mod tests {
 
         // Verify no data corruption
         let tx_history = engine.tx_history.lock().await;
-        assert!(tx_history.len() <= 150, "Should have at most 150 unique transactions");
+        assert!(tx_history.len().await <= 150, "Should have at most 150 unique transactions");
     }
 
     #[tokio::test]
@@ -1425,18 +5266,118 @@ mod tests {
         let accounts = engine.get_accounts();
         let tx_history = engine.tx_history.lock().await;
 
-        assert_eq!(tx_history.len(), 1);
-        assert_eq!(accounts[0].total, tx_history[&1].amount,
+        assert_eq!(tx_history.len().await, 1);
+        assert_eq!(accounts[0].total, tx_history.get(1).await.unwrap().amount,
             "Account total should match the single stored transaction amount");
     }
-}
 
-impl Default for PaymentsEngine {
-    fn default() -> Self {
-        PaymentsEngine {
-            user_account_map: DashMap::new(),
-            tx_history: Mutex::new(IndexMap::new()),
-            max_tx_history: None,
+    #[test]
+    fn test_lock_two_accounts_reciprocal_transfers() {
+        use std::sync::{mpsc, Arc};
+        use std::time::Duration;
+
+        let engine = Arc::new(PaymentsEngine::new());
+        // Include a pair that shares a shard alongside pairs that don't
+        let map = &engine.user_account_map;
+        let same_shard = (3..u16::MAX)
+            .find(|&c| map.determine_map(&(c, Currency::DEFAULT)) == map.determine_map(&(2, Currency::DEFAULT)))
+            .unwrap();
+        let pairs = [(1u16, 2u16), (2, same_shard), (1, same_shard)];
+        for client in [1, 2, same_shard] {
+            let mut account = engine.get_or_create_account(client);
+            account.available = dec!(1000);
+            account.total = dec!(1000);
+        }
+
+        let (done_tx, done_rx) = mpsc::channel();
+        for thread in 0..8 {
+            let engine = engine.clone();
+            let done_tx = done_tx.clone();
+            std::thread::spawn(move || {
+                for i in 0..2_000 {
+                    let (from, to) = pairs[(thread + i) % pairs.len()];
+                    // Alternate direction so A->B and B->A race each other
+                    let (from, to) = if (thread + i) % 2 == 0 { (from, to) } else { (to, from) };
+                    engine.lock_two_accounts(from, to, |source, dest| {
+                        assert_eq!(source.client, from);
+                        assert_eq!(dest.client, to);
+                        source.available -= dec!(1);
+                        source.total -= dec!(1);
+                        dest.available += dec!(1);
+                        dest.total += dec!(1);
+                    });
+                }
+                done_tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..8 {
+            done_rx.recv_timeout(Duration::from_secs(30)).expect("transfers deadlocked");
+        }
+
+        let accounts = engine.get_accounts();
+        let total: Decimal = accounts.iter().map(|a| a.total).sum();
+        assert_eq!(total, dec!(3000));
+        assert!(accounts.iter().all(|a| a.verify_totals()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_snapshot_is_coherent_under_concurrent_writes() {
+        use std::sync::Arc;
+        let engine = Arc::new(PaymentsEngine::new());
+
+        // Each batch credits clients 1 and 2 together, so any coherent view
+        // shows them with equal totals
+        let mut handles = Vec::new();
+        for task in 0..4u32 {
+            let engine = Arc::clone(&engine);
+            handles.push(tokio::spawn(async move {
+                for i in 0..200u32 {
+                    let tx = task * 10_000 + i * 4;
+                    engine.process_batch(vec![
+                        Deposit {
+                            client: 1,
+                            tx,
+                            amount: dec!(2.0),
+                        },
+                        Deposit {
+                            client: 2,
+                            tx: tx + 1,
+                            amount: dec!(2.0),
+                        },
+                        Trx::Dispute {
+                            client: 1,
+                            tx,
+                        },
+                        Trx::Withdrawal {
+                            client: 2,
+                            tx: tx + 2,
+                            amount: dec!(1.0),
+                        },
+                        Deposit {
+                            client: 2,
+                            tx: tx + 3,
+                            amount: dec!(1.0),
+                        },
+                    ]).await;
+                }
+            }));
+        }
+
+        for _ in 0..200 {
+            let accounts = engine.snapshot_accounts(SortOrder::ClientId).await;
+            assert!(accounts.iter().all(|a| a.verify_totals()));
+            if let [first, second] = accounts.as_slice() {
+                assert_eq!(first.total, second.total);
+                assert_eq!(first.held, first.total);
+            }
+            tokio::task::yield_now().await;
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
         }
+        let accounts = engine.snapshot_accounts(SortOrder::ClientId).await;
+        assert_eq!(accounts[0].total, dec!(1600));
+        assert_eq!(accounts[1].total, dec!(1600));
     }
 }
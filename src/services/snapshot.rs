@@ -0,0 +1,218 @@
+use crate::config::ProcessorConfig;
+use crate::domain::transaction::TxRecord;
+use crate::domain::user_account::UserAccount;
+use crate::error::Result;
+use crate::services::payment_engine::PaymentsEngine;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the background task checks whether a snapshot is due.
+const SNAPSHOT_POLL: Duration = Duration::from_millis(100);
+
+/// The engine's full state: account balances plus the retained history.
+/// Restoring it doesn't depend on evicted records, which replaying the
+/// transactions would need.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub accounts: Vec<UserAccount>,
+    pub records: Vec<(u32, TxRecord)>,
+    pub next_ordinal: u64,
+    pub evicted: u64,
+}
+
+/// Periodically writes the engine's state to a file, so a long-running server
+/// can be rebuilt with `load_snapshot` after a crash, losing at most one
+/// interval. A snapshot is due once `every` transactions were applied since
+/// the last one, or once `interval` has passed and anything was applied.
+pub struct Snapshotter {
+    path: PathBuf,
+    every: Option<u64>,
+    interval: Option<Duration>,
+    last_applied: u64,
+    last_at: Instant,
+}
+
+impl Snapshotter {
+    pub fn new(path: impl Into<PathBuf>, every: Option<u64>, interval: Option<Duration>) -> Self {
+        Snapshotter {
+            path: path.into(),
+            every,
+            interval,
+            last_applied: 0,
+            last_at: Instant::now(),
+        }
+    }
+
+    /// `None` unless `snapshot_path` and at least one threshold are set.
+    pub fn from_config(config: &ProcessorConfig) -> Option<Self> {
+        let path = config.snapshot_path.clone()?;
+        (config.snapshot_every.is_some() || config.snapshot_interval.is_some())
+            .then(|| Snapshotter::new(path, config.snapshot_every, config.snapshot_interval))
+    }
+
+    fn is_due(&self, applied: u64, now: Instant) -> bool {
+        let since = applied.saturating_sub(self.last_applied);
+        self.every.is_some_and(|every| since >= every.max(1))
+            || self.interval.is_some_and(|interval| since > 0 && now.duration_since(self.last_at) >= interval)
+    }
+
+    /// Writes a snapshot if a threshold has been crossed, returning whether it did.
+    pub async fn snapshot_if_due(&mut self, engine: &PaymentsEngine) -> Result<bool> {
+        let (applied, now) = (engine.applied_count(), Instant::now());
+        if !self.is_due(applied, now) {
+            return Ok(false);
+        }
+        write_snapshot(engine, &self.path).await?;
+        self.last_applied = applied;
+        self.last_at = now;
+        log::info!("Snapshot written to {} after {} transactions", self.path.display(), applied);
+        Ok(true)
+    }
+
+    /// Checks every `SNAPSHOT_POLL` until the task is dropped. Failures are
+    /// logged and retried on the next check.
+    pub async fn run(mut self, engine: Arc<PaymentsEngine>) {
+        let mut ticker = tokio::time::interval(SNAPSHOT_POLL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.snapshot_if_due(&engine).await {
+                log::error!("Snapshot to {} failed: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Writes via a synced temporary file, so a crash never leaves a torn
+/// snapshot. The engine's sinks are flushed first, so a buffered WAL is never
+/// behind it. The file IO runs on the blocking pool.
+pub async fn write_snapshot(engine: &PaymentsEngine, path: &Path) -> Result<()> {
+    engine.flush().await?;
+    let contents = serde_json::to_vec(&engine.snapshot().await)?;
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || write_synced(&path, &contents))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    // The rename is only durable once the directory entry is
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Restores `engine` from the snapshot at `path`, returning `false` when
+/// there is none yet.
+pub async fn load_snapshot(path: &Path, engine: &PaymentsEngine) -> Result<bool> {
+    let path = path.to_path_buf();
+    let contents = match tokio::task::spawn_blocking(move || std::fs::read(path))
+        .await
+        .map_err(std::io::Error::other)?
+    {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    engine.restore(serde_json::from_slice(&contents)?).await;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::transaction::Trx;
+    use crate::domain::user_account::SortOrder;
+    use rust_decimal_macros::dec;
+
+    #[tokio::test]
+    async fn test_snapshot_written_after_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.snapshot");
+        let engine = PaymentsEngine::new();
+        let mut snapshotter = Snapshotter::new(&path, Some(3), None);
+
+        engine.process(Trx::Deposit { client: 1, tx: 1, amount: dec!(10.0) }).await;
+        engine.process(Trx::Deposit { client: 2, tx: 2, amount: dec!(4.0) }).await;
+        assert!(!snapshotter.snapshot_if_due(&engine).await.unwrap());
+        assert!(!path.exists());
+
+        engine.process(Trx::Dispute { client: 1, tx: 1 }).await;
+        assert!(snapshotter.snapshot_if_due(&engine).await.unwrap());
+        // Nothing new since, so no rewrite
+        assert!(!snapshotter.snapshot_if_due(&engine).await.unwrap());
+
+        // Later changes stay out until the next threshold
+        engine.process(Trx::Withdrawal { client: 2, tx: 3, amount: dec!(1.0) }).await;
+        let restored = PaymentsEngine::new();
+        assert!(load_snapshot(&path, &restored).await.unwrap());
+        let accounts = restored.get_accounts_ordered(SortOrder::ClientId);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].held, dec!(10.0));
+        assert_eq!(accounts[1].available, dec!(4.0));
+    }
+
+    #[tokio::test]
+    async fn test_interval_snapshot_needs_new_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.snapshot");
+        let engine = PaymentsEngine::new();
+        let mut snapshotter = Snapshotter::new(&path, None, Some(Duration::ZERO));
+
+        assert!(!snapshotter.snapshot_if_due(&engine).await.unwrap());
+        engine.process(Trx::Deposit { client: 1, tx: 1, amount: dec!(1.0) }).await;
+        assert!(snapshotter.snapshot_if_due(&engine).await.unwrap());
+        assert!(!snapshotter.snapshot_if_due(&engine).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restores_evicted_and_operator_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.snapshot");
+        let config = ProcessorConfig::default().with_max_tx_history(Some(2));
+        let engine = PaymentsEngine::with_config(config.clone());
+
+        engine.process(Trx::Deposit { client: 1, tx: 1, amount: dec!(10.0) }).await;
+        engine.process(Trx::Deposit { client: 2, tx: 2, amount: dec!(5.0) }).await;
+        engine.process(Trx::Deposit { client: 2, tx: 3, amount: dec!(1.0) }).await;
+        engine.process(Trx::Dispute { client: 2, tx: 3 }).await;
+        engine.process(Trx::Freeze { client: 1, tx: 4 }).await;
+        write_snapshot(&engine, &path).await.unwrap();
+
+        // tx 1 was evicted, but its deposit is still in client 1's balance
+        let restored = PaymentsEngine::with_config(config);
+        assert!(load_snapshot(&path, &restored).await.unwrap());
+        assert_eq!(
+            restored.get_accounts_ordered(SortOrder::ClientId),
+            engine.get_accounts_ordered(SortOrder::ClientId)
+        );
+        assert_eq!(restored.snapshot().await, engine.snapshot().await);
+        let accounts = restored.get_accounts_ordered(SortOrder::ClientId);
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert!(accounts[0].frozen);
+        assert_eq!(accounts[1].held, dec!(1.0));
+        assert_eq!(accounts[1].open_disputes, 1);
+
+        // The restored history still resolves the open dispute
+        restored.process(Trx::Resolve { client: 2, tx: 3 }).await;
+        assert_eq!(restored.get_accounts_ordered(SortOrder::ClientId)[1].available, dec!(6.0));
+    }
+
+    #[tokio::test]
+    async fn test_missing_snapshot_loads_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = PaymentsEngine::new();
+        assert!(!load_snapshot(&dir.path().join("absent.snapshot"), &engine).await.unwrap());
+        assert!(engine.get_accounts().is_empty());
+    }
+}
@@ -0,0 +1,928 @@
+use crate::config::{ConnectionLimitPolicy, OutputFormat, ProcessorConfig};
+use crate::domain::rejection::RejectionSummary;
+use crate::error::{PaymentError, Result};
+use crate::services::binary_protocol::{self, apply_binary_stream};
+use crate::services::payment_engine::PaymentsEngine;
+use crate::services::snapshot::Snapshotter;
+use crate::services::trx_processor::{
+    apply_csv, apply_csv_stream, write_accounts, ClientRateLimiter, ProcessProgress, RunHooks,
+};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::JoinSet;
+
+/// A connection's place in accept order. Holding a turn blocks dispatch of
+/// later connections until it is dropped.
+pub struct DispatchTurn {
+    previous: Option<oneshot::Receiver<()>>,
+    _done: oneshot::Sender<()>,
+}
+
+impl DispatchTurn {
+    async fn wait(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            // Resolves once the previous turn's sender is dropped
+            let _ = previous.await;
+        }
+    }
+}
+
+/// Hands out dispatch turns in the order connections are accepted. A
+/// connection holds its turn while its request is read, so `serve` requires a
+/// `read_timeout` to bound how long a stalled peer can hold up the rest.
+#[derive(Default)]
+pub struct Sequencer {
+    last: Option<oneshot::Receiver<()>>,
+}
+
+impl Sequencer {
+    pub fn next_turn(&mut self) -> DispatchTurn {
+        let (done, next) = oneshot::channel();
+        DispatchTurn {
+            previous: self.last.replace(next),
+            _done: done,
+        }
+    }
+}
+
+const BUSY_RESPONSE: &[u8] = b"ERROR server busy\n";
+const BUSY_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Longest first line inspected when checking for a health probe.
+const PROBE_SCAN_BYTES: usize = 64;
+/// Optional first request line choosing the response format, e.g. `FORMAT json`
+const FORMAT_PREFIX: &[u8] = b"FORMAT ";
+
+/// Liveness probes answered without touching the engine.
+#[derive(Debug, PartialEq, Eq)]
+enum HealthProbe {
+    /// `PING\n`, answered with `PONG\n`
+    Ping,
+    /// `GET /health`, answered with an HTTP 200
+    Http,
+}
+
+impl HealthProbe {
+    fn detect(first_line: &[u8]) -> Option<Self> {
+        let line = first_line.strip_suffix(b"\n")?;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line == b"PING" {
+            Some(HealthProbe::Ping)
+        } else if line == b"GET /health" || line.starts_with(b"GET /health ") {
+            Some(HealthProbe::Http)
+        } else {
+            None
+        }
+    }
+
+    fn response(&self) -> &'static [u8] {
+        match self {
+            HealthProbe::Ping => b"PONG\n",
+            HealthProbe::Http => {
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nOK"
+            }
+        }
+    }
+}
+
+enum Request {
+    Batch(Vec<u8>),
+    /// Streaming mode; holds the bytes read while checking for a probe
+    Stream(Vec<u8>),
+    /// Binary protocol; holds the bytes read after the marker
+    Binary(Vec<u8>),
+    Probe(HealthProbe),
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    engine: Arc<PaymentsEngine>,
+    config: ProcessorConfig,
+) -> Result<()> {
+    serve_until(listener, engine, config, std::future::pending()).await
+}
+
+/// Like `serve`, until `shutdown` resolves: then stops accepting, waits for
+/// every connection already accepted to finish, and returns. Flushing the
+/// engine afterwards therefore sees all of their writes.
+pub async fn serve_until(
+    listener: TcpListener,
+    engine: Arc<PaymentsEngine>,
+    config: ProcessorConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let sequenced = config.ordered_dispatch || config.deterministic;
+    if sequenced && config.read_timeout.is_none() {
+        return Err(PaymentError::InvalidConfig(
+            "ordered dispatch requires a read_timeout, or one stalled connection blocks every later one".to_string(),
+        ));
+    }
+    let mut sequencer = Sequencer::default();
+    let limit = config.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+    let snapshots = Snapshotter::from_config(&config).map(|snapshotter| tokio::spawn(snapshotter.run(engine.clone())));
+    let mut handlers = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        while handlers.try_join_next().is_some() {}
+
+        // Under the wait policy, stop accepting until a handler slot frees up
+        let mut permit = match (&limit, config.connection_limit_policy) {
+            (Some(limit), ConnectionLimitPolicy::Wait) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit.expect("semaphore is never closed")),
+                _ = &mut shutdown => break,
+            },
+            _ => None,
+        };
+
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        match accepted {
+            Ok((socket, addr)) => {
+                if let (Some(limit), ConnectionLimitPolicy::Reject) =
+                    (&limit, config.connection_limit_policy)
+                {
+                    match limit.clone().try_acquire_owned() {
+                        Ok(acquired) => permit = Some(acquired),
+                        Err(_) => {
+                            log::warn!("[{}] Rejected: connection limit reached", addr);
+                            // Best effort, and bounded so a stalled peer can't hold up accepts
+                            let mut socket = socket;
+                            let _ = tokio::time::timeout(BUSY_WRITE_TIMEOUT, async {
+                                socket.write_all(BUSY_RESPONSE).await?;
+                                socket.shutdown().await
+                            })
+                            .await;
+                            continue;
+                        }
+                    }
+                }
+
+                let engine = engine.clone();
+                let config = config.clone();
+                let turn = sequenced.then(|| sequencer.next_turn());
+
+                handlers.spawn(async move {
+                    log::info!("[{}] Connection accepted", addr);
+
+                    if let Err(e) = handle_connection(socket, engine, config, addr, turn).await {
+                        log::error!("[{}] Error: {}", addr, e);
+                    }
+
+                    log::info!("[{}] Connection closed", addr);
+                    drop(permit);
+                });
+            }
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+            }
+        }
+    }
+
+    drop(listener);
+    log::info!("Stopped accepting; waiting for {} open connections", handlers.len());
+    while handlers.join_next().await.is_some() {}
+    if let Some(snapshots) = snapshots {
+        snapshots.abort();
+    }
+    Ok(())
+}
+
+pub async fn handle_connection(
+    mut socket: TcpStream,
+    engine: Arc<PaymentsEngine>,
+    config: ProcessorConfig,
+    addr: SocketAddr,
+    mut turn: Option<DispatchTurn>,
+) -> Result<()> {
+    let (request, format) = read_request(&mut socket, &config, addr).await?;
+    let config = match format {
+        Some(format) => config.with_output_format(format),
+        None => config,
+    };
+    if let Request::Probe(probe) = request {
+        log::debug!("[{}] Health probe {:?}", addr, probe);
+        // Pass the turn on only once earlier connections finish, so the
+        // probe doesn't let later batches jump the queue
+        if let Some(mut turn) = turn {
+            tokio::spawn(async move { turn.wait().await });
+        }
+        socket.write_all(probe.response()).await?;
+        socket.flush().await?;
+        return Ok(());
+    }
+
+    // Requests are read concurrently, but applied strictly in accept order
+    if let Some(turn) = turn.as_mut() {
+        turn.wait().await;
+    }
+    let engine = if config.isolated_connections {
+        Arc::new(PaymentsEngine::with_config(config.clone()))
+    } else {
+        engine
+    };
+
+    let (mut reader, mut writer) = socket.split();
+    let (ack_tx, mut ack_rx) = mpsc::unbounded_channel();
+    let mut hooks = RunHooks {
+        rate_limit: config.client_rate_limit.map(ClientRateLimiter::new),
+        touched_clients: config.delta_output.then(BTreeSet::new),
+        ..RunHooks::default()
+    };
+    if let Some(interval) = config.ack_interval {
+        let callback = move |progress: ProcessProgress| {
+            let _ = ack_tx.send(progress.rows);
+        };
+        hooks.progress = Some((interval.max(1), Box::new(callback)));
+    } else {
+        drop(ack_tx);
+    }
+
+    let mut summary = RejectionSummary::new();
+    let mut touched_clients = None;
+    let apply = async {
+        // Dropping the hooks closes the ack channel once processing ends
+        let mut hooks = hooks;
+        let applied = match request {
+            Request::Batch(buffer) => {
+                apply_csv(&engine, Cursor::new(buffer), &config, &mut summary, &mut hooks).await
+            }
+            Request::Stream(prefix) => {
+                let reader = Cursor::new(prefix).chain(&mut reader);
+                let stats =
+                    apply_csv_stream(&engine, reader, &config, &mut summary, &mut hooks).await?;
+                log::info!(
+                    "[{}] Streamed {} bytes (peak buffer {} bytes)",
+                    addr,
+                    stats.bytes_read,
+                    stats.peak_buffered
+                );
+                Ok(())
+            }
+            Request::Binary(prefix) => {
+                let reader = Cursor::new(prefix).chain(&mut reader);
+                apply_binary_stream(&engine, reader, &config, &mut summary, &mut hooks).await
+            }
+            Request::Probe(_) => unreachable!("probes are answered above"),
+        };
+        touched_clients = hooks.touched_clients.take();
+        applied
+    };
+    // Acks go out while a streamed request is still being read
+    let send_acks = async {
+        while let Some(rows) = ack_rx.recv().await {
+            writer.write_all(format!("ACK {}\n", rows).as_bytes()).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let (applied, acked) = tokio::join!(apply, send_acks);
+    applied?;
+    acked?;
+
+    log::info!(
+        "[{}] Processed {} transactions ({} errors/skipped; {})",
+        addr,
+        summary.applied,
+        summary.total_rejected(),
+        summary.type_breakdown()
+    );
+    if !summary.reused_tx_ids.is_empty() {
+        log::warn!("[{}] Reused tx IDs: {:?}", addr, summary.reused_tx_ids);
+    }
+
+    log::info!("[{}] Sending account states...", addr);
+
+    let mut output = Vec::new();
+    let mut accounts = engine.snapshot_accounts(config.sort_order).await;
+    if let Some(touched) = &touched_clients {
+        accounts.retain(|account| touched.contains(&account.client));
+    }
+    write_accounts(&mut output, &accounts, &config)?;
+
+    writer.write_all(&output).await?;
+    writer.flush().await?;
+
+    log::info!("[{}] Response sent successfully", addr);
+
+    Ok(())
+}
+
+async fn read_request(
+    socket: &mut TcpStream,
+    config: &ProcessorConfig,
+    addr: SocketAddr,
+) -> Result<(Request, Option<OutputFormat>)> {
+    let mut buffer = Vec::new();
+
+    let read = async {
+        // Probes keep their write half open, so look at the first line before
+        // waiting for EOF
+        let mut chunk = [0u8; PROBE_SCAN_BYTES];
+        while !buffer.contains(&b'\n') && buffer.len() < PROBE_SCAN_BYTES {
+            let n = socket.read(&mut chunk[..PROBE_SCAN_BYTES - buffer.len()]).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+            // Binary records aren't line-based, so there is no line to wait for
+            if buffer[0] == binary_protocol::PROTOCOL_MARKER {
+                break;
+            }
+        }
+        if let Some(probe) = HealthProbe::detect(&buffer) {
+            return Ok(Some(probe));
+        }
+        if config.stream_requests {
+            return Ok(None);
+        }
+
+        match config.max_request_bytes {
+            // Read one byte past the limit so an oversized request is detectable
+            Some(max) => {
+                let remaining = (max + 1).saturating_sub(buffer.len());
+                socket.take(remaining as u64).read_to_end(&mut buffer).await?;
+            }
+            None => {
+                socket.read_to_end(&mut buffer).await?;
+            }
+        }
+        Ok::<_, std::io::Error>(None)
+    };
+
+    let probe = match config.read_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, read).await {
+            Ok(result) => result?,
+            Err(_) => {
+                log::warn!("[{}] Read timed out after {:?}", addr, timeout);
+                return Err(PaymentError::RequestTimeout(timeout));
+            }
+        },
+        None => read.await?,
+    };
+    if let Some(probe) = probe {
+        return Ok((Request::Probe(probe), None));
+    }
+    let format = take_format_line(&mut buffer)?;
+    if let (false, Some(max)) = (config.stream_requests, config.max_request_bytes) {
+        if buffer.len() > max {
+            log::warn!("[{}] Request exceeds {} byte limit", addr, max);
+            return Err(PaymentError::RequestTooLarge(max));
+        }
+    }
+
+    if buffer.first() == Some(&binary_protocol::PROTOCOL_MARKER) {
+        buffer.remove(0);
+        return Ok((Request::Binary(buffer), format));
+    }
+    if config.stream_requests {
+        return Ok((Request::Stream(buffer), format));
+    }
+    Ok((Request::Batch(buffer), format))
+}
+
+/// Strips a leading `FORMAT csv|json` line, returning the requested format.
+fn take_format_line(buffer: &mut Vec<u8>) -> Result<Option<OutputFormat>> {
+    let Some(end) = buffer.iter().position(|&b| b == b'\n') else {
+        return Ok(None);
+    };
+    let Some(name) = buffer[..end].strip_prefix(FORMAT_PREFIX) else {
+        return Ok(None);
+    };
+    let format = String::from_utf8_lossy(name).trim().parse()?;
+    buffer.drain(..=end);
+    Ok(Some(format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::transaction::Trx;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    async fn connect_pair() -> (TcpStream, TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, addr) = listener.accept().await.unwrap();
+        (client, server, addr)
+    }
+
+    #[tokio::test]
+    async fn test_connection_returns_accounts() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+
+        client.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\n").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        handle_connection(server, engine, ProcessorConfig::new(), addr, None).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains("1,10.0000,0.0000,10.0000,false"));
+    }
+
+    #[tokio::test]
+    async fn test_health_probes_answered_without_eof() {
+        let engine = Arc::new(PaymentsEngine::new());
+        let probes: [(&[u8], &str); 2] = [
+            (b"PING\n", "PONG\n"),
+            (b"GET /health HTTP/1.1\r\nHost: lb\r\n\r\n", "HTTP/1.1 200 OK\r\n"),
+        ];
+        for (probe, expected) in probes {
+            let (mut client, server, addr) = connect_pair().await;
+            // The write half stays open, as it would for a load balancer
+            client.write_all(probe).await.unwrap();
+
+            let config = ProcessorConfig::new().with_read_timeout(Some(Duration::from_secs(5)));
+            handle_connection(server, engine.clone(), config, addr, None).await.unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(expected), "unexpected response {:?}", response);
+        }
+        assert!(engine.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_request_returns_accounts() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new().with_stream_requests(true);
+
+        // Larger than both socket buffers, so the server must read while the
+        // client is still writing
+        let writer = tokio::spawn(async move {
+            client.write_all(b"type,client,tx,amount\n").await.unwrap();
+            for tx in 1..=50_000u32 {
+                let row = format!("deposit,{},{},2.0\n", tx % 3, tx);
+                client.write_all(row.as_bytes()).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        });
+
+        handle_connection(server, engine.clone(), config, addr, None).await.unwrap();
+
+        let response = writer.await.unwrap();
+        assert!(response.contains("0,33332.0000,0.0000,33332.0000,false"));
+        assert!(response.contains("1,33334.0000,0.0000,33334.0000,false"));
+        assert_eq!(engine.get_accounts().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_acks_precede_account_output() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new()
+            .with_stream_requests(true)
+            .with_ack_interval(Some(2));
+
+        client.write_all(b"type,client,tx,amount\n").await.unwrap();
+        for tx in 1..=5 {
+            client.write_all(format!("deposit,1,{},1.0\n", tx).as_bytes()).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+
+        handle_connection(server, engine, config, addr, None).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "ACK 2\nACK 4\nclient,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_acks_sent_while_streaming() {
+        use tokio::io::AsyncBufReadExt;
+
+        let (client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new()
+            .with_stream_requests(true)
+            .with_ack_interval(Some(1));
+        let handler = tokio::spawn(handle_connection(server, engine, config, addr, None));
+
+        // The ack arrives before the client has finished sending
+        let (read_half, mut write_half) = client.into_split();
+        let mut lines = tokio::io::BufReader::new(read_half).lines();
+        write_half.write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n").await.unwrap();
+        let ack = tokio::time::timeout(Duration::from_secs(5), lines.next_line()).await;
+        assert_eq!(ack.unwrap().unwrap().as_deref(), Some("ACK 1"));
+
+        write_half.shutdown().await.unwrap();
+        handler.await.unwrap().unwrap();
+        let header = lines.next_line().await.unwrap();
+        assert_eq!(header.as_deref(), Some("client,available,held,total,locked"));
+    }
+
+    #[tokio::test]
+    async fn test_empty_engine_returns_header_only() {
+        let requests: [(&[u8], bool); 5] = [
+            (b"", false),
+            (b"type,client,tx,amount\n", false),
+            (b"type,client,tx,amount\ndeposit,x,1,1.0\n", false),
+            (b"", true),
+            (b"type,client,tx,amount\ndeposit,x,1,1.0\n", true),
+        ];
+        for (request, stream) in requests {
+            let (mut client, server, addr) = connect_pair().await;
+            let engine = Arc::new(PaymentsEngine::new());
+            let config = ProcessorConfig::new().with_stream_requests(stream);
+
+            client.write_all(request).await.unwrap();
+            client.shutdown().await.unwrap();
+            handle_connection(server, engine, config, addr, None).await.unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert_eq!(response, "client,available,held,total,locked\n", "request {:?}", request);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_rate_limit_drops_burst() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new().with_client_rate_limit(Some(5));
+
+        let mut request = String::from("type,client,tx,amount\n");
+        for tx in 1..=20 {
+            request.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        request.push_str("deposit,2,100,1.0\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        handle_connection(server, engine.clone(), config, addr, None).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "client,available,held,total,locked\n\
+             1,5.0000,0.0000,5.0000,false\n\
+             2,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_isolated_connections_do_not_share_state() {
+        let shared = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new().with_isolated_connections(true);
+
+        let requests = [
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+            // Same tx ID would be a duplicate in a shared engine
+            "type,client,tx,amount\ndeposit,2,1,3.0\n",
+        ];
+        let mut responses = Vec::new();
+        for request in requests {
+            let (mut client, server, addr) = connect_pair().await;
+            client.write_all(request.as_bytes()).await.unwrap();
+            client.shutdown().await.unwrap();
+            handle_connection(server, Arc::clone(&shared), config.clone(), addr, None).await.unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            responses.push(response);
+        }
+
+        assert_eq!(responses[0], "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n");
+        assert_eq!(responses[1], "client,available,held,total,locked\n2,3.0000,0.0000,3.0000,false\n");
+        assert!(shared.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delta_output_lists_only_touched_clients() {
+        let engine = Arc::new(PaymentsEngine::new());
+        for client in 1..=10u16 {
+            engine.process(Trx::Deposit {
+                client,
+                tx: client.into(),
+                amount: dec!(10.0),
+            }).await;
+        }
+
+        let (mut client, server, addr) = connect_pair().await;
+        // Client 5's withdrawal is rejected, so only 3 and 7 changed
+        client
+            .write_all(b"type,client,tx,amount\ndeposit,3,100,1.0\nwithdrawal,7,101,2.0\nwithdrawal,5,102,99.0\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let config = ProcessorConfig::new().with_delta_output(true);
+        handle_connection(server, engine, config, addr, None).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "client,available,held,total,locked\n\
+             3,11.0000,0.0000,11.0000,false\n\
+             7,8.0000,0.0000,8.0000,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_format_line_selects_json_response() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+
+        client
+            .write_all(b"FORMAT json\ntype,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,2.5\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        handle_connection(server, engine.clone(), ProcessorConfig::new(), addr, None).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            concat!(
+                r#"[{"client":1,"available":"10.0000","held":"0.0000","total":"10.0000","locked":false,"#,
+                r#""tx_count":1,"open_disputes":0,"frozen":false},"#,
+                r#"{"client":2,"available":"2.5000","held":"0.0000","total":"2.5000","locked":false,"#,
+                r#""tx_count":1,"open_disputes":0,"frozen":false}]"#,
+                "\n"
+            )
+        );
+
+        // Without the line the response stays CSV
+        let (mut client, server, addr) = connect_pair().await;
+        client.write_all(b"type,client,tx,amount\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        handle_connection(server, engine, ProcessorConfig::new(), addr, None).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("client,available,held,total,locked\n"));
+    }
+
+    #[tokio::test]
+    async fn test_binary_protocol_request() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+
+        let mut payload = vec![binary_protocol::PROTOCOL_MARKER];
+        payload.extend_from_slice(&binary_protocol::encode(&Trx::Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.0),
+        }));
+        payload.extend_from_slice(&binary_protocol::encode(&Trx::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(4.25),
+        }));
+        // Client 10 encodes as 0x000A, which must not be taken for a line break
+        payload.extend_from_slice(&binary_protocol::encode(&Trx::Deposit {
+            client: 10,
+            tx: 10,
+            amount: dec!(1.0),
+        }));
+        client.write_all(&payload).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        handle_connection(server, engine, ProcessorConfig::new(), addr, None).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "client,available,held,total,locked\n\
+             1,5.7500,0.0000,5.7500,false\n\
+             10,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_rejected() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new().with_max_request_bytes(Some(64));
+
+        let mut payload = String::from("type,client,tx,amount\n");
+        for tx in 1..=20 {
+            payload.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        client.write_all(payload.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let result = handle_connection(server, engine.clone(), config, addr, None).await;
+        assert!(matches!(result, Err(PaymentError::RequestTooLarge(64))));
+        assert!(engine.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_binary_request_rejected() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new().with_max_request_bytes(Some(64));
+
+        let mut payload = vec![binary_protocol::PROTOCOL_MARKER];
+        for tx in 1..=5 {
+            payload.extend_from_slice(&binary_protocol::encode(&Trx::Deposit {
+                client: 1,
+                tx,
+                amount: dec!(1.0),
+            }));
+        }
+        client.write_all(&payload).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let result = handle_connection(server, engine.clone(), config, addr, None).await;
+        assert!(matches!(result, Err(PaymentError::RequestTooLarge(64))));
+        assert!(engine.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_times_out() {
+        let (mut client, server, addr) = connect_pair().await;
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new().with_read_timeout(Some(Duration::from_millis(50)));
+
+        // Never shut down the write half, so the server keeps waiting for EOF
+        client.write_all(b"type,client,tx,amount\n").await.unwrap();
+
+        let result = handle_connection(server, engine, config, addr, None).await;
+        assert!(matches!(result, Err(PaymentError::RequestTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_ordered_dispatch_is_deterministic() {
+        for _ in 0..5 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            let engine = Arc::new(PaymentsEngine::new());
+            let config = ProcessorConfig::new()
+                .with_ordered_dispatch(true)
+                .with_read_timeout(Some(Duration::from_secs(5)));
+            let server = tokio::spawn(serve(listener, engine.clone(), config));
+
+            // Both connections race on tx 1; the first to connect sends last
+            let mut first = TcpStream::connect(server_addr).await.unwrap();
+            let mut second = TcpStream::connect(server_addr).await.unwrap();
+
+            second.write_all(b"type,client,tx,amount\ndeposit,2,1,20.0\n").await.unwrap();
+            second.shutdown().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            first.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\n").await.unwrap();
+            first.shutdown().await.unwrap();
+
+            let mut response = String::new();
+            second.read_to_string(&mut response).await.unwrap();
+            first.read_to_string(&mut String::new()).await.unwrap();
+            server.abort();
+
+            let accounts = engine.get_accounts();
+            assert_eq!(accounts.len(), 1);
+            assert_eq!(accounts[0].client, 1);
+            assert_eq!(accounts[0].total, dec!(10.0));
+            assert!(response.contains("1,10.0000"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_open_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let engine = Arc::new(PaymentsEngine::new());
+        let (stop, stopped) = oneshot::channel::<()>();
+        let shutdown = async {
+            let _ = stopped.await;
+        };
+        let server = tokio::spawn(serve_until(listener, engine.clone(), ProcessorConfig::new(), shutdown));
+
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        client.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!server.is_finished());
+        assert!(TcpStream::connect(server_addr).await.is_err());
+
+        // The open connection still completes before serve_until returns
+        client.shutdown().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap().unwrap();
+        assert!(response.contains("1,10.0000"));
+        assert_eq!(engine.get_accounts()[0].total, dec!(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_ordered_dispatch_requires_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ProcessorConfig::new().with_ordered_dispatch(true);
+        let result = serve(listener, Arc::new(PaymentsEngine::new()), config).await;
+        assert!(matches!(result, Err(PaymentError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_connection_releases_turn_on_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let engine = Arc::new(PaymentsEngine::new());
+        let config = ProcessorConfig::new()
+            .with_ordered_dispatch(true)
+            .with_read_timeout(Some(Duration::from_millis(100)));
+        let server = tokio::spawn(serve(listener, engine.clone(), config));
+
+        // The first connection never finishes its request
+        let _stalled = TcpStream::connect(server_addr).await.unwrap();
+        let mut later = TcpStream::connect(server_addr).await.unwrap();
+        later.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\n").await.unwrap();
+        later.shutdown().await.unwrap();
+
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), later.read_to_string(&mut response))
+            .await
+            .expect("later connection stayed blocked")
+            .unwrap();
+        server.abort();
+        assert!(response.contains("1,10.0000"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_reject_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let config = ProcessorConfig::new()
+            .with_max_connections(Some(1))
+            .with_connection_limit_policy(ConnectionLimitPolicy::Reject);
+        let server = tokio::spawn(serve(listener, Arc::new(PaymentsEngine::new()), config));
+
+        // Holds the only slot until it shuts down its write half
+        let mut held = TcpStream::connect(server_addr).await.unwrap();
+        held.write_all(b"type,client,tx,amount\n").await.unwrap();
+
+        let mut excess = TcpStream::connect(server_addr).await.unwrap();
+        let mut response = String::new();
+        excess.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "ERROR server busy\n");
+
+        held.write_all(b"deposit,1,1,1.0\n").await.unwrap();
+        held.shutdown().await.unwrap();
+        let mut response = String::new();
+        held.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains("1,1.0000"));
+
+        // The slot is released once the handler finishes
+        let mut response = String::new();
+        for _ in 0..50 {
+            let mut retry = TcpStream::connect(server_addr).await.unwrap();
+            retry.write_all(b"type,client,tx,amount\n").await.unwrap();
+            retry.shutdown().await.unwrap();
+            response.clear();
+            retry.read_to_string(&mut response).await.unwrap();
+            if !response.starts_with("ERROR") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(response.starts_with("client,available"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_wait_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let config = ProcessorConfig::new().with_max_connections(Some(1));
+        let server = tokio::spawn(serve(listener, Arc::new(PaymentsEngine::new()), config));
+
+        let mut held = TcpStream::connect(server_addr).await.unwrap();
+        held.write_all(b"type,client,tx,amount\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let mut waiting = TcpStream::connect(server_addr).await.unwrap();
+        waiting.write_all(b"type,client,tx,amount\ndeposit,2,2,2.0\n").await.unwrap();
+        waiting.shutdown().await.unwrap();
+        let mut response = String::new();
+        let early =
+            tokio::time::timeout(Duration::from_millis(100), waiting.read_to_string(&mut response))
+                .await;
+        assert!(early.is_err(), "second connection served while the limit was reached");
+
+        held.shutdown().await.unwrap();
+        held.read_to_string(&mut String::new()).await.unwrap();
+        waiting.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains("2,2.0000"));
+        server.abort();
+    }
+}
@@ -1,13 +1,146 @@
-use crate::config::ProcessorConfig;
+use crate::config::{AmountLocale, InputFormat, OutputFormat, ProcessorConfig};
 use crate::error::{PaymentError, Result};
 use crate::services::payment_engine::PaymentsEngine;
-use crate::domain::transaction::{RawTrxRecord, Trx};
+use crate::domain::rejection::{Rejection, RejectionSummary};
+use crate::domain::transaction::{RawTrxRecord, Trx, TrxType};
+use crate::domain::user_account::{OutputColumn, UserAccount};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessProgress {
+    pub rows: usize,
+    pub errors: usize,
+}
+
+pub type ProgressCallback = Box<dyn FnMut(ProcessProgress) + Send>;
+
+/// Optional controls for a processing run.
+#[derive(Default)]
+pub struct RunHooks {
+    pub cancel: CancellationToken,
+    /// Invoked every `interval` rows
+    pub progress: Option<(usize, ProgressCallback)>,
+    /// Skips records applied by an earlier run and records progress
+    pub resume: Option<ResumeMarker>,
+    /// Drops records of clients sending faster than the limiter allows
+    pub rate_limit: Option<ClientRateLimiter>,
+    /// When set, collects the clients of every applied transaction
+    pub touched_clients: Option<BTreeSet<u16>>,
+}
+
+/// Per-client token buckets holding up to `rate` tokens and refilling at
+/// `rate` per second; each transaction takes one.
+#[derive(Debug, Clone)]
+pub struct ClientRateLimiter {
+    rate: f64,
+    buckets: HashMap<u16, (f64, Instant)>,
+}
+
+impl ClientRateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        ClientRateLimiter {
+            rate: per_second as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn allow(&mut self, client: u16) -> bool {
+        self.allow_at(client, Instant::now())
+    }
+
+    fn allow_at(&mut self, client: u16, now: Instant) -> bool {
+        let (tokens, last) = self.buckets.entry(client).or_insert((self.rate, now));
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.rate);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Records between resume marker saves.
+const RESUME_CHECKPOINT_INTERVAL: usize = 1000;
+
+/// Sidecar file holding how many input records a run has applied, and from
+/// which input file, so an interrupted job can skip them when re-run. Skipped
+/// records only count if the engine's state survived too, e.g. via a
+/// write-ahead log. Saved every `RESUME_CHECKPOINT_INTERVAL` records and when
+/// the run ends, so after a crash up to that many records are applied again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeMarker {
+    path: PathBuf,
+    offset: usize,
+    /// Canonical path of the input the offset counts into
+    input: Option<PathBuf>,
+}
+
+impl ResumeMarker {
+    /// Loads the offset stored at `path`, starting from zero if it doesn't exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let invalid = || PaymentError::InvalidConfig(format!("invalid resume offset in {}", path.display()));
+        let (offset, input) = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let mut lines = contents.lines();
+                let offset = lines.next().unwrap_or_default().trim().parse().map_err(|_| invalid())?;
+                (offset, lines.next().filter(|line| !line.is_empty()).map(PathBuf::from))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ResumeMarker { path, offset, input })
+    }
+
+    /// Records (header excluded) already applied.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Ties the marker to `input`, failing if its offset counts into another
+    /// file.
+    fn bind(&mut self, input: &str) -> Result<()> {
+        let input = std::fs::canonicalize(input)?;
+        match &self.input {
+            Some(bound) if *bound != input && self.offset > 0 => Err(PaymentError::InvalidConfig(format!(
+                "resume marker {} belongs to {}, not {}",
+                self.path.display(),
+                bound.display(),
+                input.display()
+            ))),
+            _ => {
+                self.input = Some(input);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the offset via a temporary file, so a crash never leaves a torn marker.
+    pub fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let input = self.input.as_ref().map(|input| input.display().to_string()).unwrap_or_default();
+        std::fs::write(&tmp, format!("{}\n{}\n", self.offset, input))?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
 
 pub struct TrxProcessor {
     engine: PaymentsEngine,
     config: ProcessorConfig,
+    summary: RejectionSummary,
+    hooks: RunHooks,
 }
 
 impl TrxProcessor {
@@ -18,72 +151,948 @@ impl TrxProcessor {
     #[allow(dead_code)]
     pub fn with_config(config: ProcessorConfig) -> Self {
         TrxProcessor {
-            engine: PaymentsEngine::with_max_history(config.max_tx_history),
+            engine: PaymentsEngine::with_config(config.clone()),
             config,
+            summary: RejectionSummary::new(),
+            hooks: RunHooks::default(),
         }
     }
+
+    /// Processes into `engine`, under its config; e.g. one restored from a
+    /// write-ahead log.
+    #[allow(dead_code)]
+    pub fn with_engine(engine: PaymentsEngine) -> Self {
+        TrxProcessor {
+            config: engine.config().clone(),
+            engine,
+            summary: RejectionSummary::new(),
+            hooks: RunHooks::default(),
+        }
+    }
+
+    /// Processing stops between rows once `token` is cancelled.
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.hooks.cancel = token;
+        self
+    }
+
+    pub fn with_progress<F>(mut self, interval: usize, callback: F) -> Self
+    where
+        F: FnMut(ProcessProgress) + Send + 'static,
+    {
+        self.hooks.progress = Some((interval.max(1), Box::new(callback)));
+        self
+    }
+
+    /// Resumes from, and keeps updating, the offset in `marker`.
+    pub fn with_resume_marker(mut self, marker: ResumeMarker) -> Self {
+        self.hooks.resume = Some(marker);
+        self
+    }
 }
 
 impl Default for TrxProcessor {
     fn default() -> Self {
         let config = ProcessorConfig::default();
         TrxProcessor {
-            engine: PaymentsEngine::with_max_history(config.max_tx_history),
+            engine: PaymentsEngine::with_config(config.clone()),
             config,
+            summary: RejectionSummary::new(),
+            hooks: RunHooks::default(),
         }
     }
 }
 
 impl TrxProcessor {
     pub async fn process_file(&mut self, filepath: &str) -> Result<()> {
-        let file = File::open(filepath)
-            .map_err(|_| PaymentError::FileNotFound(filepath.to_string()))?;
-
-        let reader = BufReader::new(file);
-        let mut csv_reader = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .from_reader(reader);
-
-        for result in csv_reader.deserialize() {
-            match result {
-                Ok(raw_record) => {
-                    let raw: RawTrxRecord = raw_record;
-                    if let Some(tx) = Trx::from_raw(raw) {
-                        self.engine.process(tx).await;
-                    } else if self.config.log_warnings {
-                        log::warn!("Skipping transaction with missing amount");
+        let file = open_with_retry(filepath, &self.config).await?;
+        if let Some(marker) = self.hooks.resume.as_mut() {
+            marker.bind(filepath)?;
+        }
+        let reader = RetryReader::new(file, self.config.io_retries, self.config.io_retry_delay);
+
+        let format = self.config.input_format.resolve(filepath);
+        self.process_reader_as(BufReader::new(reader), format).await
+    }
+
+    pub async fn process_reader<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        self.process_reader_as(reader, self.config.input_format).await
+    }
+
+    async fn process_reader_as<R: BufRead>(&mut self, reader: R, format: InputFormat) -> Result<()> {
+        match format {
+            InputFormat::JsonLines => {
+                apply_jsonl(&self.engine, reader, &self.config, &mut self.summary, &mut self.hooks).await?
+            }
+            InputFormat::Auto | InputFormat::Csv => {
+                apply_csv(&self.engine, reader, &self.config, &mut self.summary, &mut self.hooks).await?
+            }
+        }
+        if self.config.log_warnings {
+            log::info!("Processed by type: {}", self.summary.type_breakdown());
+            if !self.summary.reused_tx_ids.is_empty() {
+                log::warn!(
+                    "{} tx ID(s) reused by the input: {:?}",
+                    self.summary.reused_tx_ids.len(),
+                    self.summary.reused_tx_ids
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> &RejectionSummary {
+        &self.summary
+    }
+
+    /// Records seen per transaction type, including rejected ones.
+    pub fn type_counts(&self) -> &BTreeMap<TrxType, usize> {
+        &self.summary.by_type
+    }
+
+    pub fn engine(&self) -> &PaymentsEngine {
+        &self.engine
+    }
+
+    pub fn into_engine(self) -> PaymentsEngine {
+        self.engine
+    }
+
+    /// Flushes the engine's sinks first, so a buffered log is never behind the
+    /// results written.
+    pub async fn write_results<W: Write>(&self, writer: W) -> Result<()> {
+        self.engine.flush().await?;
+        let accounts = self.engine.get_accounts_ordered(self.config.sort_order);
+        write_accounts(writer, &accounts, &self.config)
+    }
+
+    /// Writes account results followed by a `# accounts=.. total=..` trailer line.
+    pub async fn write_results_with_summary<W: Write>(&self, mut writer: W) -> Result<()> {
+        self.write_results(&mut writer).await?;
+        writeln!(writer, "# {}", self.engine.aggregate_summary())?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Parses CSV transactions from `reader` and applies them to `engine`,
+/// recording every outcome in `summary`.
+pub async fn apply_csv<R: BufRead>(
+    engine: &PaymentsEngine,
+    reader: R,
+    config: &ProcessorConfig,
+    summary: &mut RejectionSummary,
+    hooks: &mut RunHooks,
+) -> Result<()> {
+    let records = csv_records(reader, config)?;
+    apply_records(engine, records, config, summary, hooks).await
+}
+
+/// Parses newline-delimited JSON transactions (one `RawTrxRecord` object per
+/// line) from `reader` and applies them to `engine`. Blank lines are ignored.
+pub async fn apply_jsonl<R: BufRead>(
+    engine: &PaymentsEngine,
+    reader: R,
+    config: &ProcessorConfig,
+    summary: &mut RejectionSummary,
+    hooks: &mut RunHooks,
+) -> Result<()> {
+    let records = jsonl_records(reader, config)?;
+    apply_records(engine, records, config, summary, hooks).await
+}
+
+fn csv_records<R: BufRead>(
+    mut reader: R,
+    config: &ProcessorConfig,
+) -> Result<impl Iterator<Item = Result<RawTrxRecord>>> {
+    skip_bom(&mut reader)?;
+    let mut csv_reader = config.csv_reader_builder().from_reader(reader);
+    let headers = csv_reader.headers().ok().cloned();
+    let column = |name: &str| headers.as_ref().and_then(|headers| headers.iter().position(|h| h == name));
+    let locale = config.amount_locale;
+    let amount_index = column("amount").filter(|_| locale != AmountLocale::Plain);
+    let type_index = column("type").filter(|_| config.case_insensitive_types);
+    let lossy = config.lossy_utf8 && config.skip_malformed;
+    let (mut bytes, mut record) = (csv::ByteRecord::new(), csv::StringRecord::new());
+
+    Ok(std::iter::from_fn(move || {
+        let result = match csv_reader.read_byte_record(&mut bytes) {
+            Ok(false) => return None,
+            Ok(true) => {
+                if let Err(e) = decode_record(&bytes, &mut record, 0, lossy) {
+                    let line = bytes.position().map_or(0, |p| p.line());
+                    return Some(Err(PaymentError::MalformedRow { line, source: Box::new(e) }));
+                }
+                let parsed = match amount_index {
+                    Some(index) => localize_amount(&record, index, locale).deserialize::<RawTrxRecord>(headers.as_ref()),
+                    None => record.deserialize::<RawTrxRecord>(headers.as_ref()),
+                };
+                parsed.map(|raw| relax_type(raw, type_index.and_then(|index| record.get(index))))
+            }
+            Err(e) => Err(e),
+        };
+        Some(result.map_err(|e| {
+            // Keep the IO error's kind, so callers can tell transient failures apart
+            if let csv::ErrorKind::Io(err) = e.kind() {
+                return PaymentError::IoError(std::io::Error::new(err.kind(), e));
+            }
+            match e.position().map(|position| position.line()) {
+                Some(line) => PaymentError::MalformedRow {
+                    line,
+                    source: Box::new(PaymentError::CsvError(e)),
+                },
+                None => PaymentError::CsvError(e),
+            }
+        }))
+    }))
+}
+
+/// Copies `bytes` into `record`, failing on the first field that isn't valid
+/// UTF-8, or with `lossy` replacing invalid sequences with U+FFFD. `base` is
+/// added to the row's byte offset for input read in chunks.
+fn decode_record(bytes: &csv::ByteRecord, record: &mut csv::StringRecord, base: u64, lossy: bool) -> Result<()> {
+    record.clear();
+    record.set_position(bytes.position().cloned());
+    for (index, field) in bytes.iter().enumerate() {
+        match std::str::from_utf8(field) {
+            Ok(field) => record.push_field(field),
+            Err(_) if lossy => record.push_field(&String::from_utf8_lossy(field)),
+            Err(_) => {
+                let offset = base + bytes.position().map_or(0, |p| p.byte());
+                return Err(PaymentError::InvalidUtf8 { offset, field: index + 1 });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn localize_amount(record: &csv::StringRecord, index: usize, locale: AmountLocale) -> csv::StringRecord {
+    let mut localized: csv::StringRecord = record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| if i == index { locale.normalize(field) } else { field.to_string() })
+        .collect();
+    localized.set_position(record.position().cloned());
+    localized
+}
+
+/// Retries an unrecognised type lowercased, given the raw `type` field when
+/// `case_insensitive_types` is on. Exact-case rows never pay for this.
+fn relax_type(mut raw: RawTrxRecord, type_field: Option<&str>) -> RawTrxRecord {
+    if let (TrxType::Unknown, Some(name)) = (raw.tx_type, type_field) {
+        raw.tx_type = TrxType::from_name(&name.to_ascii_lowercase());
+    }
+    raw
+}
+
+#[derive(serde::Deserialize)]
+struct TypeField {
+    #[serde(rename = "type")]
+    tx_type: String,
+}
+
+/// Reads one JSON object per line, skipping blank lines. A line that isn't
+/// valid UTF-8 is malformed, or with `lossy_utf8` has invalid sequences
+/// replaced, as `decode_record` does for CSV.
+fn jsonl_records<R: BufRead>(
+    mut reader: R,
+    config: &ProcessorConfig,
+) -> Result<impl Iterator<Item = Result<RawTrxRecord>>> {
+    skip_bom(&mut reader)?;
+    let case_insensitive_types = config.case_insensitive_types;
+    let lossy = config.lossy_utf8 && config.skip_malformed;
+    let mut buffer = Vec::new();
+    let (mut number, mut offset) = (0u64, 0u64);
+    Ok(std::iter::from_fn(move || loop {
+        buffer.clear();
+        let read = match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) => return None,
+            Ok(read) => read,
+            Err(e) => return Some(Err(e.into())),
+        };
+        number += 1;
+        let start = offset;
+        offset += read as u64;
+
+        let line = match std::str::from_utf8(&buffer) {
+            Ok(line) => Cow::Borrowed(line),
+            Err(_) if lossy => String::from_utf8_lossy(&buffer),
+            Err(_) => {
+                return Some(Err(PaymentError::MalformedRow {
+                    line: number,
+                    source: Box::new(PaymentError::InvalidUtf8 { offset: start, field: 1 }),
+                }));
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = serde_json::from_str::<RawTrxRecord>(&line).and_then(|raw| {
+            if !case_insensitive_types || raw.tx_type != TrxType::Unknown {
+                return Ok(raw);
+            }
+            let TypeField { tx_type } = serde_json::from_str(&line)?;
+            Ok(relax_type(raw, Some(&tx_type)))
+        });
+        return Some(parsed.map_err(|e| PaymentError::MalformedRow {
+            line: number,
+            source: Box::new(PaymentError::JsonError(e)),
+        }));
+    }))
+}
+
+/// Applies parsed records in order. Parse errors are skipped or returned per
+/// `skip_malformed`; I/O errors always abort. Stops with
+/// `PaymentError::Cancelled` before the next record once `hooks.cancel` fires.
+async fn apply_records<I>(
+    engine: &PaymentsEngine,
+    records: I,
+    config: &ProcessorConfig,
+    summary: &mut RejectionSummary,
+    hooks: &mut RunHooks,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<RawTrxRecord>>,
+{
+    let mut progress = ProcessProgress { rows: 0, errors: 0 };
+    let skip = hooks.resume.as_ref().map_or(0, ResumeMarker::offset);
+    if skip > 0 {
+        log::info!("Resuming after {} already applied records", skip);
+    }
+
+    let outcome = async {
+        for result in records.skip(skip) {
+            apply_record(engine, result, config, summary, hooks, &mut progress).await?;
+            if let Some(marker) = hooks.resume.as_mut() {
+                marker.offset += 1;
+                if marker.offset.is_multiple_of(RESUME_CHECKPOINT_INTERVAL) {
+                    marker.save()?;
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    // Save on failure and cancellation too, so a re-run starts where this one stopped
+    if let Some(marker) = hooks.resume.as_ref() {
+        marker.save()?;
+    }
+    outcome
+}
+
+pub(crate) async fn apply_record(
+    engine: &PaymentsEngine,
+    result: Result<RawTrxRecord>,
+    config: &ProcessorConfig,
+    summary: &mut RejectionSummary,
+    hooks: &mut RunHooks,
+    progress: &mut ProcessProgress,
+) -> Result<()> {
+    if hooks.cancel.is_cancelled() {
+        log::warn!("Processing cancelled after {} rows", progress.rows);
+        return Err(PaymentError::Cancelled);
+    }
+
+    let rejected_before = summary.total_rejected();
+
+    match result
+        .and_then(|raw| known_type(raw, config))
+        .map(|raw| default_amount(raw, config))
+        .map(|raw| signed_amount(raw, config))
+        .and_then(|raw| normalize_amount(raw, config))
+        .and_then(|raw| offset_client(raw, config))
+    {
+        Ok(raw) => {
+            summary.record_type(raw.tx_type);
+            let (raw_tx, currency, timestamp) = (raw.tx, raw.currency.unwrap_or_default(), raw.timestamp);
+            let outcome = if raw.tx_type == TrxType::Unknown {
+                if config.log_warnings {
+                    log::warn!("Skipping tx {} with unknown transaction type", raw.tx);
+                }
+                Err(Rejection::UnknownType)
+            } else if hooks.rate_limit.as_mut().is_some_and(|limiter| !limiter.allow(raw.client)) {
+                if config.log_warnings {
+                    log::warn!("Skipping tx {}: client {} over its rate limit", raw.tx, raw.client);
+                }
+                Err(Rejection::RateLimited)
+            } else if let Some(tx) = Trx::from_raw(raw) {
+                let client = tx.client();
+                match engine.try_process_at(tx, currency, timestamp).await {
+                    Err(Rejection::InvariantViolated) => return Err(PaymentError::InvariantViolation(client)),
+                    outcome => {
+                        if let (Ok(()), Some(touched)) = (outcome, hooks.touched_clients.as_mut()) {
+                            touched.insert(client);
+                        }
+                        outcome
                     }
                 }
-                Err(e) => {
-                    if self.config.skip_malformed {
-                        if self.config.log_warnings {
-                            log::warn!("Skipping malformed row: {}", e);
+            } else {
+                if config.log_warnings {
+                    log::warn!("Skipping transaction with missing amount");
+                }
+                Err(Rejection::MissingAmount)
+            };
+            summary.record_tx(raw_tx, outcome);
+            if let (true, Err(rejection)) = (config.fail_fast, outcome) {
+                return Err(PaymentError::Rejected { tx: raw_tx, rejection });
+            }
+        }
+        Err(e @ PaymentError::IoError(_)) => return Err(e),
+        Err(e) => {
+            if config.skip_malformed && !config.fail_fast {
+                if config.log_warnings {
+                    log::warn!("Skipping malformed row: {}", e);
+                }
+                summary.reject(Rejection::Malformed);
+            } else {
+                return Err(e);
+            }
+        }
+    }
+
+    progress.rows += 1;
+    progress.errors += summary.total_rejected() - rejected_before;
+    if let Some((interval, callback)) = hooks.progress.as_mut() {
+        if progress.rows.is_multiple_of(*interval) {
+            callback(*progress);
+        }
+    }
+
+    Ok(())
+}
+
+/// Fills in `default_amount` for deposits and withdrawals that lack an amount.
+/// Without `skip_malformed`, an unknown type fails the row like any other
+/// malformed input rather than being skipped as `Rejection::UnknownType`.
+fn known_type(raw: RawTrxRecord, config: &ProcessorConfig) -> Result<RawTrxRecord> {
+    if raw.tx_type == TrxType::Unknown && !config.skip_malformed {
+        return Err(PaymentError::InvalidTransaction(format!("tx {} has an unknown type", raw.tx)));
+    }
+    Ok(raw)
+}
+
+fn default_amount(mut raw: RawTrxRecord, config: &ProcessorConfig) -> RawTrxRecord {
+    if raw.amount.is_none() && matches!(raw.tx_type, TrxType::Deposit | TrxType::Withdrawal) {
+        raw.amount = config.default_amount;
+    }
+    raw
+}
+
+/// With `signed_amounts`, flips negative deposits into withdrawals and negative
+/// withdrawals into deposits, keeping the absolute amount.
+fn signed_amount(mut raw: RawTrxRecord, config: &ProcessorConfig) -> RawTrxRecord {
+    let Some(amount) = raw.amount.filter(|a| config.signed_amounts && a.is_sign_negative() && !a.is_zero()) else {
+        return raw;
+    };
+    raw.tx_type = match raw.tx_type {
+        TrxType::Deposit => TrxType::Withdrawal,
+        TrxType::Withdrawal => TrxType::Deposit,
+        _ => return raw,
+    };
+    raw.amount = Some(amount.abs());
+    raw
+}
+
+/// Rounds the amount to `decimal_precision`, or with `reject_excess_precision`
+/// fails amounts that would need rounding.
+fn normalize_amount(mut raw: RawTrxRecord, config: &ProcessorConfig) -> Result<RawTrxRecord> {
+    if let Some(amount) = raw.amount {
+        let scale = amount.normalize().scale();
+        if config.reject_excess_precision && scale > config.decimal_precision {
+            return Err(PaymentError::PrecisionExceeded { tx: raw.tx, scale });
+        }
+        raw.amount = Some(config.round_amount(amount));
+    }
+    Ok(raw)
+}
+
+/// Shifts the client ID by `client_id_offset`; IDs pushed past `u16::MAX` fail.
+fn offset_client(mut raw: RawTrxRecord, config: &ProcessorConfig) -> Result<RawTrxRecord> {
+    raw.client = raw.client.checked_add(config.client_id_offset).ok_or_else(|| {
+        PaymentError::InvalidTransaction(format!(
+            "client {} exceeds the ID range with offset {}",
+            raw.client, config.client_id_offset
+        ))
+    })?;
+    Ok(raw)
+}
+
+/// Bytes requested from the reader per read when streaming.
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+/// Longest line accepted when streaming; bounds the carried-over partial line.
+pub const MAX_STREAM_LINE_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    pub bytes_read: u64,
+    /// Most input bytes held in memory at once
+    pub peak_buffered: usize,
+}
+
+/// Parses CSV from an async reader without buffering the whole input: each
+/// read is split at its last newline and the complete lines are applied before
+/// reading on. `config.read_timeout` applies to each read rather than the whole
+/// stream. Quoted fields spanning lines are not supported.
+pub async fn apply_csv_stream<R: AsyncRead + Unpin>(
+    engine: &PaymentsEngine,
+    mut reader: R,
+    config: &ProcessorConfig,
+    summary: &mut RejectionSummary,
+    hooks: &mut RunHooks,
+) -> Result<StreamStats> {
+    let mut stats = StreamStats::default();
+    let mut progress = ProcessProgress { rows: 0, errors: 0 };
+    let mut chunk = vec![0u8; STREAM_CHUNK_BYTES];
+    let mut pending = Vec::new();
+    let mut headers: Option<csv::StringRecord> = None;
+    let mut lines_before = 0u64;
+    let mut bytes_before = 0u64;
+    let lossy = config.lossy_utf8 && config.skip_malformed;
+    let mut bom_checked = false;
+
+    let mut builder = config.csv_reader_builder();
+    // Lengths are checked against the header below; each batch's reader only
+    // sees data rows
+    builder.has_headers(false).flexible(true);
+
+    loop {
+        let read = reader.read(&mut chunk);
+        let n = match config.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read)
+                .await
+                .map_err(|_| PaymentError::RequestTimeout(timeout))??,
+            None => read.await?,
+        };
+        stats.bytes_read += n as u64;
+        pending.extend_from_slice(&chunk[..n]);
+        stats.peak_buffered = stats.peak_buffered.max(pending.len());
+
+        if !bom_checked && (pending.len() >= UTF8_BOM.len() || n == 0) {
+            if pending.starts_with(UTF8_BOM) {
+                pending.drain(..UTF8_BOM.len());
+            }
+            bom_checked = true;
+        }
+
+        let complete = if n == 0 {
+            pending.len()
+        } else {
+            pending.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1)
+        };
+        if complete == 0 && pending.len() > MAX_STREAM_LINE_BYTES {
+            return Err(PaymentError::MalformedRow {
+                line: lines_before + 1,
+                source: Box::new(PaymentError::InvalidTransaction(format!(
+                    "line longer than {} bytes",
+                    MAX_STREAM_LINE_BYTES
+                ))),
+            });
+        }
+
+        if complete > 0 {
+            let mut records = builder.from_reader(&pending[..complete]).into_byte_records();
+            for bytes in records.by_ref() {
+                let result = match bytes {
+                    Ok(bytes) => {
+                        let line = lines_before + bytes.position().map_or(0, |p| p.line());
+                        let mut record = csv::StringRecord::new();
+                        if let Err(e) = decode_record(&bytes, &mut record, bytes_before, lossy) {
+                            Err(PaymentError::MalformedRow { line, source: Box::new(e) })
+                        } else if let Some(headers) = headers.as_ref() {
+                            parse_streamed_record(&record, headers, line, config)
+                        } else {
+                            headers = Some(record);
+                            continue;
                         }
-                    } else {
-                        return Err(PaymentError::CsvError(e));
+                    }
+                    Err(e) => Err(PaymentError::MalformedRow {
+                        line: lines_before + e.position().map_or(0, |p| p.line()),
+                        source: Box::new(PaymentError::CsvError(e)),
+                    }),
+                };
+                apply_record(engine, result, config, summary, hooks, &mut progress).await?;
+            }
+            lines_before += pending[..complete].iter().filter(|&&b| b == b'\n').count() as u64;
+            bytes_before += complete as u64;
+            pending.drain(..complete);
+        }
+
+        if n == 0 {
+            return Ok(stats);
+        }
+    }
+}
+
+fn parse_streamed_record(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    line: u64,
+    config: &ProcessorConfig,
+) -> Result<RawTrxRecord> {
+    let malformed = |source| PaymentError::MalformedRow { line, source: Box::new(source) };
+    if !config.flexible_columns && record.len() != headers.len() {
+        return Err(malformed(PaymentError::InvalidTransaction(format!(
+            "found record with {} fields, but the header has {}",
+            record.len(),
+            headers.len()
+        ))));
+    }
+    let type_field = headers
+        .iter()
+        .position(|h| h == "type")
+        .filter(|_| config.case_insensitive_types)
+        .and_then(|index| record.get(index));
+    record
+        .deserialize(Some(headers))
+        .map(|raw| relax_type(raw, type_field))
+        .map_err(|e| malformed(PaymentError::CsvError(e)))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    /// 1-based record number, excluding the header
+    pub row: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "row {}: {}", self.row, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub rows: usize,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Checks that every record parses (strictly, nothing is skipped) and that
+/// each dispute, resolve and chargeback references an earlier transaction in
+/// the same input. No balances are computed.
+pub fn validate_reader<R: BufRead>(
+    reader: R,
+    format: InputFormat,
+    config: &ProcessorConfig,
+) -> Result<ValidationReport> {
+    match format {
+        InputFormat::JsonLines => validate_records(jsonl_records(reader, config)?),
+        InputFormat::Auto | InputFormat::Csv => validate_records(csv_records(reader, config)?),
+    }
+}
+
+pub fn validate_file(filepath: &str, config: &ProcessorConfig) -> Result<ValidationReport> {
+    let file = File::open(filepath)
+        .map_err(|_| PaymentError::FileNotFound(filepath.to_string()))?;
+    validate_reader(BufReader::new(file), config.input_format.resolve(filepath), config)
+}
+
+fn validate_records<I>(records: I) -> Result<ValidationReport>
+where
+    I: Iterator<Item = Result<RawTrxRecord>>,
+{
+    let mut report = ValidationReport::default();
+    let mut seen = HashSet::new();
+
+    for result in records {
+        report.rows += 1;
+        let problem = match result {
+            Ok(raw) => {
+                let (kind, tx_type, tx) = (raw.tx_type, raw.tx_type.name(), raw.tx);
+                match Trx::from_raw(raw) {
+                    None if kind == TrxType::Unknown => Some(format!("malformed record: tx {} has an unknown type", tx)),
+                    None => Some(format!("{} tx {} is missing an amount", tx_type, tx)),
+                    Some(Trx::Deposit { .. } | Trx::Withdrawal { .. } | Trx::Fee { .. }) => {
+                        (!seen.insert(tx)).then(|| format!("duplicate tx {}", tx))
+                    }
+                    Some(Trx::Unlock { .. } | Trx::Freeze { .. } | Trx::Thaw { .. }) => None,
+                    Some(
+                        Trx::Dispute { .. }
+                        | Trx::Resolve { .. }
+                        | Trx::PartialResolve { .. }
+                        | Trx::Chargeback { .. },
+                    ) => {
+                        (!seen.contains(&tx))
+                            .then(|| format!("{} references unknown tx {}", tx_type, tx))
                     }
                 }
             }
+            Err(e @ PaymentError::IoError(_)) => return Err(e),
+            Err(e) => Some(format!("malformed record: {}", e)),
+        };
+        if let Some(message) = problem {
+            report.issues.push(ValidationIssue { row: report.rows, message });
+        }
+    }
+
+    Ok(report)
+}
+
+/// Processes independent files (no shared tx IDs) into separate engines on
+/// their own tasks, then merges them in the given order. Any cross-file tx ID
+/// collision fails the merge with `PaymentError::TxIdCollision`. With
+/// `config.deterministic` the files are processed one after another instead.
+pub async fn process_files_parallel(
+    paths: &[String],
+    config: &ProcessorConfig,
+) -> Result<PaymentsEngine> {
+    if config.deterministic {
+        let merged = PaymentsEngine::with_config(config.clone());
+        for path in paths {
+            let mut processor = TrxProcessor::with_config(config.clone());
+            processor.process_file(path).await?;
+            merged.merge(processor.into_engine()).await?;
         }
+        return Ok(merged);
+    }
 
-        Ok(())
+    let handles: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            let path = path.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let mut processor = TrxProcessor::with_config(config);
+                processor.process_file(&path).await?;
+                Ok::<_, PaymentError>(processor.into_engine())
+            })
+        })
+        .collect();
+
+    let merged = PaymentsEngine::with_config(config.clone());
+    for handle in handles {
+        let engine = handle
+            .await
+            .map_err(|e| PaymentError::InvalidTransaction(format!("file task failed: {}", e)))??;
+        merged.merge(engine).await?;
+    }
+
+    Ok(merged)
+}
+
+/// Applies an in-memory CSV document to `engine`, for embedding and tests.
+pub async fn process_csv_str(
+    engine: &PaymentsEngine,
+    csv: &str,
+    config: &ProcessorConfig,
+) -> Result<RejectionSummary> {
+    let mut summary = RejectionSummary::new();
+    apply_csv(engine, csv.as_bytes(), config, &mut summary, &mut RunHooks::default()).await?;
+    Ok(summary)
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt))
+}
+
+/// Opens `path`, retrying transient failures per `io_retries`. A missing file
+/// is reported immediately.
+async fn open_with_retry(path: &str, config: &ProcessorConfig) -> Result<File> {
+    let mut attempt = 0;
+    loop {
+        match File::open(path) {
+            Ok(file) => return Ok(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(PaymentError::FileNotFound(path.to_string()))
+            }
+            Err(e) if attempt < config.io_retries => {
+                let delay = backoff_delay(config.io_retry_delay, attempt);
+                log::warn!("Opening {} failed ({}), retrying in {:?}", path, e, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(PaymentError::IoError(e)),
+        }
     }
+}
 
-    pub fn write_results<W: Write>(&self, writer: W) -> Result<()> {
-        let mut csv_writer = csv::Writer::from_writer(writer);
+/// Retries each transiently failed read up to `retries` times with doubling
+/// delays; other errors are returned at once. The parsers read synchronously,
+/// so on a multi-threaded runtime the delay is taken with `block_in_place`,
+/// letting the worker's other tasks move elsewhere.
+struct RetryReader<R> {
+    inner: R,
+    retries: u32,
+    base_delay: Duration,
+}
 
-        for account in self.engine.get_accounts() {
-            csv_writer.serialize(&account)?;
+impl<R> RetryReader<R> {
+    fn new(inner: R, retries: u32, base_delay: Duration) -> Self {
+        Self {
+            inner,
+            retries,
+            base_delay,
         }
+    }
+}
 
-        csv_writer.flush()?;
-        Ok(())
+impl<R: Read> Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    let delay = backoff_delay(self.base_delay, attempt);
+                    log::warn!("Read failed ({}), retrying in {:?}", e, delay);
+                    pause(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn pause(delay: Duration) {
+    if delay.is_zero() {
+        return;
+    }
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| std::thread::sleep(delay))
+        }
+        _ => std::thread::sleep(delay),
+    }
+}
+
+/// Consumes a leading UTF-8 byte order mark, as written by Excel exports.
+pub fn skip_bom<R: BufRead>(reader: &mut R) -> Result<()> {
+    if reader.fill_buf()?.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+    Ok(())
+}
+
+/// Writes `accounts` in `config.output_format`. As CSV, a `currency` column is
+/// added after `client` when any account holds a non-default currency, and a
+/// `frozen` column after `locked` when any account is frozen, unless
+/// `output_columns` already lists them. The header row is written even when
+/// there are no accounts.
+pub fn write_accounts<W: Write>(
+    mut writer: W,
+    accounts: &[UserAccount],
+    config: &ProcessorConfig,
+) -> Result<()> {
+    if config.output_format == OutputFormat::Json {
+        serde_json::to_writer(&mut writer, accounts)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        return Ok(());
+    }
+
+    let mut csv_writer = config.csv_writer_builder().from_writer(ChecksumWriter { inner: writer, crc: !0 });
+
+    let precision = &config.output_precision;
+    let mut columns = config.output_columns.clone();
+    if !columns.contains(&OutputColumn::Currency)
+        && accounts.iter().any(|account| !account.currency.is_default())
+    {
+        let at = columns.iter().position(|&c| c == OutputColumn::Client).map_or(0, |i| i + 1);
+        columns.insert(at, OutputColumn::Currency);
+    }
+    if !columns.contains(&OutputColumn::Frozen) && accounts.iter().any(|account| account.frozen) {
+        let at = columns.iter().position(|&c| c == OutputColumn::Locked).map_or(columns.len(), |i| i + 1);
+        columns.insert(at, OutputColumn::Frozen);
+    }
+
+    csv_writer.write_record(columns.iter().map(|column| column.name()))?;
+    for account in accounts {
+        csv_writer.write_record(columns.iter().map(|&column| account.column_value_with(column, precision)))?;
+    }
+
+    csv_writer.flush()?;
+    if config.output_footer {
+        let mut checksum = csv_writer.into_inner().map_err(|e| PaymentError::IoError(e.into_error()))?;
+        let crc = checksum.crc32();
+        writeln!(checksum.inner, "{}{} crc32={:08x}", FOOTER_PREFIX, accounts.len(), crc)?;
+        checksum.inner.flush()?;
+    }
+    Ok(())
+}
+
+/// Starts the integrity footer line; `#` lets CSV readers treat it as a comment.
+pub const FOOTER_PREFIX: &str = "# count=";
+
+/// Checks an output written with `output_footer`: the last line must be the
+/// footer, and its count and CRC32 must match the rows before it. Returns the
+/// row count.
+pub fn verify_footer(output: &[u8]) -> Result<usize> {
+    let invalid = |reason: &str| PaymentError::FooterMismatch(reason.to_string());
+    let body_end = output[..output.len().saturating_sub(1)]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let (body, footer) = output.split_at(body_end);
+    let footer = std::str::from_utf8(footer).map_err(|_| invalid("footer is not UTF-8"))?.trim_end();
+    let (count, crc) = footer
+        .strip_prefix(FOOTER_PREFIX)
+        .and_then(|rest| rest.split_once(" crc32="))
+        .ok_or_else(|| invalid("no footer line"))?;
+    let count: usize = count.parse().map_err(|_| invalid("bad row count"))?;
+    let crc = u32::from_str_radix(crc, 16).map_err(|_| invalid("bad checksum"))?;
+
+    let mut checksum = ChecksumWriter { inner: std::io::sink(), crc: !0 };
+    checksum.write_all(body)?;
+    if checksum.crc32() != crc {
+        return Err(invalid("checksum mismatch"));
+    }
+    let rows = body.split(|&b| b == b'\n').filter(|line| !line.is_empty()).count().saturating_sub(1);
+    if rows != count {
+        return Err(invalid(&format!("footer counts {} rows but {} were written", count, rows)));
+    }
+    Ok(count)
+}
+
+/// Passes writes through while accumulating their CRC32 (IEEE).
+struct ChecksumWriter<W> {
+    inner: W,
+    crc: u32,
+}
+
+impl<W> ChecksumWriter<W> {
+    fn crc32(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        for &byte in &buf[..written] {
+            self.crc ^= u32::from(byte);
+            for _ in 0..8 {
+                self.crc = (self.crc >> 1) ^ (0xEDB8_8320 & (self.crc & 1).wrapping_neg());
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::QuoteStyle;
+    use crate::domain::user_account::{OutputPrecision, SortOrder};
+    use crate::services::wal::{self, WriteAheadLog};
+    use rust_decimal_macros::dec;
 
     #[tokio::test]
     async fn test_processor_basic_flow() {
@@ -94,7 +1103,7 @@ mod tests {
 
         // Write to a buffer
         let mut buffer = Vec::new();
-        processor.write_results(&mut buffer).unwrap();
+        processor.write_results(&mut buffer).await.unwrap();
 
         // Verify output contains expected data
         let output = String::from_utf8(buffer).unwrap();
@@ -104,14 +1113,1114 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_processor_with_disputes() {
+    async fn test_processor_currency_column() {
         let mut processor = TrxProcessor::new();
-        processor.process_file("tests/fixtures/chargeback.csv").await.unwrap();
+        let csv = "type,client,tx,amount,currency\n\
+                   deposit,1,1,2.0,usd\n\
+                   deposit,1,2,3.0,EUR\n\
+                   deposit,2,3,1.0,\n";
+        processor.process_reader(csv.as_bytes()).await.unwrap();
 
         let mut buffer = Vec::new();
-        processor.write_results(&mut buffer).unwrap();
-
+        processor.write_results(&mut buffer).await.unwrap();
         let output = String::from_utf8(buffer).unwrap();
-        assert!(output.contains("true")); // Account should be locked
+        assert_eq!(
+            output,
+            "client,currency,available,held,total,locked\n\
+             1,EUR,3.0000,0.0000,3.0000,false\n\
+             1,USD,2.0000,0.0000,2.0000,false\n\
+             2,,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processor_frozen_column() {
+        let mut processor = TrxProcessor::new();
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,2.0\n\
+                   deposit,2,2,1.0\n\
+                   freeze,2,3,\n";
+        processor.process_reader(csv.as_bytes()).await.unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,frozen\n\
+             1,2.0000,0.0000,2.0000,false,false\n\
+             2,1.0000,0.0000,1.0000,false,true\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processor_with_disputes() {
+        let mut processor = TrxProcessor::new();
+        processor.process_file("tests/fixtures/chargeback.csv").await.unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("true")); // Account should be locked
+    }
+
+    #[tokio::test]
+    async fn test_processor_custom_columns() {
+        let config = ProcessorConfig::new().with_output_columns(vec![
+            OutputColumn::Total,
+            OutputColumn::Client,
+            OutputColumn::TxCount,
+        ]);
+        let mut processor = TrxProcessor::with_config(config);
+        processor.process_file("tests/fixtures/basic.csv").await.unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "total,client,tx_count");
+        assert_eq!(lines[1], "0.5000,1,2");
+        assert_eq!(lines[2], "2.0000,2,1");
+    }
+
+    #[tokio::test]
+    async fn test_processor_per_field_precision() {
+        let config = ProcessorConfig::new().with_output_precision(OutputPrecision {
+            available: 4,
+            held: 2,
+            total: 0,
+        });
+        let mut processor = TrxProcessor::with_config(config);
+        processor
+            .process_reader("type,client,tx,amount\ndeposit,1,1,10.5\ndeposit,1,2,2.126\ndispute,1,2,\n".as_bytes())
+            .await
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(output, "client,available,held,total,locked\n1,10.5000,2.13,13,false\n");
+    }
+
+    #[tokio::test]
+    async fn test_processor_quote_style_always() {
+        let config = ProcessorConfig::new().with_quote_style(QuoteStyle::Always);
+        let mut processor = TrxProcessor::with_config(config);
+        processor.process_file("tests/fixtures/basic.csv").await.unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            output,
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\n\
+             \"1\",\"0.5000\",\"0.0000\",\"0.5000\",\"false\"\n\
+             \"2\",\"2.0000\",\"0.0000\",\"2.0000\",\"false\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processor_header_only_output() {
+        let mut processor = TrxProcessor::new();
+        processor.process_file("tests/fixtures/malformed_only.csv").await.unwrap();
+        assert_eq!(processor.summary().count(Rejection::Malformed), 2);
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "client,available,held,total,locked\n");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_rejected_or_read_lossily() {
+        let path = "tests/fixtures/invalid_utf8.csv";
+        let mut processor = TrxProcessor::with_config(ProcessorConfig::new().with_skip_malformed(false));
+        let err = processor.process_file(path).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Malformed row at line 3: Invalid UTF-8 in field 5 of the row starting at byte 47"
+        );
+
+        // Skipped as malformed by default
+        let mut processor = TrxProcessor::new();
+        processor.process_file(path).await.unwrap();
+        assert_eq!(processor.summary().count(Rejection::Malformed), 1);
+        assert_eq!(processor.engine().get_accounts().len(), 1);
+
+        let mut processor = TrxProcessor::with_config(ProcessorConfig::new().with_lossy_utf8(true));
+        processor.process_file(path).await.unwrap();
+        assert_eq!(processor.summary().count(Rejection::Malformed), 0);
+        assert_eq!(processor.engine().get_accounts().len(), 2);
+
+        // Streamed input reports the same offset
+        let input = std::fs::read(path).unwrap();
+        let config = ProcessorConfig::new().with_skip_malformed(false);
+        let (mut summary, mut hooks) = (RejectionSummary::new(), RunHooks::default());
+        let err = apply_csv_stream(&PaymentsEngine::new(), &input[..], &config, &mut summary, &mut hooks)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().ends_with("row starting at byte 47"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_processor_strips_bom() {
+        let mut processor = TrxProcessor::new();
+        processor.process_file("tests/fixtures/bom.csv").await.unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("1,7.5000,0.0000,7.5000,false"));
+        assert!(output.contains("2,4.0000,0.0000,4.0000,false"));
+    }
+
+    #[tokio::test]
+    async fn test_processor_semicolon_delimiter() {
+        let config = ProcessorConfig::new().with_delimiter(b';');
+        let mut processor = TrxProcessor::with_config(config);
+        processor.process_file("tests/fixtures/semicolon.csv").await.unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("1,7.5000,0.0000,7.5000,false"));
+        assert!(output.contains("2,0.0000,4.0000,4.0000,false"));
+    }
+
+    #[tokio::test]
+    async fn test_amounts_rounded_on_ingest() {
+        use crate::config::RoundingMode;
+
+        let csv = "type,client,tx,amount\ndeposit,1,1,2.50005\n";
+        let engine = PaymentsEngine::new();
+        process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+        assert_eq!(engine.get_accounts()[0].available, dec!(2.5000));
+
+        let engine = PaymentsEngine::new();
+        let config = ProcessorConfig::new().with_rounding_mode(RoundingMode::HalfUp);
+        process_csv_str(&engine, csv, &config).await.unwrap();
+        assert_eq!(engine.get_accounts()[0].available, dec!(2.5001));
+    }
+
+    #[tokio::test]
+    async fn test_ingest_rounding_keeps_output_within_precision() {
+        // Output from before amounts were rounded on ingest; inputs within 4
+        // places must still produce it byte for byte
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,0.1234\n\
+                   withdrawal,1,3,2.5\n\
+                   deposit,2,4,1.0001\n\
+                   dispute,2,4,\n";
+        let mut processor = TrxProcessor::new();
+        processor.process_reader(csv.as_bytes()).await.unwrap();
+
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "client,available,held,total,locked\n\
+             1,7.6234,0.0000,7.6234,false\n\
+             2,0.0000,1.0001,1.0001,false\n"
+        );
+
+        // Past 4 places the result changed: 1.23456 was kept and printed
+        // truncated as 1.2345, and is now rounded to 1.2346
+        let mut processor = TrxProcessor::new();
+        processor.process_reader("type,client,tx,amount\ndeposit,3,5,1.23456\n".as_bytes()).await.unwrap();
+        assert_eq!(processor.engine().get_accounts()[0].available, dec!(1.2346));
+    }
+
+    #[tokio::test]
+    async fn test_amount_scale_normalized_on_ingest() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,10.00000\ndeposit,1,2,2.5\n";
+        let engine = PaymentsEngine::new();
+        process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+
+        let account = &engine.get_accounts()[0];
+        assert_eq!(account.available.to_string(), "12.5000");
+        assert_eq!(account.total.scale(), 4);
+
+        let mut export = Vec::new();
+        engine.export_transactions(&mut export).await.unwrap();
+        assert_eq!(
+            String::from_utf8(export).unwrap(),
+            "type,client,tx,amount\ndeposit,1,1,10.0000\ndeposit,1,2,2.5000\n"
+        );
+    }
+
+    #[test]
+    fn test_validate_reader_collects_all_problems() {
+        let csv = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,1,5.0
+chargeback,1,3,
+bogus,1,4,1.0
+dispute,1,1,
+";
+        let report = validate_reader(csv.as_bytes(), InputFormat::Csv, &ProcessorConfig::new()).unwrap();
+        assert_eq!(report.rows, 5);
+        let rows: Vec<usize> = report.issues.iter().map(|issue| issue.row).collect();
+        assert_eq!(rows, vec![2, 3, 4]);
+        assert_eq!(report.issues[0].message, "duplicate tx 1");
+        assert_eq!(report.issues[1].message, "chargeback references unknown tx 3");
+        assert!(report.issues[2].message.starts_with("malformed record"));
+        assert!(!report.is_valid());
+    }
+
+    #[tokio::test]
+    async fn test_type_counts() {
+        let mut processor = TrxProcessor::new();
+        processor.process_file("tests/fixtures/comprehensive_all_types.csv").await.unwrap();
+
+        let expected = BTreeMap::from([
+            (TrxType::Deposit, 4),
+            (TrxType::Withdrawal, 2),
+            (TrxType::Dispute, 2),
+            (TrxType::Resolve, 1),
+            (TrxType::Chargeback, 1),
+        ]);
+        assert_eq!(processor.type_counts(), &expected);
+        assert_eq!(processor.summary().type_count(TrxType::Fee), 0);
+    }
+
+    #[tokio::test]
+    async fn test_extra_trailing_column_lenient() {
+        let mut processor = TrxProcessor::new();
+        processor.process_file("tests/fixtures/extra_column.csv").await.unwrap();
+
+        assert_eq!(processor.summary().applied, 4);
+        assert_eq!(processor.summary().total_rejected(), 0);
+        let accounts = processor.into_engine().get_accounts();
+        assert_eq!(accounts[0].available, dec!(7.5));
+        assert_eq!(accounts[1].held, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_extra_trailing_column_rejected_when_strict() {
+        let config = ProcessorConfig::new().with_flexible_columns(false);
+        let mut processor = TrxProcessor::with_config(config);
+        processor.process_file("tests/fixtures/extra_column.csv").await.unwrap();
+        assert_eq!(processor.summary().count(Rejection::Malformed), 3);
+
+        let mut processor = TrxProcessor::with_config(ProcessorConfig::strict());
+        let result = processor.process_file("tests/fixtures/extra_column.csv").await;
+        assert!(matches!(
+            result,
+            Err(PaymentError::MalformedRow { line: 2, ref source }) if matches!(**source, PaymentError::CsvError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_row_reports_line() {
+        let config = ProcessorConfig::new().with_skip_malformed(false);
+        let mut processor = TrxProcessor::with_config(config);
+        let result = processor.process_file("tests/fixtures/bad_row.csv").await;
+
+        let err = result.unwrap_err();
+        assert!(matches!(err, PaymentError::MalformedRow { line: 4, .. }));
+        assert!(err.to_string().starts_with("Malformed row at line 4: CSV error"));
+        assert_eq!(processor.summary().applied, 2);
+    }
+
+    #[tokio::test]
+    async fn test_csv_stream_bounded_buffering() {
+        let mut payload = String::from("\u{feff}type, client, tx, amount\n");
+        for tx in 1..=100_000u32 {
+            payload.push_str(&format!("deposit,{},{},1.5\n", tx % 10, tx));
+        }
+        payload.push_str("withdrawal,1,100001,2.0");
+
+        let engine = PaymentsEngine::new();
+        let mut summary = RejectionSummary::new();
+        let stats = apply_csv_stream(
+            &engine,
+            payload.as_bytes(),
+            &ProcessorConfig::new(),
+            &mut summary,
+            &mut RunHooks::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stats.bytes_read, payload.len() as u64);
+        assert!(payload.len() > 10 * STREAM_CHUNK_BYTES);
+        assert!(stats.peak_buffered <= STREAM_CHUNK_BYTES + 64);
+        assert_eq!(summary.applied, 100_001);
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 10);
+        assert_eq!(accounts[1].total, dec!(14998.0));
+        assert_eq!(accounts[2].total, dec!(15000.0));
+    }
+
+    #[tokio::test]
+    async fn test_csv_stream_reports_line_numbers() {
+        let mut payload = String::from("type,client,tx,amount\n");
+        for tx in 1..=5_000u32 {
+            payload.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        payload.push_str("deposit,1,oops,1.0\n");
+
+        let engine = PaymentsEngine::new();
+        let config = ProcessorConfig::new().with_skip_malformed(false);
+        let result = apply_csv_stream(
+            &engine,
+            payload.as_bytes(),
+            &config,
+            &mut RejectionSummary::new(),
+            &mut RunHooks::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(PaymentError::MalformedRow { line: 5002, .. })));
+        assert_eq!(engine.get_accounts()[0].total, dec!(5000.0));
+    }
+
+    #[tokio::test]
+    async fn test_strict_rejects_excess_precision() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.500000\n\
+                   deposit,1,2,1.123456\n";
+        let mut processor = TrxProcessor::with_config(ProcessorConfig::strict());
+        let result = processor.process_reader(csv.as_bytes()).await;
+
+        // Trailing zeros don't count towards the scale
+        assert!(matches!(result, Err(PaymentError::PrecisionExceeded { tx: 2, scale: 6 })));
+        assert_eq!(processor.summary().applied, 1);
+
+        let config = ProcessorConfig::new().with_reject_excess_precision(true);
+        let mut processor = TrxProcessor::with_config(config);
+        processor.process_reader(csv.as_bytes()).await.unwrap();
+        assert_eq!(processor.summary().count(Rejection::Malformed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_boundary_ids_parse_and_out_of_range_ids_rejected() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,65535,4294967295,2.0\n\
+                   deposit,65536,1,1.0\n\
+                   deposit,1,4294967296,1.0\n\
+                   deposit,-1,2,1.0\n\
+                   dispute,65535,4294967295,\n";
+        let mut processor = TrxProcessor::new();
+        processor.process_reader(csv.as_bytes()).await.unwrap();
+
+        assert_eq!(processor.summary().applied, 2);
+        assert_eq!(processor.summary().count(Rejection::Malformed), 3);
+        let mut buffer = Vec::new();
+        processor.write_results(&mut buffer).await.unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "client,available,held,total,locked\n65535,0.0000,2.0000,2.0000,false\n"
+        );
+    }
+
+    /// Fails reads numbered `fail_from..fail_from + failures`, otherwise
+    /// returns at most `chunk` bytes of `data`.
+    struct FlakyReader {
+        data: std::io::Cursor<&'static [u8]>,
+        chunk: usize,
+        reads: usize,
+        fail_from: usize,
+        failures: usize,
+    }
+
+    impl FlakyReader {
+        fn new(data: &'static str, fail_from: usize, failures: usize) -> Self {
+            Self {
+                data: std::io::Cursor::new(data.as_bytes()),
+                chunk: 16,
+                reads: 0,
+                fail_from,
+                failures,
+            }
+        }
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.reads;
+            self.reads += 1;
+            if (self.fail_from..self.fail_from + self.failures).contains(&n) {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "transient"));
+            }
+            let len = buf.len().min(self.chunk);
+            Read::read(&mut self.data, &mut buf[..len])
+        }
+    }
+
+    const FLAKY_INPUT: &str = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\n";
+
+    #[tokio::test]
+    async fn test_retry_reader_recovers_from_transient_failures() {
+        for fail_from in [0, 2] {
+            let reader = RetryReader::new(FlakyReader::new(FLAKY_INPUT, fail_from, 3), 3, Duration::ZERO);
+            let mut processor = TrxProcessor::new();
+            processor.process_reader(BufReader::new(reader)).await.unwrap();
+
+            let accounts = processor.into_engine().get_accounts();
+            assert_eq!(accounts[0].total, dec!(15.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_reader_exhausted_is_io_error() {
+        // Failing mid-stream must abort the run rather than skip a row
+        for fail_from in [0, 2] {
+            let reader = RetryReader::new(FlakyReader::new(FLAKY_INPUT, fail_from, 3), 2, Duration::ZERO);
+            let mut processor = TrxProcessor::new();
+            let result = processor.process_reader(BufReader::new(reader)).await;
+            assert!(matches!(result, Err(PaymentError::IoError(_))), "{:?}", result);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_retry_reader_delays_on_multi_thread_runtime() {
+        let reader = RetryReader::new(FlakyReader::new(FLAKY_INPUT, 1, 2), 2, Duration::from_millis(1));
+        let mut processor = TrxProcessor::new();
+        processor.process_reader(BufReader::new(reader)).await.unwrap();
+        assert_eq!(processor.into_engine().get_accounts()[0].total, dec!(15.0));
+    }
+
+    #[tokio::test]
+    async fn test_retry_reader_skips_permanent_errors() {
+        struct Denied(usize);
+        impl Read for Denied {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0 += 1;
+                Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied"))
+            }
+        }
+
+        let mut inner = Denied(0);
+        let mut reader = RetryReader::new(&mut inner, 3, Duration::ZERO);
+        let mut processor = TrxProcessor::new();
+        let result = processor.process_reader(BufReader::new(&mut reader)).await;
+        assert!(matches!(result, Err(PaymentError::IoError(ref e)) if e.kind() == std::io::ErrorKind::PermissionDenied));
+        assert_eq!(inner.0, 1);
+    }
+
+    #[test]
+    fn test_backoff_delay_doubles() {
+        let base = Duration::from_millis(10);
+        assert_eq!(backoff_delay(base, 0), base);
+        assert_eq!(backoff_delay(base, 3), Duration::from_millis(80));
+        assert_eq!(backoff_delay(base, 64), backoff_delay(base, 32));
+        assert_eq!(backoff_delay(Duration::MAX, 1), Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_type_skipped_distinctly() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     refund,1,2,3.0\n\
+                     deposit,x,3,1.0\n\
+                     withdrawal,1,4,2.0\n";
+        let mut processor = TrxProcessor::new();
+        processor.process_reader(input.as_bytes()).await.unwrap();
+
+        let summary = processor.summary();
+        assert_eq!(summary.applied, 2);
+        assert_eq!(summary.count(Rejection::UnknownType), 1);
+        assert_eq!(summary.count(Rejection::Malformed), 1);
+        assert_eq!(summary.type_count(TrxType::Unknown), 1);
+        assert_eq!(processor.into_engine().get_accounts()[0].total, dec!(8.0));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_type_aborts_without_skip_malformed() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10.0\n\
+                   refund,1,2,3.0\n\
+                   deposit,1,3,1.0\n";
+        let engine = PaymentsEngine::new();
+        let config = ProcessorConfig::new().with_skip_malformed(false);
+
+        let result = process_csv_str(&engine, csv, &config).await;
+        assert!(matches!(result, Err(PaymentError::InvalidTransaction(ref msg)) if msg.contains("tx 2")));
+        assert_eq!(engine.get_accounts()[0].total, dec!(10.0));
+    }
+
+    #[test]
+    fn test_rate_limiter_refills() {
+        let mut limiter = ClientRateLimiter::new(2);
+        let start = Instant::now();
+        assert!(limiter.allow_at(1, start));
+        assert!(limiter.allow_at(1, start));
+        assert!(!limiter.allow_at(1, start));
+        // Buckets are per client
+        assert!(limiter.allow_at(2, start));
+
+        assert!(!limiter.allow_at(1, start + Duration::from_millis(400)));
+        assert!(limiter.allow_at(1, start + Duration::from_millis(500)));
+        // Refill is capped at one second's worth
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.allow_at(1, later));
+        assert!(limiter.allow_at(1, later));
+        assert!(!limiter.allow_at(1, later));
+    }
+
+    #[tokio::test]
+    async fn test_client_id_offset_keeps_feeds_apart() {
+        let feeds = [
+            (0, "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,1.0\n"),
+            (10_000, "type,client,tx,amount\ndeposit,1,3,4.0\nwithdrawal,1,4,1.5\n"),
+        ];
+        let merged = PaymentsEngine::new();
+        for (offset, feed) in feeds {
+            let mut processor = TrxProcessor::with_config(ProcessorConfig::new().with_client_id_offset(offset));
+            processor.process_reader(feed.as_bytes()).await.unwrap();
+            merged.merge(processor.into_engine()).await.unwrap();
+        }
+
+        let accounts = merged.get_accounts_ordered(SortOrder::ClientId);
+        let balances: Vec<(u16, rust_decimal::Decimal)> = accounts.iter().map(|a| (a.client, a.total)).collect();
+        assert_eq!(balances, vec![(1, dec!(10.0)), (2, dec!(1.0)), (10_001, dec!(2.5))]);
+    }
+
+    #[tokio::test]
+    async fn test_client_id_offset_overflow_is_malformed() {
+        let config = ProcessorConfig::new().with_client_id_offset(u16::MAX - 1);
+        let mut processor = TrxProcessor::with_config(config);
+        processor
+            .process_reader("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,1.0\n".as_bytes())
+            .await
+            .unwrap();
+
+        assert_eq!(processor.summary().applied, 1);
+        assert_eq!(processor.summary().count(Rejection::Malformed), 1);
+        assert_eq!(processor.into_engine().get_accounts()[0].client, u16::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_invariant_violation_is_fatal_only_in_strict_mode() {
+        for strict in [true, false] {
+            let config = ProcessorConfig::new().with_abort_on_invariant_violation(strict);
+            let engine = PaymentsEngine::with_config(config.clone());
+            process_csv_str(&engine, "type,client,tx,amount\ndeposit,1,1,10.0\n", &config).await.unwrap();
+            engine.get_or_create_account(1).total = rust_decimal::Decimal::from(99);
+
+            let result = process_csv_str(&engine, "type,client,tx,amount\ndeposit,1,2,5.0\n", &config).await;
+            if strict {
+                assert!(matches!(result, Err(PaymentError::InvariantViolation(1))));
+            } else {
+                assert_eq!(result.unwrap().applied, 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_european_amounts_with_semicolon_delimiter() {
+        let config = ProcessorConfig::new()
+            .with_delimiter(b';')
+            .with_amount_locale(AmountLocale::European);
+        let engine = PaymentsEngine::with_config(config.clone());
+        let csv = "type;client;tx;amount\ndeposit;1;1;1.234,56\nwithdrawal;1;2;0,56\n";
+
+        let summary = process_csv_str(&engine, csv, &config).await.unwrap();
+
+        assert_eq!(summary.applied, 2);
+        assert_eq!(engine.get_accounts()[0].available, rust_decimal::Decimal::from(1234));
+    }
+
+    #[tokio::test]
+    async fn test_localized_amount_error_keeps_line_number() {
+        let config = ProcessorConfig::new()
+            .with_delimiter(b';')
+            .with_amount_locale(AmountLocale::English)
+            .with_skip_malformed(false);
+        let engine = PaymentsEngine::with_config(config.clone());
+        let csv = "type;client;tx;amount\ndeposit;1;1;1,000.5\ndeposit;1;2;abc\n";
+
+        let result = process_csv_str(&engine, csv, &config).await;
+
+        assert!(matches!(result, Err(PaymentError::MalformedRow { line: 3, .. })));
+        assert_eq!(engine.get_accounts()[0].available, rust_decimal::Decimal::new(10005, 1));
+    }
+
+    #[tokio::test]
+    async fn test_default_amount_fills_missing_deposit_and_withdrawal_amounts() {
+        let config = ProcessorConfig::new().with_default_amount(Some(rust_decimal::Decimal::from(5)));
+        let engine = PaymentsEngine::with_config(config.clone());
+        let csv = "type,client,tx,amount\ndeposit,1,1,\ndeposit,1,2,\nwithdrawal,1,3,\ndispute,1,1,\n";
+
+        let summary = process_csv_str(&engine, csv, &config).await.unwrap();
+
+        assert_eq!(summary.applied, 4);
+        let account = &engine.get_accounts()[0];
+        assert_eq!(account.available, rust_decimal::Decimal::ZERO);
+        assert_eq!(account.held, rust_decimal::Decimal::from(5));
+    }
+
+    #[tokio::test]
+    async fn test_missing_amount_skipped_without_default_amount() {
+        let engine = PaymentsEngine::new();
+        let csv = "type,client,tx,amount\ndeposit,1,1,\ndeposit,1,2,3.0\n";
+
+        let summary = process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.count(Rejection::MissingAmount), 1);
+        assert_eq!(engine.get_accounts()[0].available, rust_decimal::Decimal::from(3));
+    }
+
+    #[test]
+    fn test_checksum_writer_crc32() {
+        let mut checksum = ChecksumWriter { inner: Vec::new(), crc: !0 };
+        checksum.write_all(b"123456789").unwrap();
+        assert_eq!(checksum.crc32(), 0xCBF4_3926);
+        assert_eq!(checksum.inner, b"123456789");
+    }
+
+    #[tokio::test]
+    async fn test_output_footer_matches_rows() {
+        let config = ProcessorConfig::new().with_output_footer(true);
+        let engine = PaymentsEngine::new();
+        let csv = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndeposit,3,3,1.0\n";
+        process_csv_str(&engine, csv, &config).await.unwrap();
+
+        let mut output = Vec::new();
+        write_accounts(&mut output, &engine.get_accounts_ordered(SortOrder::ClientId), &config).unwrap();
+        let text = String::from_utf8(output.clone()).unwrap();
+        let footer = text.lines().last().unwrap();
+        let body = &text[..text.len() - footer.len() - 1];
+
+        let mut checksum = ChecksumWriter { inner: std::io::sink(), crc: !0 };
+        checksum.write_all(body.as_bytes()).unwrap();
+        assert_eq!(footer, format!("# count=3 crc32={:08x}", checksum.crc32()));
+        assert_eq!(verify_footer(&output).unwrap(), 3);
+
+        // Readers that skip comments see only the accounts
+        let rows = csv::ReaderBuilder::new().comment(Some(b'#')).from_reader(output.as_slice()).records().count();
+        assert_eq!(rows, 3);
+
+        // A dropped row is caught
+        let second_row = text.find("\n2,").unwrap();
+        let third_row = text.find("\n3,").unwrap();
+        let truncated = [&output[..second_row], &output[third_row..]].concat();
+        assert!(matches!(verify_footer(&truncated), Err(PaymentError::FooterMismatch(_))));
+        let missing = verify_footer(body.as_bytes()).unwrap_err();
+        assert_eq!(missing.to_string(), "Output footer check failed: no footer line");
+    }
+
+    #[tokio::test]
+    async fn test_reused_tx_ids_reported() {
+        let engine = PaymentsEngine::new();
+        let csv = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,10.0
+withdrawal,1,3,1.0
+deposit,2,1,5.0
+withdrawal,1,2,1.0
+fee,1,3,0.5
+deposit,1,1,10.0
+dispute,1,1,
+withdrawal,1,4,100.0
+";
+
+        let summary = process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+
+        assert_eq!(summary.reused_tx_ids, BTreeSet::from([1, 2, 3]));
+        assert_eq!(summary.count(Rejection::DuplicateTx), 4);
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_column_stored_on_record() {
+        let engine = PaymentsEngine::new();
+        let csv = "type,client,tx,amount,timestamp\n\
+                   deposit,1,1,10.0,2024-01-31T12:00:00Z\n\
+                   withdrawal,1,2,4.0,\n\
+                   dispute,1,1,,2024-02-01T08:30:00+02:00\n";
+        let summary = process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+
+        assert_eq!(summary.total_rejected(), 0);
+        let expected: jiff::Timestamp = "2024-01-31T12:00:00Z".parse().unwrap();
+        assert_eq!(engine.tx_timestamp(1).await, Some(expected));
+        assert_eq!(engine.tx_timestamp(2).await, None);
+        assert_eq!(engine.tx_status(1).await, Some(crate::domain::transaction::TrxStatus::UnderDispute));
+    }
+
+    #[tokio::test]
+    async fn test_input_without_timestamp_column() {
+        let engine = PaymentsEngine::new();
+        process_csv_str(&engine, "type,client,tx,amount\ndeposit,1,1,10.0\n", &ProcessorConfig::new())
+            .await
+            .unwrap();
+
+        assert_eq!(engine.tx_timestamp(1).await, None);
+        assert_eq!(engine.get_accounts()[0].available, dec!(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast_aborts_on_first_rejection() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   withdrawal,1,2,8.0\n\
+                   deposit,1,3,1.0\n";
+
+        let engine = PaymentsEngine::new();
+        let result = process_csv_str(&engine, csv, &ProcessorConfig::new().with_fail_fast(true)).await;
+        match result {
+            Err(PaymentError::Rejected { tx, rejection }) => {
+                assert_eq!(tx, 2);
+                assert_eq!(rejection, Rejection::InsufficientFunds);
+            }
+            other => panic!("expected fail-fast rejection, got {:?}", other),
+        }
+        assert_eq!(engine.get_accounts()[0].available, dec!(5.0));
+
+        let engine = PaymentsEngine::new();
+        let summary = process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+        assert_eq!(summary.total_rejected(), 1);
+        assert_eq!(engine.get_accounts()[0].available, dec!(6.0));
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_types_need_lenient_mode() {
+        let csv = "type,client,tx,amount\n\
+                   Deposit,1,1,10.0\n\
+                   DEPOSIT,1,2,5.0\n\
+                   WithDrawal,1,3,3.0\n";
+
+        let engine = PaymentsEngine::new();
+        let summary = process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+        assert_eq!(summary.total_rejected(), 0);
+        assert_eq!(summary.type_count(TrxType::Deposit), 2);
+        assert_eq!(engine.get_accounts()[0].available, dec!(12.0));
+
+        let engine = PaymentsEngine::new();
+        let result = process_csv_str(&engine, csv, &ProcessorConfig::strict()).await;
+        assert!(matches!(result, Err(PaymentError::InvalidTransaction(_))));
+        assert!(engine.get_accounts().is_empty());
+
+        let jsonl = r#"{"type":"Deposit","client":1,"tx":1,"amount":"2.5"}"#;
+        let mut processor = TrxProcessor::new();
+        processor.process_reader_as(jsonl.as_bytes(), InputFormat::JsonLines).await.unwrap();
+        assert_eq!(processor.engine().get_accounts()[0].available, dec!(2.5));
+    }
+
+    #[tokio::test]
+    async fn test_balance_scale_stays_within_precision() {
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 0..500u32 {
+            let client = tx % 7;
+            let row = match tx % 6 {
+                0 | 1 => format!("deposit,{},{},{}.{:05}\n", client, tx, tx, tx * 37),
+                2 => format!("withdrawal,{},{},0.123456789\n", client, tx),
+                3 => format!("fee,{},{},0.00015\n", client, tx),
+                // Disputes and their follow-ups name the deposit's own client
+                4 => format!("dispute,{},{},\n", (tx - 4) % 7, tx - 4),
+                _ => format!("{},{},{},\n", if tx % 12 == 5 { "resolve" } else { "chargeback" }, (tx - 5) % 7, tx - 5),
+            };
+            csv.push_str(&row);
+        }
+
+        for precision in [2, 4] {
+            let engine = PaymentsEngine::new();
+            let config = ProcessorConfig::new().with_precision(precision);
+            let summary = process_csv_str(&engine, &csv, &config).await.unwrap();
+            assert!(summary.applied > 300);
+            assert_eq!(summary.count(Rejection::ClientMismatch), 0);
+            assert!(summary.type_count(TrxType::Dispute) > 50);
+            assert!(engine.max_scale_in_use() <= precision, "precision {}", precision);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_amounts_flip_type() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,-5.0\ndeposit,1,3,-1.5\nwithdrawal,1,4,-2.0\n";
+
+        let engine = PaymentsEngine::new();
+        let config = ProcessorConfig::new().with_signed_amounts(true);
+        let summary = process_csv_str(&engine, csv, &config).await.unwrap();
+        assert_eq!(summary.total_rejected(), 0);
+        assert_eq!(engine.get_accounts()[0].available, dec!(5.5));
+        assert_eq!(summary.type_count(TrxType::Deposit), 2);
+        assert_eq!(summary.type_count(TrxType::Withdrawal), 2);
+
+        // Off by default: rows keep the type they were given
+        let summary = process_csv_str(&PaymentsEngine::new(), csv, &ProcessorConfig::new()).await.unwrap();
+        assert_eq!(summary.type_count(TrxType::Deposit), 3);
+        assert_eq!(summary.type_count(TrxType::Withdrawal), 1);
+    }
+
+    #[tokio::test]
+    async fn test_process_csv_str_end_to_end() {
+        let engine = PaymentsEngine::new();
+        let csv = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+withdrawal,2,4,50.0
+deposit,1,1,99.0
+dispute,2,2,
+resolve,1,9,
+bogus,1,5,1.0
+";
+
+        let summary = process_csv_str(&engine, csv, &ProcessorConfig::new()).await.unwrap();
+
+        assert_eq!(summary.applied, 4);
+        assert_eq!(summary.count(Rejection::InsufficientFunds), 1);
+        assert_eq!(summary.count(Rejection::DuplicateTx), 1);
+        assert_eq!(summary.count(Rejection::TxNotFound), 1);
+        assert_eq!(summary.count(Rejection::UnknownType), 1);
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].available, dec!(7.5));
+        assert_eq!(accounts[1].held, dec!(5.0));
+    }
+
+    /// Hands out one line per read and cancels the token once `cancel_after`
+    /// lines have been read.
+    struct CancellingReader {
+        lines: Vec<String>,
+        next: usize,
+        cancel_after: usize,
+        token: CancellationToken,
+    }
+
+    impl std::io::Read for CancellingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(line) = self.lines.get(self.next) else {
+                return Ok(0);
+            };
+            self.next += 1;
+            if self.next == self.cancel_after {
+                self.token.cancel();
+            }
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            Ok(line.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_processor_cancellation_stops_early() {
+        let token = CancellationToken::new();
+        let mut lines = vec!["type,client,tx,amount\n".to_string()];
+        for tx in 1..=10 {
+            lines.push(format!("deposit,1,{},1.0\n", tx));
+        }
+        // Cancel while the third deposit is being read
+        let reader = CancellingReader {
+            lines,
+            next: 0,
+            cancel_after: 4,
+            token: token.clone(),
+        };
+
+        let mut processor = TrxProcessor::new().with_cancellation_token(token);
+        let result = processor.process_reader(BufReader::new(reader)).await;
+
+        assert!(matches!(result, Err(PaymentError::Cancelled)));
+        assert_eq!(processor.summary().applied, 2);
+        let accounts = processor.engine.get_accounts();
+        assert_eq!(accounts[0].total, dec!(2.0));
+    }
+
+    #[tokio::test]
+    async fn test_resume_skips_applied_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.csv");
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 1..=10 {
+            csv.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        std::fs::write(&input, csv).unwrap();
+        let input = input.to_str().unwrap();
+        let marker_path = dir.path().join("input.offset");
+        let wal_path = dir.path().join("engine.wal");
+
+        // Interrupt the first run after half the records
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let engine = PaymentsEngine::new().with_wal(WriteAheadLog::open(&wal_path).unwrap());
+        let mut processor = TrxProcessor::with_engine(engine)
+            .with_cancellation_token(token)
+            .with_progress(5, move |_| cancel.cancel())
+            .with_resume_marker(ResumeMarker::open(&marker_path).unwrap());
+        let result = processor.process_file(input).await;
+        assert!(matches!(result, Err(PaymentError::Cancelled)));
+        assert_eq!(ResumeMarker::open(&marker_path).unwrap().offset(), 5);
+
+        // A restart gets a fresh engine, rebuilt from its WAL
+        let restarted = PaymentsEngine::new();
+        wal::replay(&wal_path, &restarted).await.unwrap();
+        let restarted = restarted.with_wal(WriteAheadLog::open(&wal_path).unwrap());
+        let mut processor = TrxProcessor::with_engine(restarted)
+            .with_resume_marker(ResumeMarker::open(&marker_path).unwrap());
+        processor.process_file(input).await.unwrap();
+
+        assert_eq!(processor.summary().applied, 5);
+        assert_eq!(processor.summary().total_rejected(), 0);
+        assert_eq!(processor.engine.get_accounts()[0].total, dec!(10.0));
+        assert_eq!(ResumeMarker::open(&marker_path).unwrap().offset(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_resume_marker_tied_to_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("first.csv"), dir.path().join("second.csv"));
+        std::fs::write(&first, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        std::fs::write(&second, "type,client,tx,amount\ndeposit,1,2,1.0\n").unwrap();
+        let marker_path = dir.path().join("job.offset");
+
+        let mut processor = TrxProcessor::new().with_resume_marker(ResumeMarker::open(&marker_path).unwrap());
+        processor.process_file(first.to_str().unwrap()).await.unwrap();
+
+        let mut processor = TrxProcessor::new().with_resume_marker(ResumeMarker::open(&marker_path).unwrap());
+        let result = processor.process_file(second.to_str().unwrap()).await;
+        assert!(matches!(result, Err(PaymentError::InvalidConfig(_))));
+        assert!(processor.engine.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_files_parallel_merges() {
+        let paths = vec![
+            "tests/fixtures/daily_1.csv".to_string(),
+            "tests/fixtures/daily_2.csv".to_string(),
+        ];
+        let engine = process_files_parallel(&paths, &ProcessorConfig::new()).await.unwrap();
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[0].available, dec!(11.0));
+        assert_eq!(accounts[1].held, dec!(20.0));
+        assert_eq!(accounts[2].total, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_mode_output_is_byte_identical() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for file in 0..4u32 {
+            let mut csv = String::from("type,client,tx,amount\n");
+            for i in 0..50u32 {
+                let tx = file * 1000 + i;
+                csv.push_str(&format!("deposit,{},{},{}.5\n", (i * 7 + file) % 40, tx, i));
+            }
+            let path = dir.path().join(format!("part_{}.csv", file));
+            std::fs::write(&path, csv).unwrap();
+            paths.push(path.to_string_lossy().into_owned());
+        }
+
+        let config = ProcessorConfig::new()
+            .with_deterministic(true)
+            .with_sort_order(SortOrder::Unsorted);
+        let mut outputs = Vec::new();
+        for _ in 0..20 {
+            let engine = process_files_parallel(&paths, &config).await.unwrap();
+            let accounts = engine.get_accounts_ordered(SortOrder::Unsorted);
+            let mut buffer = Vec::new();
+            write_accounts(&mut buffer, &accounts, &config).unwrap();
+            outputs.push(buffer);
+        }
+        assert!(outputs.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[tokio::test]
+    async fn test_process_files_parallel_reports_collisions() {
+        let paths = vec![
+            "tests/fixtures/daily_1.csv".to_string(),
+            "tests/fixtures/daily_1.csv".to_string(),
+        ];
+        let result = process_files_parallel(&paths, &ProcessorConfig::new()).await;
+        assert!(matches!(result, Err(PaymentError::TxIdCollision(ref ids)) if ids == &vec![1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn test_processor_progress_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let mut processor = TrxProcessor::new()
+            .with_progress(2, move |progress| sink.lock().unwrap().push(progress));
+
+        // 5 rows, the resolve of tx 9 is rejected
+        let csv = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,1.0
+resolve,1,9,
+deposit,1,3,1.0
+deposit,1,4,1.0
+";
+        processor.process_reader(csv.as_bytes()).await.unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(
+            *reports,
+            vec![
+                ProcessProgress { rows: 2, errors: 0 },
+                ProcessProgress { rows: 4, errors: 1 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processor_jsonl_matches_csv() {
+        let mut csv_processor = TrxProcessor::new();
+        csv_processor.process_file("tests/fixtures/comprehensive_all_types.csv").await.unwrap();
+        let mut expected = Vec::new();
+        csv_processor.write_results(&mut expected).await.unwrap();
+
+        let mut jsonl_processor = TrxProcessor::new();
+        jsonl_processor.process_file("tests/fixtures/comprehensive_all_types.jsonl").await.unwrap();
+        let mut actual = Vec::new();
+        jsonl_processor.write_results(&mut actual).await.unwrap();
+
+        assert_eq!(String::from_utf8(actual).unwrap(), String::from_utf8(expected).unwrap());
+        assert_eq!(jsonl_processor.summary(), csv_processor.summary());
+    }
+
+    #[tokio::test]
+    async fn test_processor_jsonl_malformed_lines() {
+        let jsonl = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}
+{"type":"deposit","client":1,
+{"type":"withdrawal","client":1,"tx":2,"amount":1.0}
+"#;
+        let config = ProcessorConfig::new().with_input_format(InputFormat::JsonLines);
+        let mut processor = TrxProcessor::with_config(config.clone());
+        processor.process_reader(jsonl.as_bytes()).await.unwrap();
+        assert_eq!(processor.summary().applied, 2);
+        assert_eq!(processor.summary().count(Rejection::Malformed), 1);
+
+        let mut strict = TrxProcessor::with_config(config.with_skip_malformed(false));
+        let result = strict.process_reader(jsonl.as_bytes()).await;
+        assert!(matches!(
+            result,
+            Err(PaymentError::MalformedRow { line: 2, ref source }) if matches!(**source, PaymentError::JsonError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_processor_jsonl_invalid_utf8_line() {
+        let mut jsonl = br#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#.to_vec();
+        jsonl.extend_from_slice(b"\n{\"type\":\"deposit\",\"client\":2,\"tx\":2,\"amount\":\"1.0\",\"note\":\"\xff\"}\n");
+        jsonl.extend_from_slice(br#"{"type":"deposit","client":3,"tx":3,"amount":"1.0"}"#);
+        let config = ProcessorConfig::new().with_input_format(InputFormat::JsonLines);
+
+        let mut processor = TrxProcessor::with_config(config.clone());
+        processor.process_reader(&jsonl[..]).await.unwrap();
+        assert_eq!(processor.summary().applied, 2);
+        assert_eq!(processor.summary().count(Rejection::Malformed), 1);
+
+        let mut lossy = TrxProcessor::with_config(config.clone().with_lossy_utf8(true));
+        lossy.process_reader(&jsonl[..]).await.unwrap();
+        assert_eq!(lossy.summary().applied, 3);
+
+        let mut strict = TrxProcessor::with_config(config.with_skip_malformed(false));
+        let result = strict.process_reader(&jsonl[..]).await;
+        assert!(matches!(
+            result,
+            Err(PaymentError::MalformedRow { line: 2, ref source })
+                if matches!(**source, PaymentError::InvalidUtf8 { offset: 52, .. })
+        ));
     }
 }
@@ -0,0 +1,236 @@
+use crate::domain::transaction::TxRecord;
+use indexmap::IndexMap;
+use std::future::Future;
+
+/// Storage for the engine's transaction history. The engine only calls it
+/// while holding its history lock, so implementations need no locking of
+/// their own for consistency.
+pub trait TxStore: Send + Sync {
+    fn get(&self, tx: u32) -> impl Future<Output = Option<TxRecord>> + Send;
+
+    fn contains(&self, tx: u32) -> impl Future<Output = bool> + Send;
+
+    /// Stores `record` under `tx`, replacing an existing record in place.
+    fn insert(&mut self, tx: u32, record: TxRecord) -> impl Future<Output = ()> + Send;
+
+    /// Drops the record with the lowest insertion ordinal, returning its tx ID.
+    fn remove_oldest(&mut self) -> impl Future<Output = Option<u32>> + Send;
+
+    fn len(&self) -> impl Future<Output = usize> + Send;
+
+    fn is_empty(&self) -> impl Future<Output = bool> + Send {
+        async { self.len().await == 0 }
+    }
+
+    /// Records the store has room for without growing; feeds memory estimates.
+    fn capacity(&self) -> impl Future<Output = usize> + Send {
+        self.len()
+    }
+
+    /// Every record, in the order first inserted.
+    fn records(&self) -> impl Future<Output = Vec<(u32, TxRecord)>> + Send;
+
+    /// Calls `f` on every record, in the order first inserted. The default
+    /// goes through `records`; stores that can lend their records override it
+    /// to skip the copy.
+    fn for_each_record<F>(&self, mut f: F) -> impl Future<Output = ()> + Send
+    where
+        F: FnMut(u32, &TxRecord) + Send,
+    {
+        async move {
+            for (tx, record) in self.records().await {
+                f(tx, &record);
+            }
+        }
+    }
+
+    /// Drops every record.
+    fn clear(&mut self) -> impl Future<Output = ()> + Send {
+        async { while self.remove_oldest().await.is_some() {} }
+    }
+}
+
+/// Default store: an insertion-ordered map kept in memory.
+#[derive(Debug, Default)]
+pub struct InMemoryTxStore(IndexMap<u32, TxRecord>);
+
+impl InMemoryTxStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        InMemoryTxStore(IndexMap::with_capacity(capacity))
+    }
+}
+
+impl TxStore for InMemoryTxStore {
+    async fn get(&self, tx: u32) -> Option<TxRecord> {
+        self.0.get(&tx).cloned()
+    }
+
+    async fn contains(&self, tx: u32) -> bool {
+        self.0.contains_key(&tx)
+    }
+
+    async fn insert(&mut self, tx: u32, record: TxRecord) {
+        self.0.insert(tx, record);
+    }
+
+    async fn remove_oldest(&mut self) -> Option<u32> {
+        let index = self
+            .0
+            .values()
+            .enumerate()
+            .min_by_key(|(_, record)| record.insertion_ordinal)
+            .map(|(index, _)| index)?;
+        self.0.shift_remove_index(index).map(|(tx, _)| tx)
+    }
+
+    async fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    async fn records(&self) -> Vec<(u32, TxRecord)> {
+        self.0.iter().map(|(&tx, record)| (tx, record.clone())).collect()
+    }
+
+    async fn for_each_record<F>(&self, mut f: F)
+    where
+        F: FnMut(u32, &TxRecord) + Send,
+    {
+        for (&tx, record) in &self.0 {
+            f(tx, record);
+        }
+    }
+
+    async fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::domain::currency::Currency;
+    use crate::domain::transaction::{TxKind, TrxStatus};
+    use rust_decimal_macros::dec;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Vec-backed store that counts every call, to check the engine goes
+    /// through the trait alone.
+    #[derive(Default)]
+    pub(crate) struct MockTxStore {
+        records: Vec<(u32, TxRecord)>,
+        pub(crate) calls: Arc<AtomicUsize>,
+    }
+
+    impl MockTxStore {
+        fn touch(&self) {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl TxStore for MockTxStore {
+        async fn get(&self, tx: u32) -> Option<TxRecord> {
+            self.touch();
+            self.records.iter().find(|(id, _)| *id == tx).map(|(_, record)| record.clone())
+        }
+
+        async fn contains(&self, tx: u32) -> bool {
+            self.touch();
+            self.records.iter().any(|(id, _)| *id == tx)
+        }
+
+        async fn insert(&mut self, tx: u32, record: TxRecord) {
+            self.touch();
+            match self.records.iter_mut().find(|(id, _)| *id == tx) {
+                Some((_, existing)) => *existing = record,
+                None => self.records.push((tx, record)),
+            }
+        }
+
+        async fn remove_oldest(&mut self) -> Option<u32> {
+            self.touch();
+            let index = (0..self.records.len()).min_by_key(|&i| self.records[i].1.insertion_ordinal)?;
+            Some(self.records.remove(index).0)
+        }
+
+        async fn len(&self) -> usize {
+            self.touch();
+            self.records.len()
+        }
+
+        async fn records(&self) -> Vec<(u32, TxRecord)> {
+            self.touch();
+            self.records.clone()
+        }
+    }
+
+    fn record(amount: rust_decimal::Decimal, insertion_ordinal: u64) -> TxRecord {
+        TxRecord {
+            kind: TxKind::Deposit,
+            client: 1,
+            currency: Currency::DEFAULT,
+            amount,
+            status: TrxStatus::Normal,
+            insertion_ordinal,
+            resolved: rust_decimal::Decimal::ZERO,
+            timestamp: None,
+        }
+    }
+
+    async fn check_contract<S: TxStore>(mut store: S) {
+        assert!(store.is_empty().await);
+        assert_eq!(store.get(1).await, None);
+
+        store.insert(2, record(dec!(2.0), 5)).await;
+        store.insert(1, record(dec!(1.0), 7)).await;
+        store.insert(3, record(dec!(3.0), 9)).await;
+        assert_eq!(store.len().await, 3);
+        assert!(store.contains(1).await);
+        assert!(!store.contains(4).await);
+
+        // Replacing keeps the original position
+        let mut disputed = record(dec!(2.0), 5);
+        disputed.status = TrxStatus::UnderDispute;
+        store.insert(2, disputed.clone()).await;
+        assert_eq!(store.get(2).await, Some(disputed));
+        let order: Vec<u32> = store.records().await.into_iter().map(|(tx, _)| tx).collect();
+        assert_eq!(order, vec![2, 1, 3]);
+        let mut visited = Vec::new();
+        store.for_each_record(|tx, record| visited.push((tx, record.clone()))).await;
+        assert_eq!(visited, store.records().await);
+
+        assert_eq!(store.remove_oldest().await, Some(2));
+        assert_eq!(store.remove_oldest().await, Some(1));
+        assert_eq!(store.len().await, 1);
+        assert_eq!(store.remove_oldest().await, Some(3));
+        assert_eq!(store.remove_oldest().await, None);
+        assert!(store.is_empty().await);
+
+        store.insert(1, record(dec!(1.0), 1)).await;
+        store.insert(2, record(dec!(2.0), 2)).await;
+        store.clear().await;
+        assert!(store.is_empty().await);
+        assert!(!store.contains(1).await);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_contract() {
+        check_contract(InMemoryTxStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_mock_store_contract() {
+        let store = MockTxStore::default();
+        let calls = store.calls.clone();
+        check_contract(store).await;
+        assert!(calls.load(Ordering::Relaxed) > 0);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::config::{AmountLocale, InputFormat, ProcessorConfig};
+use crate::domain::rejection::RejectionSummary;
+use crate::domain::currency::Currency;
+use crate::domain::transaction::Trx;
+use crate::error::Result;
+use crate::services::payment_engine::PaymentsEngine;
+use crate::services::trx_processor::{apply_csv, RunHooks};
+use jiff::Timestamp;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use tokio::sync::{Mutex, MutexGuard};
+
+const HEADER: [&str; 6] = ["type", "client", "tx", "amount", "currency", "timestamp"];
+
+/// Append-only log of transactions, each written before it is applied. Entries
+/// use the regular CSV input format (`type,client,tx,amount,currency,timestamp`), so a
+/// log can also be fed to the CLI. Rejected transactions are logged too;
+/// replay under the same config rejects them again.
+pub struct WriteAheadLog {
+    writer: Mutex<WalWriter>,
+}
+
+pub(crate) struct WalWriter {
+    writer: csv::Writer<Box<dyn Write + Send>>,
+    /// Flush after every entry rather than only on `WriteAheadLog::flush`
+    sync: bool,
+    /// Set once a write fails; the entry may still sit in the buffer, so
+    /// nothing more is accepted
+    failed: bool,
+}
+
+impl WriteAheadLog {
+    /// Opens `path` for appending, creating it (with a header row) if needed.
+    /// A log written before the currency or timestamp columns existed has its
+    /// header upgraded first. Every entry is flushed as it is written.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path, true)
+    }
+
+    /// Like `open`, but entries are buffered until `flush` (or the buffer
+    /// fills), trading the tail of the log on a crash for throughput.
+    pub fn open_buffered(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path, false)
+    }
+
+    fn open_with(path: impl AsRef<Path>, sync: bool) -> Result<Self> {
+        let path = path.as_ref();
+        upgrade_header(path)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        Self::from_writer(Box::new(file), is_empty, sync)
+    }
+
+    pub(crate) fn from_writer(writer: Box<dyn Write + Send>, write_header: bool, sync: bool) -> Result<Self> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(writer);
+        if write_header {
+            writer.write_record(HEADER)?;
+            writer.flush()?;
+        }
+        Ok(WriteAheadLog {
+            writer: Mutex::new(WalWriter { writer, sync, failed: false }),
+        })
+    }
+
+    /// Writes out any buffered entries.
+    pub async fn flush(&self) -> Result<()> {
+        self.lock().await.writer.flush()?;
+        Ok(())
+    }
+
+    /// Held by the engine across applying a transaction, so log order always
+    /// matches application order.
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, WalWriter> {
+        self.writer.lock().await
+    }
+}
+
+impl WalWriter {
+    /// Writes one entry, flushing it unless the log is buffered. The default
+    /// currency and a missing timestamp are logged as empty fields.
+    pub(crate) fn append(&mut self, tx: &Trx, currency: Currency, timestamp: Option<Timestamp>) -> Result<()> {
+        if self.failed {
+            return Err(std::io::Error::other("write-ahead log failed earlier").into());
+        }
+        let result = self.write_entry(tx, currency, timestamp);
+        self.failed = result.is_err();
+        result
+    }
+
+    fn write_entry(&mut self, tx: &Trx, currency: Currency, timestamp: Option<Timestamp>) -> Result<()> {
+        let amount = tx.amount().map(|amount| amount.to_string()).unwrap_or_default();
+        let timestamp = timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default();
+        self.writer.write_record([
+            tx.type_name(),
+            &tx.client().to_string(),
+            &tx.tx().to_string(),
+            &amount,
+            currency.as_str(),
+            &timestamp,
+        ])?;
+        if self.sync {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Replay reads columns by header name, so entries appended under an older,
+/// shorter header would lose their currency and timestamp. Rewrites the log
+/// with the current header; older rows stay short, which replay accepts.
+fn upgrade_header(path: &Path) -> Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let columns: Vec<&str> = header.trim_end_matches(['\r', '\n']).split(',').collect();
+    if header.is_empty() || columns.len() >= HEADER.len() || !HEADER.starts_with(&columns) {
+        return Ok(());
+    }
+
+    let upgraded = path.with_extension("upgrade");
+    let mut out = File::create(&upgraded)?;
+    writeln!(out, "{}", HEADER.join(","))?;
+    std::io::copy(&mut reader, &mut out)?;
+    out.sync_all()?;
+    std::fs::rename(&upgraded, path)?;
+    log::info!("WAL header upgraded from {} to {} columns", columns.len(), HEADER.len());
+    Ok(())
+}
+
+/// Rebuilds state by applying every logged transaction to `engine`. Replay
+/// must happen before the log is attached to the engine, or each entry would be
+/// logged again. A missing log is treated as empty; a torn final row from a
+/// crash is skipped like any other malformed row.
+pub async fn replay(path: impl AsRef<Path>, engine: &PaymentsEngine) -> Result<RejectionSummary> {
+    let mut summary = RejectionSummary::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(summary),
+        Err(e) => return Err(e.into()),
+    };
+
+    let config = replay_config(engine.config());
+    apply_csv(engine, BufReader::new(file), &config, &mut summary, &mut RunHooks::default()).await?;
+    log::info!(
+        "WAL replay: {} transactions applied, {} rejected",
+        summary.applied,
+        summary.total_rejected()
+    );
+    Ok(summary)
+}
+
+/// The engine's own precision and rounding, with the input-side transforms
+/// off: logged entries are already rounded, offset and sign-normalized.
+fn replay_config(config: &ProcessorConfig) -> ProcessorConfig {
+    ProcessorConfig {
+        skip_malformed: true,
+        fail_fast: false,
+        input_format: InputFormat::Csv,
+        delimiter: b',',
+        flexible_columns: true,
+        amount_locale: AmountLocale::Plain,
+        client_id_offset: 0,
+        signed_amounts: false,
+        default_amount: None,
+        reject_excess_precision: false,
+        lossy_utf8: false,
+        ..config.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::rejection::Rejection;
+    use crate::error::PaymentError;
+    use crate::services::trx_processor::TrxProcessor;
+    use rust_decimal_macros::dec;
+
+    async fn export(engine: &PaymentsEngine) -> String {
+        let mut out = Vec::new();
+        engine.export_transactions(&mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_wal_replay_restores_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.wal");
+
+        // Amounts at the 4dp scale replay normalizes to, so exports compare equal
+        let engine = PaymentsEngine::new().with_wal(WriteAheadLog::open(&path).unwrap());
+        let txs = [
+            Trx::Deposit { client: 1, tx: 1, amount: dec!(10.0000) },
+            Trx::Deposit { client: 2, tx: 2, amount: dec!(5.5000) },
+            Trx::Withdrawal { client: 1, tx: 3, amount: dec!(3.0000) },
+            Trx::Withdrawal { client: 2, tx: 4, amount: dec!(50.0000) },
+            Trx::Dispute { client: 1, tx: 1 },
+            Trx::Resolve { client: 1, tx: 1 },
+            Trx::Dispute { client: 2, tx: 2 },
+            Trx::Chargeback { client: 2, tx: 2 },
+            Trx::Fee { client: 1, tx: 5, amount: dec!(0.2500) },
+        ];
+        for tx in txs {
+            engine.process(tx).await;
+        }
+
+        // The overdrawing withdrawal is logged ahead of being rejected, and is
+        // rejected again on replay
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 10);
+        assert!(log.contains("withdrawal,2,4"));
+
+        let restored = PaymentsEngine::new();
+        let summary = replay(&path, &restored).await.unwrap();
+        assert_eq!(summary.applied, 8);
+        assert_eq!(summary.count(Rejection::InsufficientFunds), 1);
+        assert_eq!(summary.total_rejected(), 1);
+        assert_eq!(restored.get_accounts(), engine.get_accounts());
+        assert_eq!(export(&restored).await, export(&engine).await);
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_survive_replay_and_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.wal");
+        let at: Timestamp = "2024-01-31T12:00:00Z".parse().unwrap();
+
+        let engine = PaymentsEngine::new().with_wal(WriteAheadLog::open(&path).unwrap());
+        let deposit = Trx::Deposit { client: 1, tx: 1, amount: dec!(10.0000) };
+        engine.try_process_at(deposit, Currency::DEFAULT, Some(at)).await.unwrap();
+        engine.process(Trx::Deposit { client: 1, tx: 2, amount: dec!(1.0000) }).await;
+
+        let restored = PaymentsEngine::new();
+        replay(&path, &restored).await.unwrap();
+        assert_eq!(restored.tx_timestamp(1).await, Some(at));
+        assert_eq!(restored.tx_timestamp(2).await, None);
+
+        let export_path = dir.path().join("export.csv");
+        std::fs::write(&export_path, export(&engine).await).unwrap();
+        let from_export = PaymentsEngine::new();
+        replay(&export_path, &from_export).await.unwrap();
+        assert_eq!(from_export.tx_timestamp(1).await, Some(at));
+        assert_eq!(export(&from_export).await, export(&engine).await);
+    }
+
+    #[tokio::test]
+    async fn test_wal_reopen_appends_without_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.wal");
+
+        for tx in 1..=2 {
+            let engine = PaymentsEngine::new();
+            replay(&path, &engine).await.unwrap();
+            let engine = engine.with_wal(WriteAheadLog::open(&path).unwrap());
+            engine.process(Trx::Deposit { client: 1, tx, amount: dec!(1.0) }).await;
+        }
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log, "type,client,tx,amount,currency,timestamp\ndeposit,1,1,1.0,,\ndeposit,1,2,1.0,,\n");
+
+        let engine = PaymentsEngine::new();
+        let summary = replay(&path, &engine).await.unwrap();
+        assert_eq!(summary.applied, 2);
+        assert_eq!(engine.get_accounts()[0].available, dec!(2.0));
+    }
+
+    #[tokio::test]
+    async fn test_wal_reopen_upgrades_old_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.wal");
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+
+        let engine = PaymentsEngine::new();
+        replay(&path, &engine).await.unwrap();
+        let engine = engine.with_wal(WriteAheadLog::open(&path).unwrap());
+        let usd: Currency = "USD".parse().unwrap();
+        engine.try_process_in(Trx::Deposit { client: 1, tx: 2, amount: dec!(2.0) }, usd).await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log, "type,client,tx,amount,currency,timestamp\ndeposit,1,1,1.0\ndeposit,1,2,2.0,USD,\n");
+
+        let restored = PaymentsEngine::new();
+        let summary = replay(&path, &restored).await.unwrap();
+        assert_eq!(summary.applied, 2);
+        let accounts = restored.get_accounts();
+        assert_eq!((accounts[1].currency, accounts[1].available), (usd, dec!(2.0)));
+        assert_eq!(restored.get_accounts(), engine.get_accounts());
+    }
+
+    #[tokio::test]
+    async fn test_deposit_retry_not_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.wal");
+        let config = ProcessorConfig::new().with_idempotent_deposits(true);
+        let engine = PaymentsEngine::with_config(config).with_wal(WriteAheadLog::open(&path).unwrap());
+
+        let deposit = Trx::Deposit { client: 1, tx: 1, amount: dec!(1.0) };
+        engine.try_process(deposit.clone()).await.unwrap();
+        engine.try_process(deposit).await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log, "type,client,tx,amount,currency,timestamp\ndeposit,1,1,1.0,,\n");
+        assert_eq!(engine.applied_count(), 1);
+    }
+
+    struct BrokenSink;
+
+    impl Write for BrokenSink {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::Error::other("disk full"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_append_rejects_transaction() {
+        let wal = WriteAheadLog::from_writer(Box::new(BrokenSink), false, true).unwrap();
+        let engine = PaymentsEngine::new().with_wal(wal);
+        let result = engine.try_process(Trx::Deposit { client: 1, tx: 1, amount: dec!(1.0) }).await;
+        assert_eq!(result, Err(Rejection::LogWriteFailed));
+        assert!(engine.get_accounts().is_empty());
+        assert_eq!(engine.applied_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_clear_refused_with_wal_attached() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = PaymentsEngine::new().with_wal(WriteAheadLog::open(dir.path().join("engine.wal")).unwrap());
+        engine.process(Trx::Deposit { client: 1, tx: 1, amount: dec!(1.0) }).await;
+
+        assert!(matches!(engine.clear().await, Err(PaymentError::InvalidConfig(_))));
+        assert_eq!(engine.get_accounts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replay_uses_engine_precision() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.wal");
+        let config = ProcessorConfig::new().with_precision(6);
+
+        let engine = PaymentsEngine::with_config(config.clone()).with_wal(WriteAheadLog::open(&path).unwrap());
+        engine.process(Trx::Deposit { client: 1, tx: 1, amount: dec!(1.123456) }).await;
+
+        let restored = PaymentsEngine::with_config(config);
+        replay(&path, &restored).await.unwrap();
+        assert_eq!(restored.get_accounts()[0].available, dec!(1.123456));
+    }
+
+    /// In-memory sink shared with the test, so writes can be observed.
+    #[derive(Clone, Default)]
+    struct SharedSink(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffered_wal_visible_after_flush() {
+        let sink = SharedSink::default();
+        let wal = WriteAheadLog::from_writer(Box::new(sink.clone()), false, false).unwrap();
+        let engine = PaymentsEngine::new().with_wal(wal);
+        for tx in 1..=3 {
+            engine.process(Trx::Deposit { client: 1, tx, amount: dec!(1.0) }).await;
+        }
+        assert!(sink.0.lock().unwrap().is_empty());
+
+        engine.flush().await.unwrap();
+        let log = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(log, "deposit,1,1,1.0,,\ndeposit,1,2,1.0,,\ndeposit,1,3,1.0,,\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_results_flushes_buffered_wal() {
+        let sink = SharedSink::default();
+        let wal = WriteAheadLog::from_writer(Box::new(sink.clone()), false, false).unwrap();
+        let mut processor = TrxProcessor::with_engine(PaymentsEngine::new().with_wal(wal));
+        processor.process_reader("type,client,tx,amount\ndeposit,1,1,1.0000\n".as_bytes()).await.unwrap();
+        assert!(sink.0.lock().unwrap().is_empty());
+
+        processor.write_results(std::io::sink()).await.unwrap();
+        assert_eq!(String::from_utf8(sink.0.lock().unwrap().clone()).unwrap(), "deposit,1,1,1.0000,,\n");
+    }
+
+    #[tokio::test]
+    async fn test_replay_missing_log_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = PaymentsEngine::new();
+        let summary = replay(dir.path().join("absent.wal"), &engine).await.unwrap();
+        assert_eq!(summary, RejectionSummary::new());
+        assert!(engine.get_accounts().is_empty());
+    }
+}
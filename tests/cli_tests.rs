@@ -16,3 +16,199 @@ fn test_nonexistent_file() {
         .failure()
         .stderr(predicate::str::contains("not found"));
 }
+
+#[test]
+fn test_sort_output_none_emits_same_accounts() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    let output = cmd
+        .arg("tests/fixtures/comprehensive_all_types.csv")
+        .arg("--sort-output=none")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let sorted = Command::cargo_bin("payments_engine")
+        .unwrap()
+        .arg("tests/fixtures/comprehensive_all_types.csv")
+        .output()
+        .unwrap();
+
+    let mut unsorted_rows: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    unsorted_rows[1..].sort_by_key(|row| row.split(',').next().unwrap().parse::<u16>().unwrap());
+    let sorted_rows: Vec<String> = String::from_utf8(sorted.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    assert_eq!(unsorted_rows, sorted_rows);
+}
+
+#[test]
+fn test_invalid_sort_output_rejected() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/basic.csv")
+        .arg("--sort-output=random")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown sort order"));
+}
+
+#[test]
+fn test_output_file_written() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("accounts.csv");
+
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/basic.csv")
+        .arg("--output")
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty());
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        contents,
+        "client,available,held,total,locked\n1,0.5000,0.0000,0.5000,false\n2,2.0000,0.0000,2.0000,false\n"
+    );
+}
+
+#[test]
+fn test_output_file_creation_error() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/basic.csv")
+        .arg("--output=missing_dir/accounts.csv")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Cannot create output file missing_dir/accounts.csv"));
+
+    // The output is opened before the input is read
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("nonexistent_file.csv")
+        .arg("--output=missing_dir/accounts.csv")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Cannot create output file missing_dir/accounts.csv"));
+}
+
+#[test]
+fn test_summary_trailer() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/basic.csv")
+        .arg("--summary")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with(
+            "2,2.0000,0.0000,2.0000,false\n# accounts=2 locked=0 available=2.5 held=0 total=2.5\n"
+        ));
+}
+
+#[test]
+fn test_no_accounts_prints_header_only() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/malformed_only.csv")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n");
+}
+
+#[test]
+fn test_stats_on_stderr() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/basic.csv")
+        .arg("--stats")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("client,available,held,total,locked\n"))
+        .stderr(predicate::str::contains("accounts=2 tx_history=3 evicted=0 estimated_bytes="));
+}
+
+#[test]
+fn test_validate_valid_file() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/dispute_resolve.csv")
+        .arg("--validate")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("rows valid"));
+}
+
+#[test]
+fn test_validate_reports_dangling_dispute() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/dangling_dispute.csv")
+        .arg("--validate")
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("row 4: dispute references unknown tx 7"))
+        .stderr(predicate::str::contains("row 5: withdrawal tx 2 is missing an amount"))
+        .stderr(predicate::str::contains("Validation failed with 2 problem(s)"));
+}
+
+#[test]
+fn test_validate_rejects_extra_column() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/extra_column.csv")
+        .arg("--validate")
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("row 1: malformed record: Malformed row at line 2"))
+        .stderr(predicate::str::contains("found record with 5 fields"));
+}
+
+#[test]
+fn test_strict_reports_bad_row_line() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/bad_row.csv")
+        .arg("--strict")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Malformed row at line 4"));
+
+    // Strict mode also requires lowercase types, as `ProcessorConfig::strict()` does
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mixed_case.csv");
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,10.0\nDeposit,2,2,5.0\n").unwrap();
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg(&path)
+        .arg("--strict")
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("tx 2 has an unknown type"));
+
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/extra_column.csv")
+        .arg("--strict")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("found record with 5 fields"));
+}
+
+#[test]
+fn test_fail_fast_names_rejected_tx() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/basic.csv")
+        .arg("--fail-fast")
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("Transaction 4 rejected: InsufficientFunds"));
+}
+
+#[test]
+fn test_sort_output_total_lists_largest_first() {
+    let mut cmd = Command::cargo_bin("payments_engine").unwrap();
+    cmd.arg("tests/fixtures/basic.csv")
+        .arg("--sort-output=total")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n2,2.0000,0.0000,2.0000,false\n1,0.5000,0.0000,0.5000,false\n");
+}
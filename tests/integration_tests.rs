@@ -1,4 +1,7 @@
 use assert_cmd::Command;
+use payments_engine::config::ProcessorConfig;
+use payments_engine::services::account_diff::diff_accounts;
+use payments_engine::services::trx_processor::TrxProcessor;
 use std::fs;
 
 fn normalize_csv(csv: &str) -> Vec<Vec<String>> {
@@ -95,3 +98,49 @@ fn test_comprehensive_all_types() {
         "tests/expected/comprehensive_all_types_expected.csv",
     );
 }
+
+/// Runs `fixture` through an engine per config and asserts the account output
+/// is identical, so performance and safety settings can't change outcomes for
+/// well-behaved input.
+fn assert_same_outcome(fixture: &str, baseline: ProcessorConfig, candidates: &[(&str, ProcessorConfig)]) {
+    let run = |config: ProcessorConfig| {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut processor = TrxProcessor::with_config(config);
+            processor.process_file(fixture).await.unwrap();
+            let mut output = Vec::new();
+            processor.write_results(&mut output).await.unwrap();
+            output
+        })
+    };
+
+    let expected = run(baseline);
+    for (name, config) in candidates {
+        let diffs = diff_accounts(expected.as_slice(), run(config.clone()).as_slice()).unwrap();
+        assert!(diffs.is_empty(), "{} changed the output of {}: {:?}", name, fixture, diffs);
+    }
+}
+
+#[test]
+fn test_configs_agree_on_comprehensive_all_types() {
+    assert_same_outcome(
+        "tests/fixtures/comprehensive_all_types.csv",
+        ProcessorConfig::new(),
+        &[
+            ("max_tx_history", ProcessorConfig::new().with_max_tx_history(Some(1_000))),
+            ("deterministic", ProcessorConfig::new().with_deterministic(true)),
+            ("verify_invariants", ProcessorConfig::new().with_verify_invariants(true).with_self_check(true)),
+            ("abort_on_invariant_violation", ProcessorConfig::new().with_abort_on_invariant_violation(true)),
+            ("reject_excess_precision", ProcessorConfig::new().with_reject_excess_precision(true)),
+            ("strict", ProcessorConfig::strict()),
+        ],
+    );
+}
+
+#[test]
+fn test_large_history_agrees_with_unbounded() {
+    assert_same_outcome(
+        "tests/fixtures/large_test.csv",
+        ProcessorConfig::new().with_max_tx_history(None),
+        &[("max_tx_history", ProcessorConfig::new().with_max_tx_history(Some(200_000)))],
+    );
+}